@@ -25,6 +25,7 @@ fn main() {
             key,
             // set to None to automatically guess
             cipher_hmac_algorithm: None,
+            kdf_iter: None,
         },
     )
     .expect("Failed to decrypt db");
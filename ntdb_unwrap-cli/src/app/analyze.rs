@@ -0,0 +1,99 @@
+use crate::Result;
+use ntdb_unwrap::ntqq::analyzer::{AnalysisReport, Analyzer, Architecture, Signature, SignatureDb};
+use std::path::PathBuf;
+
+pub struct Analyze {
+    file: PathBuf,
+    patterns: Vec<String>,
+    json: bool,
+    save: bool,
+    qq_version: Option<String>,
+    slice: Option<Architecture>,
+}
+pub fn analyze(matches: clap::ArgMatches) -> Result<Analyze> {
+    Ok(Analyze {
+        file: matches.get_one::<PathBuf>("file").unwrap().to_owned(),
+        patterns: matches
+            .get_many::<String>("pattern")
+            .map(|x| x.cloned().collect())
+            .unwrap_or_default(),
+        json: matches.get_flag("json"),
+        save: matches.get_flag("save"),
+        qq_version: matches.get_one::<String>("qq-version").cloned(),
+        slice: matches.get_one::<String>("arch").map(|x| match x.as_str() {
+            "arm64" => Architecture::Aarch64,
+            _ => Architecture::X86_64,
+        }),
+    })
+}
+
+impl super::App for Analyze {
+    fn run(self: Box<Self>) -> Result<()> {
+        let mut analyzer = Analyzer::new();
+        for pattern in self.patterns {
+            analyzer = analyzer.pattern(pattern);
+        }
+        if let Some(slice) = self.slice {
+            analyzer = analyzer.slice(slice);
+        }
+        let report = analyzer
+            .analyze_file(&self.file)
+            .map_err(ntdb_unwrap::Error::from)?;
+        if self.json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).expect("report is serializable")
+            );
+        } else {
+            print_report(&self.file, &report);
+        }
+        if self.save
+            && let Some(signature) = Signature::from_report(&report, self.qq_version.as_deref())
+            && let Some(path) = SignatureDb::default_path()
+        {
+            let mut db = SignatureDb::load(&path).map_err(ntdb_unwrap::Error::from)?;
+            db.insert(signature);
+            db.save(&path).map_err(ntdb_unwrap::Error::from)?;
+            // keep stdout clean for --json
+            eprintln!("已记录到签名数据库：{:?}", path);
+        }
+        report.target_function().map_err(ntdb_unwrap::Error::from)?;
+        Ok(())
+    }
+}
+
+fn print_report(file: &std::path::Path, report: &AnalysisReport) {
+    println!("文件: {}", file.display());
+    println!("SHA-256: {}", report.sha256);
+    println!("格式: {}，架构: {}", report.format, report.architecture);
+    if report.slice_offset != 0 {
+        println!("Universal 二进制中的切片偏移: 0x{:X}", report.slice_offset);
+    }
+    println!("镜像基址: 0x{:X}（以下地址均相对于此）", report.image_base);
+    println!("特征字符串 ({}):", report.strings.len());
+    for x in &report.strings {
+        println!("  0x{:X} [{}] {:?}", x.offset, x.section, x.pattern);
+    }
+    println!("引用特征字符串的指令 ({}):", report.candidates.len());
+    for x in &report.candidates {
+        match (x.function_offset, x.bounds_source) {
+            (Some(function_offset), Some(source)) => println!(
+                "  0x{:X} -> 所在函数 0x{:X}（来自 {}）",
+                x.instr_offset, function_offset, source
+            ),
+            _ => println!(
+                "  0x{:X} -> 未能定位所在函数: {}",
+                x.instr_offset,
+                x.error.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+    match &report.chosen {
+        Some(func) => {
+            println!("密钥函数 RVA: 0x{:X}", func.function_offset);
+            println!("引用指令 RVA: 0x{:X}", func.lea_instr_offset);
+        }
+        None => println!("未找到密钥函数"),
+    }
+    println!("置信度: {}", report.confidence);
+}
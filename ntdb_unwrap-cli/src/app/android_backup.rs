@@ -0,0 +1,86 @@
+use crate::Result;
+use ntdb_unwrap::*;
+use snafu::prelude::*;
+use std::io::Read;
+use std::{env, fs, path::PathBuf};
+
+pub struct AndroidBackup {
+    backup_dir: PathBuf,
+    output_dir: PathBuf,
+    extracted: bool,
+}
+pub fn android_backup(matches: clap::ArgMatches) -> Result<AndroidBackup> {
+    let backup = matches.get_one::<PathBuf>("backup").unwrap().to_owned();
+    let output_dir = matches.get_one::<PathBuf>("output").unwrap().to_owned();
+    if backup.is_dir() {
+        return Ok(AndroidBackup {
+            backup_dir: backup,
+            output_dir,
+            extracted: false,
+        });
+    }
+    let temp_dir = env::temp_dir().join("nt_android_backup");
+    println!("解压备份文件到临时目录：{:?}", temp_dir);
+    if temp_dir.exists() {
+        fs::remove_dir_all(&temp_dir)?;
+    }
+    fs::create_dir_all(&temp_dir)?;
+    // from now on, the temp dir is cleaned up on drop
+    let app = AndroidBackup {
+        backup_dir: temp_dir,
+        output_dir,
+        extracted: true,
+    };
+    ntqq::android::extract_backup(&backup, &app.backup_dir)?;
+    Ok(app)
+}
+
+impl super::App for AndroidBackup {
+    fn run(self: Box<Self>) -> Result<()> {
+        let files = ntqq::android::detect_db_file_in_backup(&self.backup_dir)?;
+        if files.is_empty() {
+            whatever!("备份中未找到 NTQQ 数据库");
+        }
+        println!("备份中找到 {} 个账号数据库", files.len());
+        let mut exported = 0;
+        for file in &files {
+            println!("{}", file);
+            let Some(uid) = &file.uid else {
+                println!("[WARN] 备份中未找到此账号的 uid，跳过");
+                continue;
+            };
+            let mut buf = [0u8; db::NtDbHeader::LEN];
+            fs::File::open(&file.path)?.read_exact(&mut buf)?;
+            let decrypt_info =
+                ntqq::android::decode_db_header(uid, &buf).map_err(ntdb_unwrap::Error::from)?;
+            let account_dir = file
+                .path
+                .parent()
+                .expect("a database file always has a parent dir");
+            let output_dir = self.output_dir.join(match file.uin {
+                Some(uin) => uin.to_string(),
+                None => uid.clone(),
+            });
+            let manifest = db::export_dir_to_plain(account_dir, &output_dir, decrypt_info)?;
+            for failed in &manifest.failed {
+                println!("[WARN] 导出 {} 失败：{}", failed.file_name, failed.error);
+            }
+            println!(
+                "已导出 {} 个数据库到：{:?}",
+                manifest.databases.len(),
+                output_dir
+            );
+            exported += 1;
+        }
+        println!("共导出 {}/{} 个账号", exported, files.len());
+        Ok(())
+    }
+}
+impl Drop for AndroidBackup {
+    fn drop(&mut self) {
+        if self.extracted {
+            println!("清理临时目录: {:?}", self.backup_dir);
+            fs::remove_dir_all(&self.backup_dir).unwrap();
+        }
+    }
+}
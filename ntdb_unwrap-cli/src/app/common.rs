@@ -14,68 +14,37 @@ use std::{env, fs, io::Read};
 /// 2. use the `file` argument to detect the as database file, if none, try auto detect, and interactively asks user to choose one.
 /// 3. use the `pkey` argument to decrypt the database file, if none, try auto detect.
 /// 4. if `nocopy` flag is not set, copy the database file to a temp file, and use the temp file as the database file.
-/// 5. open the database file with the offset vfs, and try decrypt it.
+/// 5. open the database file with the offset vfs, or its read-only variant if working on the original file, and try decrypt it.
 pub fn bootstrap(matches: &ArgMatches) -> Result<Bootstrap> {
-    let mut file: UserDBFile = match matches.get_one::<String>("file") {
-        Some(f) => UserDBFile {
-            path: fs::canonicalize(f)?,
-            uid: matches
-                .get_one::<String>("android-uid")
-                .map(ToOwned::to_owned),
-            ..Default::default()
-        },
-        None => {
-            let db_files = ntqq::detect_db_file()?;
-            if db_files.is_empty() {
-                whatever!("无法自动检测到数据库文件，请通过命令行参数手动指定");
-            } else if db_files.len() == 1 {
-                db_files.into_iter().next().unwrap()
-            } else {
-                println!("选择要使用的数据库文件：");
-                for (i, db_file) in db_files.iter().enumerate() {
-                    println!("{}. {}", i, db_file);
-                }
-                let mut input = String::new();
-                loop {
-                    input.clear();
-                    std::io::stdin().read_line(&mut input).unwrap();
-                    if let Ok(i) = input.trim().parse::<usize>()
-                        && i < db_files.len()
-                    {
-                        break db_files.into_iter().nth(i).unwrap();
-                    }
-                    println!("无效输入，请重试：");
-                }
-            }
-        }
-    };
-
-    let decrypt_info: DBDecryptInfo = match matches.get_one::<String>("pkey") {
-        Some(pkey) => DBDecryptInfo {
-            key: pkey.to_owned(),
-            ..Default::default()
-        },
-        None => get_decrypt_info(&file, running_platform())?,
-    };
+    let mut file = select_db_file(matches)?;
+    let decrypt_info = resolve_decrypt_info(matches, &file)?;
 
     let mut working_on_temp_file = false;
     if !matches.get_flag("nocopy") {
         let temp_file = env::temp_dir().join("nt_msg_temp_copy.db");
         println!("复制数据库文件为临时文件：{:?}", temp_file);
-        fs::copy(&file.path, &temp_file)?;
+        db::copy_db(&file.path, &temp_file)?;
         working_on_temp_file = true;
         file.path = temp_file;
     } else {
-        println!("[WARN] 正在直接操作原始数据库文件，这可能会损坏你的数据!");
+        println!(
+            "[WARN] 正在以只读方式直接读取原始数据库文件，尚未写回数据库的最新消息（位于 -wal 文件中）将不会被读取"
+        );
     }
 
     db::register_offset_vfs().map_err(|e| {
         Error::without_source(format!("failed to register offset vfs: sqlite code {}", e))
     })?;
+    // the original file is opened read-only, the connection itself stays writable to attach export targets
+    let vfs = if working_on_temp_file {
+        db::OFFSET_VFS_NAME
+    } else {
+        db::OFFSET_VFS_READONLY_NAME
+    };
     let conn = rusqlite::Connection::open_with_flags_and_vfs(
         &file.path,
         OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
-        db::OFFSET_VFS_NAME,
+        vfs,
     )
     .context(SqliteSnafu { op: "open db" })?;
 
@@ -107,25 +76,147 @@ impl Drop for Bootstrap {
         }
         if self.working_on_temp_file {
             println!("清理临时文件: {:?}", self.user_db_file.path);
-            fs::remove_file(&self.user_db_file.path).unwrap();
+            db::remove_db(&self.user_db_file.path).unwrap();
         }
     }
 }
 
+/// Use the `file` argument as the database file, if none, try auto detect, and interactively asks user to choose one.
+///
+/// If `file` is a directory, it's taken as an account's database directory and its `nt_msg.db` is used.
+pub fn select_db_file(matches: &ArgMatches) -> Result<UserDBFile> {
+    let file: UserDBFile = match matches.get_one::<String>("file") {
+        Some(f) => UserDBFile {
+            path: {
+                let path = fs::canonicalize(f)?;
+                // an account's database directory is given, use the message database in it
+                if path.is_dir() {
+                    path.join("nt_msg.db")
+                } else {
+                    path
+                }
+            },
+            uid: matches
+                .get_one::<String>("android-uid")
+                .map(ToOwned::to_owned),
+            ..Default::default()
+        },
+        None if matches.contains_id("android-uid-from") => {
+            whatever!("使用 --android-uid-from 时必须指定数据库文件");
+        }
+        None => {
+            let db_files = match matches.get_many::<String>("android-package") {
+                Some(packages) if running_platform() == Platform::Android => {
+                    let packages = packages.collect::<Vec<_>>();
+                    ntqq::android::detect_db_file_for(&ntqq::android::data_dir(), &packages)?
+                }
+                _ => ntqq::detect_db_file()?,
+            };
+            if db_files.is_empty() {
+                whatever!("无法自动检测到数据库文件，请通过命令行参数手动指定");
+            } else if db_files.len() == 1 {
+                db_files.into_iter().next().unwrap()
+            } else {
+                println!("选择要使用的数据库文件：");
+                for (i, db_file) in db_files.iter().enumerate() {
+                    println!("{}. {}", i, db_file);
+                }
+                let mut input = String::new();
+                loop {
+                    input.clear();
+                    std::io::stdin().read_line(&mut input).unwrap();
+                    if let Ok(i) = input.trim().parse::<usize>()
+                        && i < db_files.len()
+                    {
+                        break db_files.into_iter().nth(i).unwrap();
+                    }
+                    println!("无效输入，请重试：");
+                }
+            }
+        }
+    };
+    let mut file = file;
+    if let Some(from) = matches.get_one::<std::path::PathBuf>("android-uid-from") {
+        let candidates = ntqq::android::uid_candidates(from)?;
+        println!("找到 {} 个候选 uid", candidates.len());
+        let Some(resolved) = ntqq::android::resolve_uid(&file.path, &candidates)? else {
+            whatever!("候选 uid 均不匹配此数据库");
+        };
+        println!("数据库所属的 uid：{}", resolved.uid);
+        file.uid = Some(resolved.uid);
+        file.uin = resolved.uin;
+    }
+    warn_if_not_ntqq_db(&file);
+    Ok(file)
+}
+
+/// The offset vfs silently falls back to no offset for files without the NTQQ header,
+/// which then fail to decrypt with a confusing error, so warn early.
+fn warn_if_not_ntqq_db(file: &UserDBFile) {
+    let mut buf = [0u8; db::NtDbHeader::LEN];
+    let n = fs::File::open(&file.path)
+        .and_then(|mut f| f.read(&mut buf))
+        .unwrap_or_default();
+    if let Err(e) = db::NtDbHeader::parse(&buf[..n]) {
+        println!("[WARN] {:?} 可能不是 NTQQ 数据库文件：{}", file.path, e);
+    }
+}
+
+/// Use the `pkey` argument as the database key, if none, try auto detect.
+pub fn resolve_decrypt_info(matches: &ArgMatches, file: &UserDBFile) -> Result<DBDecryptInfo> {
+    let decrypt_info: DBDecryptInfo = match matches.get_one::<String>("pkey") {
+        Some(pkey) => DBDecryptInfo {
+            key: pkey.to_owned(),
+            ..Default::default()
+        },
+        // the uid is given for an android database, which may be decrypted on any platform
+        None if matches.contains_id("android-uid") || matches.contains_id("android-uid-from") => {
+            get_decrypt_info(file, Platform::Android, None)?
+        }
+        None if matches.contains_id("dump") => {
+            let dump = matches.get_one::<std::path::PathBuf>("dump").unwrap();
+            println!("从内存转储中搜索数据库密钥：{:?}", dump);
+            let kdf_iter = matches.get_one::<usize>("kdf-iter").copied();
+            let Some(found) = ntqq::keyscan::scan_file(dump, &file.path, kdf_iter)? else {
+                whatever!("未能在内存转储中找到可用的数据库密钥");
+            };
+            println!(
+                "找到数据库密钥：{}（转储文件偏移 0x{:X}）",
+                found.decrypt_info.key, found.candidate.file_offset
+            );
+            found.decrypt_info
+        }
+        None => {
+            // present without a value: attach to whichever QQ process found
+            let attach = matches
+                .contains_id("attach")
+                .then(|| matches.get_one::<u32>("attach").copied());
+            get_decrypt_info(file, running_platform(), attach)?
+        }
+    };
+    Ok(DBDecryptInfo {
+        kdf_iter: matches.get_one::<usize>("kdf-iter").copied(),
+        ..decrypt_info
+    })
+}
+
 /// 此函数对不同平台的行为不同。
 /// - Android: 仅仅是简单的计算，可以很快完成。
 /// - Windows: 需要启动一个 QQ 进程并附加调试器，这需要用户操作，且会长时间阻塞。
 ///
 /// **注意**: 对于任何异步调用者，应当将此函数视为长时阻塞调用。
-fn get_decrypt_info(file: &UserDBFile, platform: Platform) -> Result<DBDecryptInfo> {
+fn get_decrypt_info(
+    file: &UserDBFile,
+    platform: Platform,
+    attach: Option<Option<u32>>,
+) -> Result<DBDecryptInfo> {
     match platform {
         Platform::Android => {
             if let Some(uid) = &file.uid {
                 let mut f = fs::File::open(&file.path)?;
-                let mut buf = [0u8; 1024];
+                let mut buf = [0u8; db::NtDbHeader::LEN];
                 f.read_exact(&mut buf)?;
-                ntqq::android::decode_db_header(uid, &buf)
-                    .whatever_context::<_, Error>("decode android nt_qq db header")
+                Ok(ntqq::android::decode_db_header(uid, &buf).map_err(ntdb_unwrap::Error::from)?)
             } else {
                 whatever!("Android平台下必须提供UID以自动解密数据库");
             }
@@ -133,7 +224,7 @@ fn get_decrypt_info(file: &UserDBFile, platform: Platform) -> Result<DBDecryptIn
         Platform::Windows => {
             #[cfg(target_os = "windows")]
             {
-                use ntdb_unwrap::ntqq::windows::{DebugInfo, debug_for_key};
+                use ntdb_unwrap::ntqq::windows::{DebugInfo, DebugTarget, debug_for_key};
                 let qq = ntqq::windows::get_installed_qq()?;
                 println!("检测到已安装的QQ: {:?}", qq);
                 let func = ntqq::windows::TargetFunction::from_installed_qq(&qq)?;
@@ -142,9 +233,24 @@ fn get_decrypt_info(file: &UserDBFile, platform: Platform) -> Result<DBDecryptIn
                     func.lea_instr_offset
                 );
                 println!("指令所在函数开始地址：0x{:X}", func.function_offset);
-                println!("启动QQ进程并附加调试器以提取解密密钥...");
-                println!("请在新打开的QQ窗口正登录目标账号后，等待程序自动完成解密密钥提取。");
-                let decrypt_info = debug_for_key(&DebugInfo { qq, func })?;
+                let target = match attach {
+                    None => DebugTarget::Launch,
+                    Some(Some(pid)) => DebugTarget::Attach { pid },
+                    Some(None) => match ntqq::windows::find_qq_processes()?.first() {
+                        Some(&pid) => DebugTarget::Attach { pid },
+                        None => whatever!("未找到正在运行的QQ进程"),
+                    },
+                };
+                if let DebugTarget::Attach { pid } = target {
+                    println!("附加调试器到QQ进程（PID: {}）以提取解密密钥...", pid);
+                    println!(
+                        "请在QQ中进行会打开数据库的操作（如重新登录），等待程序自动完成解密密钥提取。"
+                    );
+                } else {
+                    println!("启动QQ进程并附加调试器以提取解密密钥...");
+                    println!("请在新打开的QQ窗口正登录目标账号后，等待程序自动完成解密密钥提取。");
+                }
+                let decrypt_info = debug_for_key(&DebugInfo { qq, func, target })?;
                 println!("解密密钥提取完成: {}", decrypt_info.key);
                 Ok(decrypt_info)
             }
@@ -156,6 +262,50 @@ fn get_decrypt_info(file: &UserDBFile, platform: Platform) -> Result<DBDecryptIn
                 )
             }
         }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        Platform::Linux => {
+            use ntdb_unwrap::ntqq::linux::{self, DebugInfo, DebugTarget, debug_for_key};
+            let install_dir = std::path::Path::new(linux::DEFAULT_INSTALL_DIR);
+            let (target, wrapper_node) = match attach {
+                None => (
+                    DebugTarget::Spawn {
+                        program: install_dir.join("qq"),
+                        args: Vec::new(),
+                    },
+                    linux::wrapper_node_path(install_dir),
+                ),
+                Some(pid) => {
+                    let processes = linux::find_qq_processes();
+                    let found = match pid {
+                        Some(pid) => processes.into_iter().find(|x| x.0 == pid as i32),
+                        None => processes.into_iter().next(),
+                    };
+                    let Some((pid, wrapper_node)) = found else {
+                        whatever!("未找到已加载 {} 的QQ进程", linux::WRAPPER_NODE);
+                    };
+                    (DebugTarget::Attach { pid }, wrapper_node)
+                }
+            };
+            let func =
+                ntqq::analyzer::TargetFunction::from_wrapper_node_file_cached(&wrapper_node, None)?;
+            println!(
+                "引用特征字符串的 LEA 指令地址: 0x{:X}",
+                func.lea_instr_offset
+            );
+            println!("指令所在函数开始地址：0x{:X}", func.function_offset);
+            if let DebugTarget::Attach { pid } = target {
+                println!("附加调试器到QQ进程（PID: {}）以提取解密密钥...", pid);
+                println!(
+                    "请在QQ中进行会打开数据库的操作（如重新登录），等待程序自动完成解密密钥提取。"
+                );
+            } else {
+                println!("启动QQ进程并附加调试器以提取解密密钥...");
+                println!("请在新打开的QQ窗口正登录目标账号后，等待程序自动完成解密密钥提取。");
+            }
+            let decrypt_info = debug_for_key(&DebugInfo { target, func })?;
+            println!("解密密钥提取完成: {}", decrypt_info.key);
+            Ok(decrypt_info)
+        }
         _ => {
             whatever!("此平台({:?})不支持自动解密，请手动提供解密密钥", platform);
         }
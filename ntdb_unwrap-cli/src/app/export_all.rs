@@ -0,0 +1,81 @@
+use crate::Result;
+use ntdb_unwrap::*;
+use std::{env, fs, path::PathBuf};
+
+pub struct ExportAll {
+    source_dir: PathBuf,
+    output_dir: PathBuf,
+    decrypt_info: ntqq::DBDecryptInfo,
+    working_on_temp_dir: bool,
+}
+pub fn export_all(matches: clap::ArgMatches) -> Result<ExportAll> {
+    let file = super::common::select_db_file(&matches)?;
+    let decrypt_info = super::common::resolve_decrypt_info(&matches, &file)?;
+    let output_dir = matches.get_one::<PathBuf>("output").unwrap().to_owned();
+    let mut source_dir = file
+        .path
+        .parent()
+        .expect("a database file always has a parent dir")
+        .to_path_buf();
+
+    let mut working_on_temp_dir = false;
+    if !matches.get_flag("nocopy") {
+        let temp_dir = env::temp_dir().join("nt_db_temp_copy");
+        println!("复制数据库目录到临时目录：{:?}", temp_dir);
+        // leftovers of an aborted run would otherwise be exported as well
+        if temp_dir.exists() {
+            fs::remove_dir_all(&temp_dir)?;
+        }
+        fs::create_dir_all(&temp_dir)?;
+        working_on_temp_dir = true;
+        for entry in fs::read_dir(&source_dir)? {
+            let entry = entry?;
+            // sidecars are copied along with their databases
+            if entry.file_type()?.is_file() && !db::is_sidecar(&entry.path()) {
+                db::copy_db(entry.path(), temp_dir.join(entry.file_name()))?;
+            }
+        }
+        source_dir = temp_dir;
+    } else {
+        println!("[WARN] 正在直接操作原始数据库文件，这可能会损坏你的数据!");
+    }
+    Ok(ExportAll {
+        source_dir,
+        output_dir,
+        decrypt_info,
+        working_on_temp_dir,
+    })
+}
+
+impl super::App for ExportAll {
+    fn run(self: Box<Self>) -> Result<()> {
+        let manifest = db::export_dir_to_plain(
+            &self.source_dir,
+            &self.output_dir,
+            self.decrypt_info.clone(),
+        )?;
+        for exported in &manifest.databases {
+            println!(
+                "已导出 {}：{} 个表，{} 行",
+                exported.file_name, exported.table_count, exported.row_count
+            );
+        }
+        for failed in &manifest.failed {
+            println!("[WARN] 导出 {} 失败：{}", failed.file_name, failed.error);
+        }
+        println!(
+            "已导出为未加密数据库：{:?}，清单文件：{}",
+            self.output_dir,
+            db::MANIFEST_FILE_NAME
+        );
+        Ok(())
+    }
+}
+impl Drop for ExportAll {
+    fn drop(&mut self) {
+        if self.working_on_temp_dir {
+            println!("清理临时目录: {:?}", self.source_dir);
+            fs::remove_dir_all(&self.source_dir).unwrap();
+        }
+    }
+}
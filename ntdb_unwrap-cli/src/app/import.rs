@@ -0,0 +1,39 @@
+use crate::Result;
+use ntdb_unwrap::*;
+use std::path::PathBuf;
+
+pub struct Import {
+    input_file: PathBuf,
+    original: ntqq::UserDBFile,
+    decrypt_info: ntqq::DBDecryptInfo,
+    output_file: PathBuf,
+}
+pub fn import(matches: clap::ArgMatches) -> Result<Import> {
+    let original = super::common::select_db_file(&matches)?;
+    let decrypt_info = super::common::resolve_decrypt_info(&matches, &original)?;
+    Ok(Import {
+        input_file: matches.get_one::<PathBuf>("input").unwrap().to_owned(),
+        original,
+        decrypt_info,
+        output_file: matches.get_one::<PathBuf>("output").unwrap().to_owned(),
+    })
+}
+
+impl super::App for Import {
+    fn run(self: Box<Self>) -> Result<()> {
+        let d = db::import_from_plain(
+            &self.input_file,
+            &self.original.path,
+            &self.output_file,
+            self.decrypt_info,
+        )?;
+        println!(
+            "已将 {:?} 加密为 NTQQ 数据库：{:?}（密钥 {}，{}）",
+            self.input_file,
+            self.output_file,
+            d.key,
+            d.cipher_hmac_algorithm.unwrap_or_default()
+        );
+        Ok(())
+    }
+}
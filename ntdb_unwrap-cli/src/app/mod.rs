@@ -1,7 +1,19 @@
+mod analyze;
+pub use analyze::*;
+mod android_backup;
+pub use android_backup::*;
 mod export;
 pub use export::*;
+mod export_all;
+pub use export_all::*;
+mod import;
+pub use import::*;
+mod rekey;
+pub use rekey::*;
 mod serve;
 pub use serve::*;
+mod signatures;
+pub use signatures::*;
 
 mod common;
 
@@ -0,0 +1,59 @@
+use crate::{Error, Result, SqliteSnafu};
+use ntdb_unwrap::*;
+use rusqlite::{Connection, OpenFlags};
+use snafu::{FromString, prelude::*};
+use std::{fs, path::PathBuf};
+
+pub struct Rekey {
+    source: ntqq::UserDBFile,
+    decrypt_info: ntqq::DBDecryptInfo,
+    new: ntqq::DBDecryptInfo,
+    output_file: PathBuf,
+}
+pub fn rekey(matches: clap::ArgMatches) -> Result<Rekey> {
+    let source = super::common::select_db_file(&matches)?;
+    let decrypt_info = super::common::resolve_decrypt_info(&matches, &source)?;
+    let new = ntqq::DBDecryptInfo {
+        key: matches.get_one::<String>("new-key").unwrap().to_owned(),
+        cipher_hmac_algorithm: matches.get_one::<String>("new-hmac").cloned(),
+        kdf_iter: matches.get_one::<usize>("new-kdf-iter").copied(),
+    };
+    Ok(Rekey {
+        source,
+        decrypt_info,
+        new,
+        output_file: matches.get_one::<PathBuf>("output").unwrap().to_owned(),
+    })
+}
+
+impl super::App for Rekey {
+    fn run(self: Box<Self>) -> Result<()> {
+        let in_place = fs::canonicalize(&self.output_file)
+            .is_ok_and(|x| fs::canonicalize(&self.source.path).is_ok_and(|y| x == y));
+        if in_place {
+            println!("[WARN] 正在直接修改原始数据库文件，请先退出QQ并自行做好备份");
+        } else {
+            println!("复制数据库文件到：{:?}", self.output_file);
+            db::copy_db(&self.source.path, &self.output_file)?;
+        }
+        db::register_offset_vfs().map_err(|e| {
+            Error::without_source(format!("failed to register offset vfs: sqlite code {}", e))
+        })?;
+        let conn = Connection::open_with_flags_and_vfs(
+            &self.output_file,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            db::OFFSET_VFS_NAME,
+        )
+        .context(SqliteSnafu { op: "open db" })?;
+        db::try_decrypt_db(&conn, self.decrypt_info)?;
+        let conn = db::rekey(conn, self.new.clone())?;
+        drop(conn);
+        println!(
+            "已更换数据库密钥：{:?}（新密钥 {}，kdf_iter {}）",
+            self.output_file,
+            self.new.key,
+            self.new.kdf_iter()
+        );
+        Ok(())
+    }
+}
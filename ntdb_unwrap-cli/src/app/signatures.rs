@@ -0,0 +1,65 @@
+use crate::{Error, Result};
+use ntdb_unwrap::ntqq::analyzer::SignatureDb;
+use snafu::prelude::*;
+use std::path::PathBuf;
+
+enum Action {
+    List,
+    Export(PathBuf),
+    Import(PathBuf),
+}
+
+pub struct Signatures {
+    db_path: PathBuf,
+    action: Action,
+}
+pub fn signatures(mut matches: clap::ArgMatches) -> Result<Signatures> {
+    let db_path = match matches.get_one::<PathBuf>("database") {
+        Some(x) => x.to_owned(),
+        None => SignatureDb::default_path()
+            .whatever_context::<_, Error>("无法确定签名数据库的默认路径，请通过 --database 指定")?,
+    };
+    let action = match matches.remove_subcommand() {
+        Some((s, m)) if s == "export" => {
+            Action::Export(m.get_one::<PathBuf>("file").unwrap().to_owned())
+        }
+        Some((s, m)) if s == "import" => {
+            Action::Import(m.get_one::<PathBuf>("file").unwrap().to_owned())
+        }
+        _ => Action::List,
+    };
+    Ok(Signatures { db_path, action })
+}
+
+impl super::App for Signatures {
+    fn run(self: Box<Self>) -> Result<()> {
+        let mut db = SignatureDb::load(&self.db_path).map_err(ntdb_unwrap::Error::from)?;
+        match self.action {
+            Action::List => {
+                println!("签名数据库：{:?}", self.db_path);
+                for x in db.signatures() {
+                    println!(
+                        "{} {} {} 函数: 0x{:X} 引用指令: 0x{:X}",
+                        x.sha256,
+                        x.qq_version.as_deref().unwrap_or("unknown_version"),
+                        x.architecture,
+                        x.function_offset,
+                        x.lea_instr_offset
+                    );
+                }
+                println!("共 {} 条", db.signatures().len());
+            }
+            Action::Export(file) => {
+                db.save(&file).map_err(ntdb_unwrap::Error::from)?;
+                println!("已导出 {} 条签名到：{:?}", db.signatures().len(), file);
+            }
+            Action::Import(file) => {
+                let other = SignatureDb::load(&file).map_err(ntdb_unwrap::Error::from)?;
+                let count = db.merge(other);
+                db.save(&self.db_path).map_err(ntdb_unwrap::Error::from)?;
+                println!("已导入 {} 条新增或变更的签名到：{:?}", count, self.db_path);
+            }
+        }
+        Ok(())
+    }
+}
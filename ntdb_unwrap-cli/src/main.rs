@@ -21,20 +21,37 @@ fn main() -> Result<()> {
     let mut matches = cmd().get_matches();
     let app: Box<dyn app::App> = match matches.remove_subcommand() {
         Some((s, matches)) if s == "export" => Box::new(app::export(matches)?),
+        Some((s, matches)) if s == "export-all" => Box::new(app::export_all(matches)?),
+        Some((s, matches)) if s == "import" => Box::new(app::import(matches)?),
+        Some((s, matches)) if s == "rekey" => Box::new(app::rekey(matches)?),
         Some((s, matches)) if s == "serve" => Box::new(app::serve(matches)?),
+        Some((s, matches)) if s == "android-backup" => Box::new(app::android_backup(matches)?),
+        Some((s, matches)) if s == "analyze" => Box::new(app::analyze(matches)?),
+        Some((s, matches)) if s == "signatures" => Box::new(app::signatures(matches)?),
         _ => Box::new(app::export(subcommand_export().get_matches())?),
     };
     app.run()?;
     Ok(())
 }
 
-fn common_args() -> [Arg; 4] {
+fn common_args() -> [Arg; 9] {
     [
         arg!([file] "NT QQ 数据库文件。如果未提供，将尝试自动检测"),
         arg!(-p --pkey <pkey> "数据库密钥。如果未提供，将尝试自动探测"),
-        arg!(-N --nocopy "默认情况下，会先将db文件复制到一个临时文件，再去操作临时文件。启用此选项以直接读取原始数据库文件。注意：这可能损坏你的数据库！")
+        arg!(--"kdf-iter" <N> "数据库的 PBKDF2 迭代次数，仅用于读取经 rekey 修改过参数的数据库。默认为 NTQQ 使用的 4000")
+        .value_parser(value_parser!(usize)),
+        arg!(-N --nocopy "默认情况下，会先将db文件复制到一个临时文件，再去操作临时文件。启用此选项以直接读取原始数据库文件：export 和 serve 会以只读方式打开原文件，不会修改它（但若存在 -wal 文件，会像其他读者一样使用 -shm 文件以读取其中尚未写回的最新消息）；export-all 则可能损坏你的数据库！")
         .action(ArgAction::SetTrue),
-        arg!(--"android-uid" <UID> "如果确信这是一个 android NTQQ 的数据库，那么提供 uid 可以直接解密")
+        arg!(--"android-uid" <UID> "如果确信这是一个 android NTQQ 的数据库，那么提供 uid 可以直接解密"),
+        arg!(--"android-uid-from" <PATH> "从文件或目录（如QQ数据目录下的 files/uid）中收集候选 uid，自动找出 android NTQQ 数据库所属的 uid 并解密")
+        .value_parser(value_parser!(PathBuf))
+        .conflicts_with("android-uid"),
+        arg!(--"android-package" <PACKAGE> "Android 下自动检测数据库时查找的QQ包名，可多次指定。默认查找 QQ、TIM 和 QQ轻聊版")
+        .action(ArgAction::Append),
+        arg!(-a --attach [PID] "自动提取密钥时，附加到正在运行的QQ进程，而不是启动一个新的QQ进程。未提供 PID 时自动查找QQ进程")
+        .value_parser(value_parser!(u32)),
+        arg!(--dump <DUMP> "从QQ进程的内存转储文件（ELF core、minidump 或原始内存）中恢复数据库密钥，而无需调试QQ进程")
+        .value_parser(value_parser!(PathBuf)),
     ]
 }
 fn subcommand_export() -> Command {
@@ -53,6 +70,48 @@ fn cmd() -> Command {
         )
         .args_conflicts_with_subcommands(true)
         .subcommand(subcommand_export())
+        .subcommand(
+            command!("export-all")
+                .about("将账号数据库目录下的所有数据库导出为未加密 sqlite 数据库，并生成清单文件")
+                .args(common_args())
+                .mut_arg("file", |a| {
+                    a.help("NT QQ 账号的数据库目录，或其中的任一数据库文件。如果未提供，将尝试自动检测")
+                })
+                .args([arg!(-o --output <DIR> "输出目录")
+                    .value_parser(value_parser!(PathBuf))
+                    .default_value("./nt_unwraped")]),
+        )
+        .subcommand(
+            command!("import")
+                .about("将未加密 sqlite 数据库重新加密为 NTQQ 数据库，即 export 的逆操作，以便将修改或合并后的聊天记录导回QQ")
+                .args(common_args())
+                .mut_arg("file", |a| {
+                    a.help("作为模板的 NT QQ 数据库文件，将沿用其文件头和密钥。如果未提供，将尝试自动检测")
+                })
+                .args([
+                    arg!(-i --input <PATH> "未加密的 sqlite 数据库文件")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                    arg!(-o --output <PATH> "输出文件。可以是模板数据库文件本身，以直接替换它，此时请先退出QQ")
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value("./nt_imported.db"),
+                ]),
+        )
+        .subcommand(
+            command!("rekey")
+                .about("更换数据库密钥，如改为自己的密码以便存档。可同时更换 HMAC 算法和 PBKDF2 迭代次数，文件头保持不变")
+                .args(common_args())
+                .args([
+                    arg!(--"new-key" <KEY> "新密钥").required(true),
+                    arg!(--"new-hmac" <ALGORITHM> "新的 HMAC 算法。默认保持不变")
+                        .value_parser(["HMAC_SHA1", "HMAC_SHA256"]),
+                    arg!(--"new-kdf-iter" <N> "新的 PBKDF2 迭代次数。默认为 NTQQ 使用的 4000，此后读取时需通过 --kdf-iter 指定")
+                        .value_parser(value_parser!(usize)),
+                    arg!(-o --output <PATH> "输出文件，原数据库会先被复制到这里再更换密钥。可以是原数据库文件本身，以直接修改它，此时请先退出QQ")
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value("./nt_rekeyed.db"),
+                ]),
+        )
         .subcommand(
             command!("serve")
                 .about("启动一个 web 服务，以通过 HTTP API 读取数据库内容。")
@@ -61,4 +120,47 @@ fn cmd() -> Command {
                     .value_parser(value_parser!(std::net::SocketAddr))
                     .default_value("127.0.0.1:19551")]),
         )
+        .subcommand(
+            command!("android-backup")
+                .about("从 Android QQ 的数据备份中找出所有账号的数据库，并全部导出为未加密 sqlite 数据库")
+                .args([
+                    arg!(<backup> "备份目录，或其 .tar/.tar.gz 归档，或未加密的 adb backup 文件")
+                        .value_parser(value_parser!(PathBuf)),
+                    arg!(-o --output <DIR> "输出目录，每个账号一个子目录")
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value("./nt_unwraped_android"),
+                ]),
+        )
+        .subcommand(
+            command!("analyze")
+                .about("分析 wrapper.node 以定位密钥函数。可在任意系统上分析任意平台的 wrapper.node")
+                .args([
+                    arg!(<file> "wrapper.node 文件").value_parser(value_parser!(PathBuf)),
+                    arg!(--json "以 JSON 格式输出完整的分析报告").action(ArgAction::SetTrue),
+                    arg!(--pattern <PATTERN> "额外搜索的特征字符串，可多次指定")
+                        .action(ArgAction::Append),
+                    arg!(-s --save "将结果记录到签名数据库，见 signatures 子命令")
+                        .action(ArgAction::SetTrue),
+                    arg!(--"qq-version" <VERSION> "随结果记录到签名数据库的QQ版本号")
+                        .requires("save"),
+                    arg!(--arch <ARCH> "分析 macOS Universal 二进制中哪个架构的切片。默认为当前系统的架构")
+                        .value_parser(["x86_64", "arm64"]),
+                ]),
+        )
+        .subcommand(
+            command!("signatures")
+                .about("管理 wrapper.node 的签名数据库。其中记录了已分析过的QQ版本的密钥函数地址，以免重复分析。不带子命令时列出所有签名")
+                .args([arg!(-d --database <PATH> "签名数据库路径。默认位于用户缓存目录下")
+                    .value_parser(value_parser!(PathBuf))])
+                .subcommand(
+                    command!("export")
+                        .about("导出签名数据库，以便分享给他人")
+                        .args([arg!(<file> "导出文件").value_parser(value_parser!(PathBuf))]),
+                )
+                .subcommand(
+                    command!("import")
+                        .about("导入他人导出的签名数据库，合并到本地")
+                        .args([arg!(<file> "导入文件").value_parser(value_parser!(PathBuf))]),
+                ),
+        )
 }
@@ -0,0 +1,103 @@
+//! Parser of the header NTQQ puts in front of the SQLCipher data of its databases.
+
+use core::fmt;
+use core::ops::Range;
+
+/// The 1024-byte header of an NTQQ database file.
+///
+/// Known fields:
+/// - `[0, 16)`: magic.
+/// - `[32, 40)`: tag, always `QQ_NT DB`. This is what identifies an NTQQ database.
+/// - somewhere after the tag: `rand`, the first run of 8 or more printable ASCII chars.
+///   Android NTQQ derives the database key from it.
+///
+/// Nothing else is known so far, see [NtDbHeader::raw] for the remaining bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtDbHeader<'a> {
+    raw: &'a [u8],
+    rand: Option<Range<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// Less than [NtDbHeader::TAG_RANGE] bytes are given.
+    TooShort,
+    /// The `QQ_NT DB` tag is missing, it's likely not an NTQQ database.
+    NoTag,
+}
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::TooShort => f.write_str("too short for an NTQQ database header"),
+            HeaderError::NoTag => f.write_str("no `QQ_NT DB` tag in header"),
+        }
+    }
+}
+
+impl<'a> NtDbHeader<'a> {
+    /// Length of the header, which is also where the SQLCipher data starts.
+    pub const LEN: usize = 1024;
+    pub const MAGIC_RANGE: Range<usize> = 0..16;
+    pub const TAG_RANGE: Range<usize> = 32..40;
+    pub const TAG: &'static [u8; 8] = b"QQ_NT DB";
+    /// Minimum length of the `rand` string.
+    pub const RAND_MIN_LEN: usize = 8;
+
+    /// Parse the header from the start of a database file.
+    ///
+    /// Theoretically `buf` should hold the whole [NtDbHeader::LEN] bytes.
+    /// Passing less is fine as long as it reaches the end of the tag, but fields after it may then be missing.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, HeaderError> {
+        let raw = &buf[..buf.len().min(Self::LEN)];
+        let tag = raw.get(Self::TAG_RANGE).ok_or(HeaderError::TooShort)?;
+        if tag != Self::TAG {
+            return Err(HeaderError::NoTag);
+        }
+        Ok(Self {
+            raw,
+            rand: Self::find_rand(raw, Self::TAG_RANGE.end),
+        })
+    }
+
+    /// Find the first run of [NtDbHeader::RAND_MIN_LEN] or more printable chars after `from`,
+    /// which must be terminated by a non-printable byte.
+    fn find_rand(raw: &[u8], from: usize) -> Option<Range<usize>> {
+        let mut begin = from;
+        for (i, byte) in raw.iter().enumerate().skip(from) {
+            if byte.is_ascii_graphic() {
+                continue;
+            }
+            if i - begin >= Self::RAND_MIN_LEN {
+                return Some(begin..i);
+            }
+            begin = i + 1;
+        }
+        None
+    }
+
+    pub fn magic(&self) -> &'a [u8] {
+        &self.raw[Self::MAGIC_RANGE]
+    }
+    pub fn tag(&self) -> &'a [u8] {
+        &self.raw[Self::TAG_RANGE]
+    }
+    /// The `rand` string, if found.
+    ///
+    /// As explained [here](https://github.com/QQBackup/qq-win-db-key/blob/master/%E6%95%99%E7%A8%8B%20-%20NTQQ%20(Android).md),
+    /// it's always printable ASCII.
+    pub fn rand(&self) -> Option<&'a [u8]> {
+        self.rand.clone().map(|x| &self.raw[x])
+    }
+    /// Where the rand string is in the header.
+    pub fn rand_range(&self) -> Option<Range<usize>> {
+        self.rand.clone()
+    }
+    /// Offset of the SQLite (SQLCipher) data in the file.
+    pub fn data_offset(&self) -> u64 {
+        Self::LEN as u64
+    }
+    /// The header bytes as given to [NtDbHeader::parse], at most [NtDbHeader::LEN] bytes.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+}
@@ -1,5 +1,13 @@
 #![no_std]
 
+// the cdylib is built along with the rlib even when used as a Rust dependency,
+// and then it has no panic handler of its own, so take the one of std.
+// libsqlite3-sys is no_std as well unless it bundles OpenSSL.
+#[cfg(not(feature = "_cdylib"))]
+extern crate std;
+
+mod header;
+pub use header::*;
 mod offset_vfs;
 pub use offset_vfs::*;
 
@@ -2,6 +2,26 @@
 //! As NTQQ database has a custom file header of 1024 bytes, this VFS is used to skip the header while
 //! keeping the original file intact, so as to avoid extra disk op cost.
 //!
+//! Only the main database file has the header, so only it is opened with the offset.
+//! The WAL (`-wal`), the wal-index (`-shm`) and journals are passed through to the underlying VFS untouched:
+//! WAL frames carry page images keyed by page number, which never refer to positions in the main file,
+//! and pages are written back through the offset only when checkpointed into the main file.
+//! So a database copied together with its sidecars shows the transactions still in the WAL.
+//!
+//! The offset can be set per database with URI parameters, e.g. `file:nt_msg.db?offset=2048&detect=off`:
+//! - `offset=N`: skip `N` bytes instead of the length of the header.
+//! - `detect=off`: don't look for the `QQ_NT DB` tag, always apply the offset,
+//!   which is needed for files with other header layouts. By default the offset applies only when the tag is found.
+//!
+//! How a database is actually opened can be queried with the [FCNTL_OFFSET_INFO] file control.
+//!
+//! There's also a strict read-only variant, [OFFSET_VFS_READONLY_NAME], to read a database in place, even one in use.
+//! Files with the NTQQ header are opened read-only, and writes, truncates and syncs to them are refused
+//! with `SQLITE_READONLY`. Any other file, e.g. a plain database attached to export into, is passed through as usual.
+//! If the database has a WAL, it's read along with the transactions not yet checkpointed, as any reader does,
+//! which takes the usual locks and needs the wal-index `-shm` (SQLite creates it if missing).
+//! Otherwise the database is reported as [SQLITE_IOCAP_IMMUTABLE], so it's read without locking or any sidecar.
+//!
 //! **Warning**: This VFS is specifically designed to read NTQQ database files,
 //! any other intended use that out of this project is undefined.
 
@@ -11,22 +31,12 @@ use core::ptr;
 use core::{mem::MaybeUninit, ptr::null};
 use libsqlite3_sys::*;
 
+use crate::NtDbHeader;
+
 // workaround for no_std
 #[allow(non_camel_case_types)]
 type sqlite3_filename = *const c_char;
 
-/// theoretically, the slice expect 1024 bytes.  
-/// As of now, it works by checking the fixed 8 bytes in [32,40] of the header.
-/// So passing a slice of no less than 40 bytes is enough.
-///
-/// Return:
-///   - [Some]: `true`` if the header matches the NTQQ database header, `false`` if not.
-///   - [None]: there is no enough information to definitively determine if it's a NTQQ database.
-fn match_ntqq_db_header(buf: &[u8]) -> Option<bool> {
-    // test fixed 8 bytes in [32,40] of the header
-    const NT_QQ_DB_PAT: [u8; 8] = [0x51, 0x51, 0x5f, 0x4e, 0x54, 0x20, 0x44, 0x42];
-    buf.get(32..40).map(|x| x == NT_QQ_DB_PAT)
-}
 #[cfg(feature = "_cdylib")]
 static mut SQLITE3_API: MaybeUninit<&sqlite3_api_routines> = MaybeUninit::uninit();
 #[inline(always)]
@@ -40,11 +50,33 @@ unsafe fn orig_file(ptr: *const sqlite3_file) -> *mut sqlite3_file {
 
 pub const OFFSET_VFS_NAME: &str = "offset_vfs";
 const OFFSET_VFS_NAME_C: &CStr = c"offset_vfs";
+pub const OFFSET_VFS_READONLY_NAME: &str = "offset_vfs_ro";
+const OFFSET_VFS_READONLY_NAME_C: &CStr = c"offset_vfs_ro";
+
+/// File control opcode to query how a main database is opened by the offset VFS,
+/// the argument is a `*mut` [OffsetInfo] to fill in.
+///
+/// Use it with `sqlite3_file_control(db, "main", FCNTL_OFFSET_INFO, &mut info)`.
+/// Databases not opened by the offset VFS return `SQLITE_NOTFOUND`.
+pub const FCNTL_OFFSET_INFO: c_int = 0x4e54_4442;
+
+/// See [FCNTL_OFFSET_INFO].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OffsetInfo {
+    /// How many bytes are skipped, 0 if the file is passed through as is.
+    pub offset: u64,
+    /// Whether the NTQQ header is found, always 0 if detection is turned off by `detect=off`.
+    pub header_detected: c_int,
+}
 
 #[repr(C)]
 struct OffsetFile {
     base: sqlite3_file,
     offset: u64,
+    header_detected: bool,
+    /// Reported as [SQLITE_IOCAP_IMMUTABLE], see [OFFSET_VFS_READONLY].
+    immutable: bool,
     /// this field is of variable length actually, but we only care about the [sqlite3_file] part.  
     /// However this affects the size we need to define in [sqlite3_vfs].
     /// see the `szOsFile`` field of [OFFSET_VFS] and the [register_offset_vfs] function for more details.
@@ -78,6 +110,13 @@ static mut OFFSET_VFS: sqlite3_vfs = sqlite3_vfs {
     xSetSystemCall: Some(offset_set_system_call),       /* xNextSystemCall */
 };
 
+/// Same as [OFFSET_VFS] but opens NTQQ databases read-only, see the module doc.
+static mut OFFSET_VFS_READONLY: sqlite3_vfs = sqlite3_vfs {
+    zName: OFFSET_VFS_READONLY_NAME_C.as_ptr(),
+    xOpen: Some(offset_open_readonly),
+    ..unsafe { OFFSET_VFS }
+};
+
 static OFFSET_IO_METHODS: sqlite3_io_methods = sqlite3_io_methods {
     iVersion: 3,                                                 /* iVersion */
     xClose: Some(offset_close),                                  /* xClose */
@@ -100,6 +139,15 @@ static OFFSET_IO_METHODS: sqlite3_io_methods = sqlite3_io_methods {
     xUnfetch: Some(offset_unfetch),                              /* xUnfetch */
 };
 
+/// For NTQQ databases opened by [OFFSET_VFS_READONLY].
+static OFFSET_READONLY_IO_METHODS: sqlite3_io_methods = sqlite3_io_methods {
+    xWrite: Some(readonly_write),
+    xTruncate: Some(readonly_truncate),
+    xSync: Some(readonly_sync),
+    xDeviceCharacteristics: Some(readonly_device_characteristics),
+    ..OFFSET_IO_METHODS
+};
+
 unsafe extern "C" fn offset_close(p_file: *mut sqlite3_file) -> c_int {
     let orig_file = orig_file(p_file);
     (*(*orig_file).pMethods).xClose.unwrap_unchecked()(orig_file)
@@ -186,6 +234,13 @@ unsafe extern "C" fn offset_file_control(
 ) -> c_int {
     let file = p_file as *mut OffsetFile;
     let orig_file = &mut (*file).origin;
+    if op == FCNTL_OFFSET_INFO {
+        *(p_arg as *mut OffsetInfo) = OffsetInfo {
+            offset: (*file).offset,
+            header_detected: (*file).header_detected as c_int,
+        };
+        return SQLITE_OK;
+    }
     if op == SQLITE_FCNTL_SIZE_HINT {
         let p_arg = p_arg as *mut sqlite3_int64;
         *p_arg += (*file).offset as sqlite3_int64;
@@ -196,14 +251,14 @@ unsafe extern "C" fn offset_file_control(
         *p_arg = {
             #[cfg(not(feature = "_cdylib"))]
             {
-                sqlite3_mprintf(c"offset(%s)/%z".as_ptr(), (*file).offset, p_arg)
+                sqlite3_mprintf(c"offset(%llu)/%z".as_ptr(), (*file).offset, *p_arg)
             }
             #[cfg(feature = "_cdylib")]
             {
                 SQLITE3_API.assume_init().mprintf.unwrap_unchecked()(
-                    c"offset(%s)/%z".as_ptr(),
+                    c"offset(%llu)/%z".as_ptr(),
                     (*file).offset,
-                    p_arg,
+                    *p_arg,
                 )
             }
         }
@@ -220,6 +275,33 @@ unsafe extern "C" fn offset_device_characteristics(p_file: *mut sqlite3_file) ->
         .xDeviceCharacteristics
         .unwrap_unchecked()(orig_file)
 }
+#[allow(unused_variables)]
+unsafe extern "C" fn readonly_write(
+    p_file: *mut sqlite3_file,
+    p_buf: *const c_void,
+    i_amt: i32,
+    i_ofst: sqlite3_int64,
+) -> c_int {
+    SQLITE_READONLY
+}
+#[allow(unused_variables)]
+unsafe extern "C" fn readonly_truncate(p_file: *mut sqlite3_file, size: sqlite3_int64) -> c_int {
+    SQLITE_READONLY
+}
+#[allow(unused_variables)]
+unsafe extern "C" fn readonly_sync(p_file: *mut sqlite3_file, flags: i32) -> c_int {
+    SQLITE_READONLY
+}
+/// Immutable if there's no WAL, so that SQLite neither locks the file nor looks for a journal to recover,
+/// which would need to write.
+unsafe extern "C" fn readonly_device_characteristics(p_file: *mut sqlite3_file) -> c_int {
+    let characteristics = offset_device_characteristics(p_file);
+    if (*(p_file as *mut OffsetFile)).immutable {
+        characteristics | SQLITE_IOCAP_IMMUTABLE
+    } else {
+        characteristics
+    }
+}
 unsafe extern "C" fn offset_shm_map(
     p_file: *mut sqlite3_file,
     i_pg: i32,
@@ -289,39 +371,156 @@ unsafe extern "C" fn offset_open(
     p_file: *mut sqlite3_file,
     flags: c_int,
     p_out_flags: *mut c_int,
+) -> c_int {
+    open(vfs, z_name, p_file, flags, p_out_flags, false)
+}
+/// Like [offset_open], but NTQQ databases are opened read-only, see [OFFSET_VFS_READONLY].
+unsafe extern "C" fn offset_open_readonly(
+    vfs: *mut sqlite3_vfs,
+    z_name: sqlite3_filename,
+    p_file: *mut sqlite3_file,
+    flags: c_int,
+    p_out_flags: *mut c_int,
+) -> c_int {
+    open(vfs, z_name, p_file, flags, p_out_flags, true)
+}
+/// Settings of a main database from the URI parameters of its name, see the module doc.
+struct OpenParams {
+    offset: Option<u64>,
+    detect: bool,
+}
+impl OpenParams {
+    /// `None` if `offset` is not a non-negative integer.
+    unsafe fn from_uri(z_name: sqlite3_filename) -> Option<Self> {
+        let offset = if uri_parameter(z_name, c"offset").is_null() {
+            None
+        } else {
+            Some(u64::try_from(uri_int64(z_name, c"offset", -1)).ok()?)
+        };
+        Some(Self {
+            offset,
+            detect: uri_boolean(z_name, c"detect", 1) != 0,
+        })
+    }
+}
+unsafe fn uri_parameter(z_name: sqlite3_filename, param: &CStr) -> *const c_char {
+    #[cfg(not(feature = "_cdylib"))]
+    {
+        sqlite3_uri_parameter(z_name, param.as_ptr())
+    }
+    #[cfg(feature = "_cdylib")]
+    {
+        SQLITE3_API.assume_init().uri_parameter.unwrap_unchecked()(z_name, param.as_ptr())
+    }
+}
+unsafe fn uri_boolean(z_name: sqlite3_filename, param: &CStr, default: c_int) -> c_int {
+    #[cfg(not(feature = "_cdylib"))]
+    {
+        sqlite3_uri_boolean(z_name, param.as_ptr(), default)
+    }
+    #[cfg(feature = "_cdylib")]
+    {
+        SQLITE3_API.assume_init().uri_boolean.unwrap_unchecked()(z_name, param.as_ptr(), default)
+    }
+}
+unsafe fn filename_wal(z_name: sqlite3_filename) -> sqlite3_filename {
+    #[cfg(not(feature = "_cdylib"))]
+    {
+        sqlite3_filename_wal(z_name)
+    }
+    #[cfg(feature = "_cdylib")]
+    {
+        SQLITE3_API.assume_init().filename_wal.unwrap_unchecked()(z_name)
+    }
+}
+unsafe fn uri_int64(
+    z_name: sqlite3_filename,
+    param: &CStr,
+    default: sqlite3_int64,
+) -> sqlite3_int64 {
+    #[cfg(not(feature = "_cdylib"))]
+    {
+        sqlite3_uri_int64(z_name, param.as_ptr(), default)
+    }
+    #[cfg(feature = "_cdylib")]
+    {
+        SQLITE3_API.assume_init().uri_int64.unwrap_unchecked()(z_name, param.as_ptr(), default)
+    }
+}
+unsafe fn open(
+    vfs: *mut sqlite3_vfs,
+    z_name: sqlite3_filename,
+    p_file: *mut sqlite3_file,
+    flags: c_int,
+    p_out_flags: *mut c_int,
+    readonly: bool,
 ) -> c_int {
     let file = p_file as *mut OffsetFile;
     let base_vfs = orig_vfs(vfs);
     let base_file = orig_file(p_file);
 
-    // there's only offset on main db
+    // there's only offset on main db, the WAL and others are relative to pages, not to the file
     if (flags & SQLITE_OPEN_MAIN_DB) == 0 {
         // use our p_file as the underlying type, it's definitely long enough, after this we no longer take care of it
         return (*base_vfs).xOpen.unwrap_unchecked()(base_vfs, z_name, p_file, flags, p_out_flags);
     }
 
+    let Some(params) = OpenParams::from_uri(z_name) else {
+        return SQLITE_CANTOPEN;
+    };
     *base_file = core::mem::zeroed();
     let rc: c_int =
         (*base_vfs).xOpen.unwrap_unchecked()(base_vfs, z_name, base_file, flags, p_out_flags);
     if rc != SQLITE_OK {
         return rc;
     }
-    (*p_file).pMethods = &OFFSET_IO_METHODS;
 
-    let mut buf = [0u8; 1024];
-    (*base_file).pMethods.as_ref().unwrap().xRead.unwrap()(
-        base_file,
-        buf.as_mut_ptr() as *mut c_void,
-        1024,
-        0,
-    );
-    // fixed offset of 1024
-    (*file).offset = if let Some(true) = match_ntqq_db_header(&buf) {
-        1024
-    } else {
-        0
+    (*file).header_detected = params.detect && {
+        let mut buf = [0u8; NtDbHeader::LEN];
+        (*base_file).pMethods.as_ref().unwrap().xRead.unwrap()(
+            base_file,
+            buf.as_mut_ptr() as *mut c_void,
+            NtDbHeader::LEN as c_int,
+            0,
+        );
+        NtDbHeader::parse(&buf).is_ok()
     };
-    rc
+    let is_ntqq_db = (*file).header_detected || !params.detect;
+    (*file).immutable = false;
+    (*file).offset = match params.offset {
+        _ if !is_ntqq_db => 0,
+        Some(offset) => offset,
+        None => NtDbHeader::LEN as u64,
+    };
+    if !readonly || !is_ntqq_db {
+        (*p_file).pMethods = &OFFSET_IO_METHODS;
+        return rc;
+    }
+
+    // reopen read-only, so the file is never written even by mistake
+    if (flags & SQLITE_OPEN_READONLY) == 0 {
+        (*(*base_file).pMethods).xClose.unwrap_unchecked()(base_file);
+        *base_file = core::mem::zeroed();
+        let flags = (flags & !(SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE)) | SQLITE_OPEN_READONLY;
+        let rc =
+            (*base_vfs).xOpen.unwrap_unchecked()(base_vfs, z_name, base_file, flags, p_out_flags);
+        if rc != SQLITE_OK {
+            // SQLite won't close a file failed to open
+            (*p_file).pMethods = ptr::null();
+            return rc;
+        }
+    }
+    // the WAL is read only if the database is not immutable
+    let mut has_wal = 0;
+    let rc = (*base_vfs).xAccess.unwrap_unchecked()(
+        base_vfs,
+        filename_wal(z_name),
+        SQLITE_ACCESS_EXISTS,
+        &mut has_wal,
+    );
+    (*file).immutable = rc == SQLITE_OK && has_wal == 0;
+    (*p_file).pMethods = &OFFSET_READONLY_IO_METHODS;
+    SQLITE_OK
 }
 
 #[allow(unused_variables)]
@@ -426,6 +625,9 @@ unsafe fn update_vfs_from_orig(origin: *mut sqlite3_vfs) {
     OFFSET_VFS.iVersion = (*origin).iVersion;
     OFFSET_VFS.pAppData = origin as *mut c_void;
     OFFSET_VFS.szOsFile += (*origin).szOsFile;
+    OFFSET_VFS_READONLY.iVersion = (*origin).iVersion;
+    OFFSET_VFS_READONLY.pAppData = origin as *mut c_void;
+    OFFSET_VFS_READONLY.szOsFile += (*origin).szOsFile;
 }
 
 /// Entry point for SQLite to load the extension as a C dynamic library.
@@ -441,8 +643,12 @@ pub unsafe extern "C" fn sqlite3_sqliteextntqqdb_init(
     SQLITE3_API = MaybeUninit::new(p_api);
     let origin = SQLITE3_API.assume_init().vfs_find.unwrap_unchecked()(null());
     update_vfs_from_orig(origin);
-    match SQLITE3_API.assume_init().vfs_register.unwrap_unchecked()(&raw mut OFFSET_VFS, 1) {
-        SQLITE_OK => SQLITE_OK_LOAD_PERMANENTLY,
+    let vfs_register = SQLITE3_API.assume_init().vfs_register.unwrap_unchecked();
+    match vfs_register(&raw mut OFFSET_VFS, 1) {
+        SQLITE_OK => match vfs_register(&raw mut OFFSET_VFS_READONLY, 0) {
+            SQLITE_OK => SQLITE_OK_LOAD_PERMANENTLY,
+            rc => rc,
+        },
         rc => rc,
     }
 }
@@ -454,12 +660,15 @@ mod rlib {
 
     use super::*;
 
-    /// Register the offset VFS to SQLite.
+    /// Register the offset VFS to SQLite as the default VFS, along with its read-only variant.
     pub fn register_offset_vfs() -> Result<(), i32> {
         match unsafe {
             let origin = sqlite3_vfs_find(null());
             update_vfs_from_orig(origin);
-            sqlite3_vfs_register(&raw mut OFFSET_VFS, 1)
+            match sqlite3_vfs_register(&raw mut OFFSET_VFS, 1) {
+                0 => sqlite3_vfs_register(&raw mut OFFSET_VFS_READONLY, 0),
+                rc => rc,
+            }
         } {
             0 => Ok(()),
             rc => Err(rc),
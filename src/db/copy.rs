@@ -0,0 +1,116 @@
+use super::*;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Suffixes of the files SQLite keeps next to a database in WAL mode, as NTQQ databases are.
+pub const SIDECAR_SUFFIXES: [&str; 2] = ["-wal", "-shm"];
+
+/// How many times [copy_db] tries before giving up on a database that keeps changing.
+const COPY_ATTEMPTS: u32 = 5;
+
+/// The path of the sidecar of `db` with `suffix`, e.g. `nt_msg.db-wal`.
+pub fn sidecar_path(db: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(db.as_os_str());
+    path.push(suffix);
+    path.into()
+}
+
+/// Whether `path` is a sidecar of some database, judging by its name.
+pub fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .map(|x| x.to_string_lossy())
+        .is_some_and(|x| SIDECAR_SUFFIXES.iter().any(|suffix| x.ends_with(suffix)))
+}
+
+/// Copy the database `src` together with its `-wal` and `-shm` sidecars to `dest`.
+///
+/// The newest transactions of a database in use, e.g. by a running QQ, are usually still in the WAL,
+/// so copying only the main file loses them, or even gets an inconsistent snapshot if a checkpoint happens meanwhile.
+/// All the files are copied again if any of them changes during the copy, until [COPY_ATTEMPTS] is reached.
+///
+/// Sidecars of `dest` that `src` doesn't have are removed, so stale ones are never applied to the copy.
+pub fn copy_db(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> crate::Result<()> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    for attempt in 1..=COPY_ATTEMPTS {
+        let before = db_state(src)?;
+        fs::copy(src, dest).context(IoSnafu {
+            op: format!("copy {}", src.display()),
+        })?;
+        for suffix in SIDECAR_SUFFIXES {
+            let (src, dest) = (sidecar_path(src, suffix), sidecar_path(dest, suffix));
+            match fs::copy(&src, &dest) {
+                Ok(_) => {}
+                // the sidecars come and go with the connections to the database
+                Err(e) if e.kind() == ErrorKind::NotFound => remove_if_exists(&dest)?,
+                Err(e) => {
+                    return Err(e).context(IoSnafu {
+                        op: format!("copy {}", src.display()),
+                    })?;
+                }
+            }
+        }
+        if db_state(src)? == before {
+            return Ok(());
+        }
+        log::warn!(
+            "{} changed while copying, retrying ({}/{})",
+            src.display(),
+            attempt,
+            COPY_ATTEMPTS
+        );
+        thread::sleep(Duration::from_millis(100 * attempt as u64));
+    }
+    ChangedWhileCopyingSnafu {
+        path: src.to_owned(),
+        attempts: COPY_ATTEMPTS,
+    }
+    .fail()
+    .map_err(crate::Error::from)
+}
+
+/// Remove the database `path` together with its sidecars, e.g. a copy made by [copy_db].
+pub fn remove_db(path: impl AsRef<Path>) -> crate::Result<()> {
+    let path = path.as_ref();
+    fs::remove_file(path).context(IoSnafu {
+        op: format!("remove {}", path.display()),
+    })?;
+    for suffix in SIDECAR_SUFFIXES {
+        remove_if_exists(&sidecar_path(path, suffix))?;
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> crate::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context(IoSnafu {
+            op: format!("remove {}", path.display()),
+        })?,
+        _ => Ok(()),
+    }
+}
+
+/// Size and modification time of the database `path` and each of its sidecars, `None` for missing sidecars.
+fn db_state(path: &Path) -> crate::Result<Vec<Option<(u64, SystemTime)>>> {
+    let files = std::iter::once(path.to_owned())
+        .chain(SIDECAR_SUFFIXES.iter().map(|x| sidecar_path(path, x)));
+    let mut state = Vec::with_capacity(SIDECAR_SUFFIXES.len() + 1);
+    for file in files {
+        match fs::metadata(&file) {
+            Ok(meta) => state.push(Some((
+                meta.len(),
+                meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            ))),
+            Err(e) if e.kind() == ErrorKind::NotFound && file != path => state.push(None),
+            Err(e) => {
+                return Err(e).context(IoSnafu {
+                    op: format!("stat {}", file.display()),
+                })?;
+            }
+        }
+    }
+    Ok(state)
+}
@@ -0,0 +1,247 @@
+use super::*;
+use crate::sqlcipher;
+use rusqlite::OpenFlags;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Try to decrypt the main database of `conn` with `d`.
+///
+/// If `d.cipher_hmac_algorithm` is `None`, all known algorithms are tried in turn.
+/// Use [try_decrypt_db_info] to learn which one worked.
+pub fn try_decrypt_db(conn: &Connection, d: ntqq::DBDecryptInfo) -> crate::Result<()> {
+    try_decrypt_db_info(conn, d).map(|_| ())
+}
+
+/// Same as [try_decrypt_db], but returns `d` with the working hmac algorithm filled in.
+pub fn try_decrypt_db_info(
+    conn: &Connection,
+    mut d: ntqq::DBDecryptInfo,
+) -> crate::Result<ntqq::DBDecryptInfo> {
+    // in-memory databases have an empty path
+    if let Some(path) = conn.path().filter(|x| !x.is_empty()) {
+        pick_hmac_algorithm(Path::new(path), &mut d);
+    }
+    for algo in candidate_hmac_algorithms(&mut d) {
+        log::debug!("trying hmac algorithm: {}", algo);
+        d.cipher_hmac_algorithm = Some(algo);
+        let stmt = d.display_pragma_stmts().to_string();
+        conn.execute_batch(&stmt)
+            .context(SqliteSnafu { op: stmt })?;
+        let stmt = conn.prepare("SELECT count(*) FROM sqlite_master;");
+        match stmt {
+            Ok(mut stmt) => {
+                if stmt.exists([]).context(SqliteSnafu { op: "stmt.exists" })? {
+                    log::info!("decryped successfully");
+                    return Ok(d);
+                }
+            }
+            Err(e) => {
+                log::debug!("attempt failed: {}", e);
+            }
+        }
+    }
+    WrongDecryptInfoSnafu.fail().map_err(crate::Error::from)
+}
+
+/// Attach the encrypted NTQQ database `file` to `conn` as `schema`.
+///
+/// SQLCipher reads the first page as soon as a database is attached,
+/// so the cipher parameters have to be set as the (process-wide) defaults beforehand.
+/// The previous defaults are restored afterwards, also on errors.
+///
+/// The function is serialized with everything else in this crate that changes the defaults.
+/// Connections opened elsewhere in the meantime still get NTQQ's parameters as their defaults though.
+///
+/// Like [try_decrypt_db_info], all known algorithms are tried if `d.cipher_hmac_algorithm` is `None`,
+/// and the returned [ntqq::DBDecryptInfo] has the working algorithm filled in.
+pub fn attach_encrypted_db(
+    conn: &Connection,
+    file: impl AsRef<Path>,
+    schema: &str,
+    mut d: ntqq::DBDecryptInfo,
+) -> crate::Result<ntqq::DBDecryptInfo> {
+    pick_hmac_algorithm(file.as_ref(), &mut d);
+    let file = file.as_ref().to_string_lossy();
+    let defaults = CipherDefaultsGuard::new(conn)?;
+    for algo in candidate_hmac_algorithms(&mut d) {
+        log::debug!("trying hmac algorithm {} on {}", algo, file);
+        d.cipher_hmac_algorithm = Some(algo);
+        defaults.set(&d)?;
+        let attached = conn
+            .execute(
+                "ATTACH DATABASE ?1 AS ?2 KEY ?3",
+                (file.as_ref(), schema, d.key.as_str()),
+            )
+            .and_then(|_| {
+                conn.query_row(
+                    &format!("SELECT count(*) FROM \"{}\".sqlite_master;", schema),
+                    [],
+                    |_| Ok(()),
+                )
+            });
+        match attached {
+            Ok(()) => {
+                log::info!("attached {} as {}", file, schema);
+                return Ok(d);
+            }
+            Err(e) => {
+                log::debug!("attempt failed: {}", e);
+                // a failed ATTACH leaves nothing behind, but a failed query after it does
+                let _ = conn.execute("DETACH DATABASE ?1", [schema]);
+            }
+        }
+    }
+    WrongDecryptInfoSnafu.fail().map_err(crate::Error::from)
+}
+
+/// Attach `file` to `conn` as `schema`, for a new database encrypted with `d`,
+/// e.g. as the target of `sqlcipher_export`.
+///
+/// A new database takes the cipher parameters from the defaults as well,
+/// so this is serialized and restores them the same way as [attach_encrypted_db].
+/// `d.cipher_hmac_algorithm` must be `Some`.
+pub(super) fn attach_new_encrypted_db(
+    conn: &Connection,
+    file: &Path,
+    schema: &str,
+    d: &ntqq::DBDecryptInfo,
+) -> crate::Result<()> {
+    let defaults = CipherDefaultsGuard::new(conn)?;
+    defaults.set(d)?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS ?2 KEY ?3",
+        (file.to_string_lossy(), schema, d.key.as_str()),
+    )
+    .context(SqliteSnafu {
+        op: format!("attach {}", file.display()),
+    })?;
+    Ok(())
+}
+
+/// Open an in-memory database with the offset vfs,
+/// so that databases attached to it are read through the vfs as well.
+pub(super) fn open_memory_db() -> crate::Result<Connection> {
+    register_offset_vfs().map_err(|code| RegisterVfsSnafu { code }.build())?;
+    let conn = Connection::open_with_flags_and_vfs(
+        ":memory:",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        OFFSET_VFS_NAME,
+    )
+    .context(SqliteSnafu {
+        op: "open in-memory db",
+    })?;
+    Ok(conn)
+}
+
+/// If `d` has no hmac algorithm yet, find it by checking page 1 of `file` directly,
+/// which spares the failed attempts through SQLCipher.
+///
+/// Anything but a definite match leaves `d` untouched, and all algorithms are tried as usual.
+fn pick_hmac_algorithm(file: &Path, d: &mut ntqq::DBDecryptInfo) {
+    if d.cipher_hmac_algorithm.is_some() {
+        return;
+    }
+    match sqlcipher::verify_key_file(file, d) {
+        Ok(sqlcipher::KeyCheck::Match(algo)) => {
+            log::debug!("page 1 of {} verifies with {}", file.display(), algo);
+            d.cipher_hmac_algorithm = Some(algo.to_string());
+        }
+        r => log::debug!("key check of {}: {:?}", file.display(), r),
+    }
+}
+
+fn candidate_hmac_algorithms(d: &mut ntqq::DBDecryptInfo) -> impl Iterator<Item = String> + use<> {
+    let try_alg: [Option<String>; 2] = match d.cipher_hmac_algorithm.take() {
+        Some(algo) => [Some(algo), None],
+        None => ["HMAC_SHA256", "HMAC_SHA1"].map(|x| Some(x.to_string())),
+    };
+    try_alg.into_iter().flatten()
+}
+
+/// Serializes the changes to SQLCipher's process-wide `cipher_default_*` settings, see [CipherDefaultsGuard].
+static CIPHER_DEFAULTS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Holds [CIPHER_DEFAULTS_LOCK] and restores the `cipher_default_*` settings found on creation when dropped,
+/// so that NTQQ's parameters don't leak into later connections, whichever way the caller returns.
+struct CipherDefaultsGuard<'a> {
+    conn: &'a Connection,
+    saved: CipherDefaults,
+    _lock: MutexGuard<'static, ()>,
+}
+impl<'a> CipherDefaultsGuard<'a> {
+    fn new(conn: &'a Connection) -> crate::Result<Self> {
+        // the settings are restored on drop even if a holder panicked, so a poisoned lock is fine
+        let lock = CIPHER_DEFAULTS_LOCK
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(Self {
+            saved: CipherDefaults::query(conn)?,
+            conn,
+            _lock: lock,
+        })
+    }
+    /// Make the cipher parameters of `d` the defaults, until the guard is dropped.
+    fn set(&self, d: &ntqq::DBDecryptInfo) -> crate::Result<()> {
+        let stmt = d.display_default_pragma_stmts().to_string();
+        self.conn
+            .execute_batch(&stmt)
+            .context(SqliteSnafu { op: stmt })?;
+        Ok(())
+    }
+}
+impl Drop for CipherDefaultsGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.saved.restore(self.conn) {
+            log::warn!("failed to restore cipher defaults: {}", e);
+        }
+    }
+}
+
+/// Snapshot of SQLCipher's process-wide `cipher_default_*` settings.
+///
+/// SQLCipher reports all of them as text.
+struct CipherDefaults {
+    page_size: String,
+    kdf_iter: String,
+    hmac_algorithm: String,
+    kdf_algorithm: String,
+}
+impl CipherDefaults {
+    fn query(conn: &Connection) -> crate::Result<Self> {
+        fn pragma(conn: &Connection, name: &str) -> crate::Result<String> {
+            let stmt = format!("PRAGMA {};", name);
+            Ok(conn
+                .query_row(&stmt, [], |row| row.get(0))
+                .context(SqliteSnafu { op: stmt })?)
+        }
+        Ok(Self {
+            page_size: pragma(conn, "cipher_default_page_size")?,
+            kdf_iter: pragma(conn, "cipher_default_kdf_iter")?,
+            hmac_algorithm: pragma(conn, "cipher_default_hmac_algorithm")?,
+            kdf_algorithm: pragma(conn, "cipher_default_kdf_algorithm")?,
+        })
+    }
+    fn restore(&self, conn: &Connection) -> crate::Result<()> {
+        let stmt = format!(
+            "PRAGMA cipher_default_page_size = {};
+            PRAGMA cipher_default_kdf_iter = {};
+            PRAGMA cipher_default_hmac_algorithm = {};
+            PRAGMA cipher_default_kdf_algorithm = {};",
+            self.page_size, self.kdf_iter, self.hmac_algorithm, self.kdf_algorithm
+        );
+        conn.execute_batch(&stmt)
+            .context(SqliteSnafu { op: stmt })?;
+        Ok(())
+    }
+}
+
+pub fn export_to_plain(conn: &Connection, file: impl AsRef<Path>) -> crate::Result<()> {
+    let stmt = format!(
+        r#"ATTACH DATABASE '{}' AS plain KEY ''; 
+        SELECT sqlcipher_export('plain');
+        DETACH DATABASE plain;"#,
+        file.as_ref().display()
+    );
+    conn.execute_batch(&stmt)
+        .context(SqliteSnafu { op: stmt })?;
+    Ok(())
+}
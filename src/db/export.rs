@@ -0,0 +1,182 @@
+use super::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Summary of an [export_dir_to_plain] run, also written to [MANIFEST_FILE_NAME] in the output directory.
+///
+/// The key is deliberately left out, as the manifest sits next to the unencrypted databases.
+#[derive(Debug, Serialize)]
+pub struct ExportManifest {
+    pub source_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub cipher: &'static str,
+    pub cipher_page_size: usize,
+    pub kdf_iter: usize,
+    pub kdf_algorithm: &'static str,
+    pub databases: Vec<ExportedDb>,
+    /// Databases that carry the NTQQ header but could not be exported.
+    pub failed: Vec<FailedDb>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedDb {
+    pub file_name: String,
+    pub source_size: u64,
+    pub plain_size: u64,
+    pub cipher_hmac_algorithm: String,
+    pub table_count: usize,
+    pub row_count: i64,
+    /// Row count of each table.
+    pub tables: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedDb {
+    pub file_name: String,
+    pub error: String,
+}
+
+/// Export every NTQQ database in `dir` as a plain SQLite database with the same file name into `output_dir`.
+///
+/// `dir` is an account's database directory, e.g. `Tencent Files/<uin>/nt_qq/nt_db` on Windows
+/// or `databases/nt_db/nt_qq_<hash>` on Android.
+/// Files without the NTQQ header are ignored, and existing files in `output_dir` are overwritten.
+///
+/// A database failing to export does not stop the others, it's recorded in [ExportManifest::failed] instead.
+pub fn export_dir_to_plain(
+    dir: impl AsRef<Path>,
+    output_dir: impl AsRef<Path>,
+    d: ntqq::DBDecryptInfo,
+) -> crate::Result<ExportManifest> {
+    let (dir, output_dir) = (dir.as_ref(), output_dir.as_ref());
+    fs::create_dir_all(output_dir).context(IoSnafu {
+        op: "create output dir",
+    })?;
+    let mut files = fs::read_dir(dir)
+        .context(IoSnafu { op: "read db dir" })?
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.extension().is_some_and(|ext| ext == "db") && is_ntqq_db(x))
+        .collect::<Vec<_>>();
+    snafu::ensure!(!files.is_empty(), NoDatabaseSnafu { dir });
+    files.sort();
+
+    let conn = open_memory_db()?;
+    let mut manifest = ExportManifest {
+        source_dir: dir.to_path_buf(),
+        output_dir: output_dir.to_path_buf(),
+        cipher: ntqq::DBDecryptInfo::CIPHER,
+        cipher_page_size: ntqq::DBDecryptInfo::CIPHER_PAGE_SIZE,
+        kdf_iter: d.kdf_iter(),
+        kdf_algorithm: ntqq::DBDecryptInfo::CIPHER_DEFAULT_KDF_ALGORITHM,
+        databases: Vec::with_capacity(files.len()),
+        failed: Vec::new(),
+    };
+    for file in files {
+        let file_name = file.file_name().unwrap().to_string_lossy().into_owned();
+        let output = output_dir.join(&file_name);
+        match export_one(&conn, &file, &output, d.clone()) {
+            Ok(exported) => {
+                log::info!("exported {} to {}", file.display(), output.display());
+                manifest.databases.push(exported);
+            }
+            Err(e) => {
+                log::warn!("failed to export {}: {}", file.display(), e);
+                manifest.failed.push(FailedDb {
+                    file_name,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    let manifest_file = fs::File::create(output_dir.join(MANIFEST_FILE_NAME)).context(IoSnafu {
+        op: "create manifest file",
+    })?;
+    serde_json::to_writer_pretty(manifest_file, &manifest).context(WriteManifestSnafu)?;
+    Ok(manifest)
+}
+
+fn export_one(
+    conn: &Connection,
+    file: &Path,
+    output: &Path,
+    d: ntqq::DBDecryptInfo,
+) -> crate::Result<ExportedDb> {
+    if output.exists() {
+        fs::remove_file(output).context(IoSnafu {
+            op: "remove existing output file",
+        })?;
+    }
+    let d = attach_encrypted_db(conn, file, "src", d)?;
+    let exported = export_attached(conn, "src", output);
+    detach_db(conn, "src")?;
+    exported?;
+
+    let plain = Connection::open(output).context(SqliteSnafu {
+        op: "open exported db",
+    })?;
+    let table_names = plain
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table';")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .context(SqliteSnafu {
+            op: "list exported tables",
+        })?;
+    let mut tables = BTreeMap::new();
+    for name in table_names {
+        let stmt = format!("SELECT count(*) FROM \"{}\";", name);
+        let rows: i64 = plain
+            .query_row(&stmt, [], |row| row.get(0))
+            .context(SqliteSnafu { op: stmt })?;
+        tables.insert(name, rows);
+    }
+    drop(plain);
+
+    let size_of = |path: &Path| {
+        fs::metadata(path).map(|x| x.len()).context(IoSnafu {
+            op: "read file size",
+        })
+    };
+    Ok(ExportedDb {
+        file_name: file.file_name().unwrap().to_string_lossy().into_owned(),
+        source_size: size_of(file)?,
+        plain_size: size_of(output)?,
+        cipher_hmac_algorithm: d.cipher_hmac_algorithm.unwrap_or_default(),
+        table_count: tables.len(),
+        row_count: tables.values().sum(),
+        tables,
+    })
+}
+
+/// Export the attached database `schema` of `conn` into the new plain database `output`.
+fn export_attached(conn: &Connection, schema: &str, output: &Path) -> crate::Result<()> {
+    conn.execute(
+        "ATTACH DATABASE ?1 AS plain KEY ''",
+        [output.to_string_lossy()],
+    )
+    .context(SqliteSnafu {
+        op: format!("attach {}", output.display()),
+    })?;
+    let stmt = format!("SELECT sqlcipher_export('plain', '{}');", schema);
+    let exported = conn
+        .query_row(&stmt, [], |_| Ok(()))
+        .context(SqliteSnafu { op: stmt });
+    detach_db(conn, "plain")?;
+    Ok(exported?)
+}
+
+/// Check whether the file starts with the NTQQ database header.
+fn is_ntqq_db(path: &Path) -> bool {
+    let mut buf = [0u8; NtDbHeader::TAG_RANGE.end];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .is_ok_and(|_| NtDbHeader::parse(&buf).is_ok())
+}
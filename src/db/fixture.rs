@@ -0,0 +1,413 @@
+//! Synthetic NTQQ databases for tests, as real ones are private.
+//!
+//! [NtDbFixture] writes a database the way Android NTQQ does: the 1024-byte header with the `QQ_NT DB` tag and a `rand`,
+//! followed by the SQLCipher data encrypted with the parameters of [DBDecryptInfo] and the key derived from the uid and the `rand`.
+//! It holds a `group_msg_table` and a `c2c_msg_table`, with the columns [GroupMsgTable](crate::db::model::GroupMsgTable)
+//! reads and [Message] protobuf blobs. Columns whose meaning is unknown are filled with zeros or `NULL`.
+//!
+//! This module is behind the `fixture` feature, so downstream tests can use it as well.
+//!
+//! ```
+//! use ntdb_unwrap::db::{self, model::{GroupMsgTable, Model}};
+//! use ntdb_unwrap::db::fixture::{FixtureMessage, NtDbFixture};
+//! use rusqlite::{Connection, OpenFlags};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let fixture = NtDbFixture::new()
+//!     .group_message(FixtureMessage::text(1, 10001, "hello"))
+//!     .c2c_message(FixtureMessage::image(1, 10002, "https://example.com/1.jpg"));
+//! let path = fixture.write_account_dir(dir.path())?;
+//!
+//! let conn = Connection::open_with_flags_and_vfs(
+//!     &path,
+//!     OpenFlags::SQLITE_OPEN_READ_WRITE,
+//!     db::OFFSET_VFS_NAME,
+//! ).unwrap();
+//! db::try_decrypt_db(&conn, fixture.decrypt_info())?;
+//! let mut stmt = conn.prepare("SELECT * FROM group_msg_table").unwrap();
+//! let rows = GroupMsgTable::parse_rows(&mut stmt.query([]).unwrap())?;
+//! assert_eq!(rows[0].message.as_ref().unwrap().messages[0].messageText, "hello");
+//! # Ok::<_, ntdb_unwrap::Error>(())
+//! ```
+
+use super::model::*;
+use super::*;
+use crate::ntqq::DBDecryptInfo;
+use protobuf::Message as _;
+use rusqlite::OpenFlags;
+use rusqlite::types::Value;
+use std::fs;
+
+/// `send_time` of the message with `seq_id` 0, messages are one minute apart by `seq_id`.
+const BASE_TIME: i64 = 1_700_000_000;
+
+/// Columns of both tables, in the order of [FixtureMessage::row].
+const COLUMNS: [(&str, &str); 36] = [
+    ("40001", "INTEGER PRIMARY KEY"),
+    ("40002", "INTEGER"),
+    ("40003", "INTEGER"),
+    ("40010", "INTEGER"),
+    ("40011", "INTEGER"),
+    ("40012", "INTEGER"),
+    ("40013", "INTEGER"),
+    ("40020", "TEXT"),
+    ("40026", "INTEGER"),
+    ("40021", "TEXT"),
+    ("40027", "INTEGER"),
+    ("40040", "INTEGER"),
+    ("40041", "INTEGER"),
+    ("40050", "INTEGER"),
+    ("40052", "INTEGER"),
+    ("40090", "TEXT"),
+    ("40093", "TEXT"),
+    ("40800", "BLOB"),
+    ("40900", "BLOB"),
+    ("40105", "INTEGER"),
+    ("40005", "INTEGER"),
+    ("40058", "INTEGER"),
+    ("40006", "INTEGER"),
+    ("40100", "INTEGER"),
+    ("40600", "BLOB"),
+    ("40060", "INTEGER"),
+    ("40850", "INTEGER"),
+    ("40851", "INTEGER"),
+    ("40601", "BLOB"),
+    ("40801", "BLOB"),
+    ("40605", "BLOB"),
+    ("40030", "INTEGER"),
+    ("40033", "INTEGER"),
+    ("40062", "BLOB"),
+    ("40083", "INTEGER"),
+    ("40084", "INTEGER"),
+];
+
+/// A fake NTQQ database, see the module doc.
+#[derive(Debug, Clone)]
+pub struct NtDbFixture {
+    uid: String,
+    rand: String,
+    cipher_hmac_algorithm: String,
+    group_messages: Vec<FixtureMessage>,
+    c2c_messages: Vec<FixtureMessage>,
+}
+
+impl Default for NtDbFixture {
+    fn default() -> Self {
+        Self {
+            uid: "u_fixturefixturefixture0".to_string(),
+            rand: "fIxTuRe0".to_string(),
+            cipher_hmac_algorithm: "HMAC_SHA1".to_string(),
+            group_messages: Vec::new(),
+            c2c_messages: Vec::new(),
+        }
+    }
+}
+
+impl NtDbFixture {
+    /// A database with empty tables.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A database with a short conversation of every kind of [FixtureMessage] in both tables.
+    pub fn sample() -> Self {
+        let hello = FixtureMessage::text(1, 10001, "大家好");
+        let reply = FixtureMessage::reply(2, 10002, &hello, "你好");
+        Self::new()
+            .group_message(hello.clone())
+            .group_message(reply)
+            .group_message(FixtureMessage::image(3, 10001, "https://example.com/1.jpg"))
+            .group_message(FixtureMessage::file(4, 10003, "notes.txt", 1024))
+            .c2c_message(FixtureMessage::text(1, 10002, "在吗"))
+            .c2c_message(FixtureMessage::text(2, 10001, "在"))
+    }
+
+    /// The account the database belongs to, which the key is derived from.
+    pub fn uid(mut self, uid: impl Into<String>) -> Self {
+        self.uid = uid.into();
+        self
+    }
+
+    /// The `rand` in the header, which the key is derived from.
+    ///
+    /// # Panics
+    ///
+    /// If `rand` is not [NtDbHeader::RAND_MIN_LEN] or more printable ASCII chars, as it couldn't be found in the header then.
+    pub fn rand(mut self, rand: impl Into<String>) -> Self {
+        let rand = rand.into();
+        assert!(
+            rand.len() >= NtDbHeader::RAND_MIN_LEN && rand.bytes().all(|x| x.is_ascii_graphic()),
+            "rand must be {} or more printable ASCII chars",
+            NtDbHeader::RAND_MIN_LEN
+        );
+        self.rand = rand;
+        self
+    }
+
+    /// `HMAC_SHA1` or `HMAC_SHA256`, both of which NTQQ uses.
+    pub fn cipher_hmac_algorithm(mut self, algorithm: impl Into<String>) -> Self {
+        self.cipher_hmac_algorithm = algorithm.into();
+        self
+    }
+
+    /// Add a row to `group_msg_table`. Its chat type is set to group chat.
+    pub fn group_message(mut self, mut message: FixtureMessage) -> Self {
+        message.chat_type = 2;
+        if message.group_number == 0 {
+            message.group_number = 100_000;
+        }
+        self.group_messages.push(message);
+        self
+    }
+
+    /// Add a row to `c2c_msg_table`. Its chat type is set to private chat.
+    pub fn c2c_message(mut self, mut message: FixtureMessage) -> Self {
+        message.chat_type = 1;
+        self.c2c_messages.push(message);
+        self
+    }
+
+    /// The key of the database, the same as Android NTQQ derives.
+    pub fn decrypt_info(&self) -> DBDecryptInfo {
+        DBDecryptInfo {
+            key: ntqq::android::derive_key(&self.uid, &self.rand),
+            cipher_hmac_algorithm: Some(self.cipher_hmac_algorithm.clone()),
+            kdf_iter: None,
+        }
+    }
+
+    pub fn header(&self) -> [u8; NtDbHeader::LEN] {
+        let mut header = [0u8; NtDbHeader::LEN];
+        header[NtDbHeader::MAGIC_RANGE].copy_from_slice(b"SQLite header 3\0");
+        header[NtDbHeader::TAG_RANGE].copy_from_slice(NtDbHeader::TAG);
+        // the rand follows the tag after a nul byte
+        let rand = NtDbHeader::TAG_RANGE.end + 1;
+        header[rand..rand + self.rand.len()].copy_from_slice(self.rand.as_bytes());
+        header
+    }
+
+    /// Write the database to `path`, replacing the file if any.
+    pub fn write(&self, path: impl AsRef<Path>) -> crate::Result<()> {
+        let path = path.as_ref();
+        for suffix in SIDECAR_SUFFIXES {
+            let _ = fs::remove_file(sidecar_path(path, suffix));
+        }
+        fs::write(path, self.header()).context(IoSnafu {
+            op: format!("write header to {}", path.display()),
+        })?;
+        register_offset_vfs().map_err(|code| RegisterVfsSnafu { code }.build())?;
+        let mut conn = Connection::open_with_flags_and_vfs(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            OFFSET_VFS_NAME,
+        )
+        .context(SqliteSnafu {
+            op: format!("open {}", path.display()),
+        })?;
+        let stmt = self.decrypt_info().display_pragma_stmts().to_string();
+        conn.execute_batch(&stmt)
+            .context(SqliteSnafu { op: stmt })?;
+
+        let tx = conn.transaction().context(SqliteSnafu {
+            op: "begin transaction",
+        })?;
+        for (table, messages) in [
+            ("group_msg_table", &self.group_messages),
+            ("c2c_msg_table", &self.c2c_messages),
+        ] {
+            let columns = COLUMNS.map(|(name, ty)| format!("\"{}\" {}", name, ty));
+            let stmt = format!("CREATE TABLE {} ({});", table, columns.join(", "));
+            tx.execute_batch(&stmt).context(SqliteSnafu { op: stmt })?;
+            let stmt = format!(
+                "INSERT INTO {} VALUES ({});",
+                table,
+                ["?"; COLUMNS.len()].join(", ")
+            );
+            for message in messages {
+                tx.execute(&stmt, rusqlite::params_from_iter(message.row()))
+                    .context(SqliteSnafu { op: stmt.clone() })?;
+            }
+        }
+        tx.commit().context(SqliteSnafu { op: "commit" })?;
+        Ok(())
+    }
+
+    /// Write the database as `nt_msg.db` into the account directory in `dir`, i.e. `dir/nt_qq_<hash>` as on Android.
+    ///
+    /// Returns the path of the database.
+    pub fn write_account_dir(&self, dir: impl AsRef<Path>) -> crate::Result<PathBuf> {
+        let account_dir = dir
+            .as_ref()
+            .join(format!("nt_qq_{}", ntqq::account_dir_hash(&self.uid)));
+        fs::create_dir_all(&account_dir).context(IoSnafu {
+            op: format!("create {}", account_dir.display()),
+        })?;
+        let path = account_dir.join("nt_msg.db");
+        self.write(&path)?;
+        Ok(path)
+    }
+}
+
+/// A row of `group_msg_table` or `c2c_msg_table`.
+///
+/// The constructors fill in plausible values derived from `seq_id` and the sender,
+/// change the fields afterwards as needed.
+#[derive(Debug, Clone, Default)]
+pub struct FixtureMessage {
+    pub id: i64,
+    pub msg_random: i64,
+    pub seq_id: i64,
+    /// Set by [NtDbFixture::group_message] and [NtDbFixture::c2c_message].
+    pub chat_type: i64,
+    pub msg_type: i64,
+    pub sub_msg_type: i64,
+    pub send_type: i64,
+    pub sender_uid: String,
+    pub sender_uin: i64,
+    pub peer_uid: String,
+    pub peer_uin: i64,
+    /// Defaults to `100000` in `group_msg_table`.
+    pub group_number: i64,
+    pub send_status: i64,
+    pub send_time: i64,
+    pub sender_group_name: String,
+    pub sender_nickname: String,
+    pub at_flag: i64,
+    pub reply_msg_seq: i64,
+    /// Elements of the [Message] blob.
+    pub elements: Vec<SingleMessage>,
+}
+
+impl FixtureMessage {
+    fn new(seq_id: i64, sender_uin: i64, msg_type: i64, sub_msg_type: i64) -> Self {
+        let send_time = BASE_TIME + seq_id * 60;
+        Self {
+            id: 7_000_000_000_000_000_000 + seq_id,
+            msg_random: 0x5eed_0000 + seq_id,
+            seq_id,
+            msg_type,
+            sub_msg_type,
+            sender_uid: fake_uid(sender_uin),
+            sender_uin,
+            send_status: 2,
+            send_time,
+            sender_nickname: format!("用户{}", sender_uin),
+            ..Default::default()
+        }
+    }
+
+    /// A plain text message.
+    pub fn text(seq_id: i64, sender_uin: i64, text: &str) -> Self {
+        let mut message = Self::new(seq_id, sender_uin, 2, 1);
+        message.elements.push(text_element(message.id, text));
+        message
+    }
+
+    /// An image message.
+    pub fn image(seq_id: i64, sender_uin: i64, url: &str) -> Self {
+        let mut message = Self::new(seq_id, sender_uin, 2, 2);
+        let mut element = element(message.id, 2);
+        element.imageUrlOrigin = url.to_string();
+        element.imageText = "[图片]".to_string();
+        message.elements.push(element);
+        message
+    }
+
+    /// A file message.
+    pub fn file(seq_id: i64, sender_uin: i64, name: &str, size: u64) -> Self {
+        let mut message = Self::new(seq_id, sender_uin, 3, 0);
+        let mut element = element(message.id, 3);
+        element.fileName = name.to_string();
+        element.fileSize = size;
+        message.elements.push(element);
+        message
+    }
+
+    /// A text message replying to `to`.
+    pub fn reply(seq_id: i64, sender_uin: i64, to: &FixtureMessage, text: &str) -> Self {
+        let mut message = Self::new(seq_id, sender_uin, 9, 33);
+        message.reply_msg_seq = to.seq_id;
+        let mut reply = element(message.id, 7);
+        reply.senderUid = to.sender_uin as u32;
+        reply.senderId = to.sender_uid.clone();
+        reply.sendTimestamp = to.send_time as u32;
+        reply.replyMessage = to.elements.first().cloned().into();
+        message.elements.push(reply);
+        message.elements.push(text_element(message.id, text));
+        message
+    }
+
+    /// The [Message] blob.
+    pub fn message(&self) -> Message {
+        Message {
+            messages: self.elements.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Values of [COLUMNS].
+    fn row(&self) -> [Value; COLUMNS.len()] {
+        let blob = self
+            .message()
+            .write_to_bytes()
+            .expect("fixture message is serializable");
+        let day = self.send_time - self.send_time.rem_euclid(86400);
+        [
+            self.id.into(),
+            self.msg_random.into(),
+            self.seq_id.into(),
+            self.chat_type.into(),
+            self.msg_type.into(),
+            self.sub_msg_type.into(),
+            self.send_type.into(),
+            self.sender_uid.clone().into(),
+            0.into(),
+            self.peer_uid.clone().into(),
+            self.peer_uin.into(),
+            0.into(),
+            self.send_status.into(),
+            self.send_time.into(),
+            0.into(),
+            self.sender_group_name.clone().into(),
+            self.sender_nickname.clone().into(),
+            blob.into(),
+            Value::Null,
+            0.into(),
+            0.into(),
+            day.into(),
+            0.into(),
+            self.at_flag.into(),
+            Value::Null,
+            0.into(),
+            self.reply_msg_seq.into(),
+            0.into(),
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            self.group_number.into(),
+            self.sender_uin.into(),
+            Value::Null,
+            0.into(),
+            0.into(),
+        ]
+    }
+}
+
+/// A uid of the real length for `uin`.
+fn fake_uid(uin: i64) -> String {
+    format!("u_fixture{:015}", uin)
+}
+
+fn element(message_id: i64, message_type: u32) -> SingleMessage {
+    SingleMessage {
+        messageId: message_id as u64,
+        messageType: message_type,
+        ..Default::default()
+    }
+}
+
+fn text_element(message_id: i64, text: &str) -> SingleMessage {
+    let mut element = element(message_id, 1);
+    element.messageText = text.to_string();
+    element
+}
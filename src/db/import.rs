@@ -0,0 +1,120 @@
+use super::*;
+use crate::sqlcipher;
+use snafu::ensure;
+use std::fs;
+use std::io::Read;
+
+/// Re-wrap the plain SQLite database `plain` into an NTQQ database at `output`, the reverse of [export_to_plain].
+///
+/// `original` is an NTQQ database of the same account, normally the one `plain` is exported from.
+/// Its header is copied as is, as what the unknown parts of it mean is unclear.
+/// `d` must open it, with the hmac algorithm in `d` if any, and the one that does is used.
+///
+/// The result is written to a temporary file next to `output` and checked to open through the offset vfs with `d`
+/// before it replaces `output`, which can be `original` itself.
+/// Fails if `output` has a WAL, i.e. it's in use or not closed cleanly, as the WAL would then be applied to the new database.
+///
+/// Returns `d` with the hmac algorithm used filled in.
+pub fn import_from_plain(
+    plain: impl AsRef<Path>,
+    original: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    mut d: ntqq::DBDecryptInfo,
+) -> crate::Result<ntqq::DBDecryptInfo> {
+    let (plain, original, output) = (plain.as_ref(), original.as_ref(), output.as_ref());
+    let mut header = [0u8; NtDbHeader::LEN];
+    fs::File::open(original)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .context(IoSnafu {
+            op: format!("read header of {}", original.display()),
+        })?;
+    NtDbHeader::parse(&header).map_err(|reason| {
+        NotNtqqDbSnafu {
+            path: original.to_owned(),
+            reason,
+        }
+        .build()
+    })?;
+    // the key must open the original, or QQ wouldn't open the result either
+    match sqlcipher::verify_key_file(original, &d)? {
+        sqlcipher::KeyCheck::Match(algo) => d.cipher_hmac_algorithm = Some(algo.to_string()),
+        _ => return WrongDecryptInfoSnafu.fail().map_err(crate::Error::from),
+    }
+    ensure!(
+        !sidecar_path(output, "-wal").exists(),
+        DatabaseInUseSnafu { path: output }
+    );
+
+    let tmp = sidecar_path(output, ".import.tmp");
+    fs::write(&tmp, header).context(IoSnafu {
+        op: format!("write header to {}", tmp.display()),
+    })?;
+    if let Err(e) = write_encrypted(plain, &tmp, &d).and_then(|_| verify_import(plain, &tmp, &d)) {
+        let _ = remove_db(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, output).context(IoSnafu {
+        op: format!("replace {}", output.display()),
+    })?;
+    Ok(d)
+}
+
+/// Encrypt `plain` with `d` into `file`, which holds only the NTQQ header so far.
+fn write_encrypted(plain: &Path, file: &Path, d: &ntqq::DBDecryptInfo) -> crate::Result<()> {
+    let conn = open_memory_db()?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS plain KEY ''",
+        [plain.to_string_lossy()],
+    )
+    .context(SqliteSnafu {
+        op: format!("attach {}", plain.display()),
+    })?;
+    attach_new_encrypted_db(&conn, file, "ntqq", d)?;
+    let stmt = "SELECT sqlcipher_export('ntqq', 'plain');";
+    conn.query_row(stmt, [], |_| Ok(()))
+        .context(SqliteSnafu { op: stmt })?;
+    detach_db(&conn, "ntqq")?;
+    detach_db(&conn, "plain")
+}
+
+/// Check `file` opens through the offset vfs with `d` and has the same schema as `plain`.
+fn verify_import(plain: &Path, file: &Path, d: &ntqq::DBDecryptInfo) -> crate::Result<()> {
+    let fail = |reason: String| {
+        ImportVerificationSnafu {
+            path: file.to_owned(),
+            reason,
+        }
+        .fail()
+        .map_err(crate::Error::from)
+    };
+    let conn = open_memory_db()?;
+    attach_encrypted_db(&conn, file, "ntqq", d.clone())?;
+    if offset_info(&conn, "ntqq")?.header_detected == 0 {
+        return fail("NTQQ header not detected".to_string());
+    }
+    conn.execute(
+        "ATTACH DATABASE ?1 AS plain KEY ''",
+        [plain.to_string_lossy()],
+    )
+    .context(SqliteSnafu {
+        op: format!("attach {}", plain.display()),
+    })?;
+    let stmt = "SELECT (SELECT count(*) FROM ntqq.sqlite_master), (SELECT count(*) FROM plain.sqlite_master);";
+    let (imported, expected): (i64, i64) = conn
+        .query_row(stmt, [], |row| Ok((row.get(0)?, row.get(1)?)))
+        .context(SqliteSnafu { op: stmt })?;
+    if imported != expected {
+        return fail(format!(
+            "{} schema objects, expected {}",
+            imported, expected
+        ));
+    }
+    let stmt = "PRAGMA ntqq.quick_check;";
+    let check: String = conn
+        .query_row(stmt, [], |row| row.get(0))
+        .context(SqliteSnafu { op: stmt })?;
+    if check != "ok" {
+        return fail(format!("quick_check: {}", check));
+    }
+    Ok(())
+}
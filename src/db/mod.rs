@@ -1,4 +1,28 @@
+mod copy;
+pub use copy::*;
+#[cfg(feature = "sqlcipher")]
+mod decrypt;
+#[cfg(feature = "sqlcipher")]
+pub use decrypt::*;
+#[cfg(feature = "sqlcipher")]
+mod export;
+#[cfg(feature = "sqlcipher")]
+pub use export::*;
+#[cfg(feature = "fixture")]
+pub mod fixture;
+#[cfg(feature = "sqlcipher")]
+mod import;
+#[cfg(feature = "sqlcipher")]
+pub use import::*;
+#[cfg(feature = "sqlcipher")]
+mod rekey;
+#[cfg(feature = "sqlcipher")]
+pub use rekey::*;
 pub mod model;
+#[cfg(feature = "sqlcipher")]
+mod set;
+#[cfg(feature = "sqlcipher")]
+pub use set::*;
 
 use std::path::Path;
 
@@ -7,49 +31,93 @@ pub use sqlite_ext_ntqq_db::*;
 use rusqlite::Connection;
 use snafu::{ResultExt, Snafu};
 
+#[cfg(feature = "sqlcipher")]
 use crate::ntqq;
+#[cfg(feature = "sqlcipher")]
+use std::path::PathBuf;
 
-pub fn try_decrypt_db(conn: &Connection, mut d: ntqq::DBDecryptInfo) -> crate::Result<()> {
-    let try_alg: [Option<String>; 2] = match d.cipher_hmac_algorithm.take() {
-        Some(algo) => [Some(algo), None],
-        None => ["HMAC_SHA256", "HMAC_SHA1"].map(|x| Some(x.to_string())),
-    };
-    for algo in try_alg.into_iter().flatten() {
-        log::info!("trying hmac algorithm: {}", algo);
-        d.cipher_hmac_algorithm = Some(algo);
-        let stmt = d.display_pragma_stmts().to_string();
-        conn.execute_batch(&stmt)
-            .context(SqliteSnafu { op: stmt })?;
-        let stmt = conn.prepare("SELECT count(*) FROM sqlite_master;");
-        match stmt {
-            Ok(mut stmt) => {
-                if stmt.exists([]).context(SqliteSnafu { op: "stmt.exists" })? {
-                    log::info!("decryped successfully");
-                    return Ok(());
-                }
-            }
-            Err(e) => {
-                log::info!("attempt failed: {}", e);
-            }
-        }
-    }
-    WrongDecryptInfoSnafu.fail().map_err(crate::Error::from)
+pub fn detach_db(conn: &Connection, schema: &str) -> crate::Result<()> {
+    conn.execute("DETACH DATABASE ?1", [schema])
+        .context(SqliteSnafu {
+            op: format!("detach {}", schema),
+        })?;
+    Ok(())
 }
 
-pub fn export_to_plain(conn: &Connection, file: impl AsRef<Path>) -> crate::Result<()> {
-    let stmt = format!(
-        r#"ATTACH DATABASE '{}' AS plain KEY ''; 
-        SELECT sqlcipher_export('plain');
-        DETACH DATABASE plain;"#,
-        file.as_ref().display()
-    );
-    conn.execute_batch(&stmt)
-        .context(SqliteSnafu { op: stmt })?;
-    Ok(())
+/// How the offset vfs opened the database `schema` of `conn`, see [FCNTL_OFFSET_INFO].
+///
+/// Fails with `SQLITE_NOTFOUND` if the database is not opened by the offset vfs.
+pub fn offset_info(conn: &Connection, schema: &str) -> crate::Result<OffsetInfo> {
+    let schema_c = std::ffi::CString::new(schema).expect("schema name has no nul byte");
+    let mut info = OffsetInfo::default();
+    let rc = unsafe {
+        rusqlite::ffi::sqlite3_file_control(
+            conn.handle(),
+            schema_c.as_ptr(),
+            FCNTL_OFFSET_INFO,
+            &mut info as *mut OffsetInfo as *mut std::ffi::c_void,
+        )
+    };
+    if rc != rusqlite::ffi::SQLITE_OK {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rc),
+            None,
+        ))
+        .context(SqliteSnafu {
+            op: format!("query offset info of {}", schema),
+        })?;
+    }
+    Ok(info)
 }
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    Sqlite { source: rusqlite::Error, op: String },
+    Sqlite {
+        source: rusqlite::Error,
+        op: String,
+    },
     WrongDecryptInfo {},
+    #[snafu(display("failed to register offset vfs: sqlite code {}", code))]
+    RegisterVfs {
+        code: i32,
+    },
+    #[snafu(display("IO operation to {}: {}", op, source))]
+    Io {
+        source: std::io::Error,
+        op: String,
+    },
+    #[snafu(display("write export manifest: {}", source))]
+    WriteManifest {
+        source: serde_json::Error,
+    },
+    #[snafu(display("{} is not an NTQQ database: {}", path.display(), reason))]
+    NotNtqqDb {
+        path: std::path::PathBuf,
+        reason: HeaderError,
+    },
+    #[snafu(display("{} is in use, it has a WAL", path.display()))]
+    DatabaseInUse {
+        path: std::path::PathBuf,
+    },
+    #[snafu(display("imported database {} failed verification: {}", path.display(), reason))]
+    ImportVerification {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[snafu(display("rekey needs a database file, not an in-memory database"))]
+    RekeyInMemory {},
+    #[snafu(display("{} kept changing during {} attempts to copy it", path.display(), attempts))]
+    ChangedWhileCopying {
+        path: std::path::PathBuf,
+        attempts: u32,
+    },
+    #[snafu(display("no NTQQ database found in {}", dir.display()))]
+    NoDatabase {
+        dir: std::path::PathBuf,
+    },
+    #[snafu(display("cannot attach {}, SQLite attaches at most {} databases", path.display(), limit))]
+    TooManyAttached {
+        path: std::path::PathBuf,
+        limit: usize,
+    },
 }
@@ -0,0 +1,106 @@
+use super::*;
+use rusqlite::OpenFlags;
+use snafu::ensure;
+use std::fs;
+use std::io::Read;
+
+/// Change the key of the main database of `conn` to `new`, keeping the NTQQ header in front of it intact.
+///
+/// `conn` must be opened through the offset vfs and decrypted already, e.g. by [try_decrypt_db].
+/// `new.cipher_hmac_algorithm` defaults to the current algorithm if `None`.
+///
+/// If only the key changes, the pages are re-encrypted in place by `PRAGMA rekey` and `conn` is returned as is.
+/// SQLCipher can't change the hmac algorithm or the KDF iterations in place though,
+/// so for those the database is rewritten by `sqlcipher_export` into a new file with the header copied,
+/// which then replaces the original. `conn` is closed for that, and a new connection to the result is returned.
+/// Like [import_from_plain], this fails if the database still has a WAL after `conn` is closed.
+pub fn rekey(conn: Connection, mut new: ntqq::DBDecryptInfo) -> crate::Result<Connection> {
+    let path = match conn.path() {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => return RekeyInMemorySnafu.fail().map_err(crate::Error::from),
+    };
+    let pragma = |name: &str| -> crate::Result<String> {
+        let stmt = format!("PRAGMA {};", name);
+        Ok(conn
+            .query_row(&stmt, [], |row| row.get(0))
+            .context(SqliteSnafu { op: stmt })?)
+    };
+    let hmac = pragma("cipher_hmac_algorithm")?;
+    let kdf_iter = pragma("kdf_iter")?;
+    let new_hmac = new.cipher_hmac_algorithm.get_or_insert(hmac.clone());
+    if new_hmac.eq_ignore_ascii_case(&hmac) && new.kdf_iter().to_string() == kdf_iter {
+        conn.pragma_update(None, "rekey", &new.key)
+            .context(SqliteSnafu { op: "PRAGMA rekey" })?;
+        log::info!("rekeyed {} in place", path.display());
+        return Ok(conn);
+    }
+
+    let tmp = sidecar_path(&path, ".rekey.tmp");
+    let result = export_rekeyed(conn, &path, &tmp, &new);
+    if result.is_err() {
+        let _ = remove_db(&tmp);
+    }
+    result?;
+    fs::rename(&tmp, &path).context(IoSnafu {
+        op: format!("replace {}", path.display()),
+    })?;
+    log::info!("rekeyed {} with new cipher parameters", path.display());
+
+    let conn = Connection::open_with_flags_and_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        OFFSET_VFS_NAME,
+    )
+    .context(SqliteSnafu {
+        op: format!("open {}", path.display()),
+    })?;
+    try_decrypt_db(&conn, new)?;
+    Ok(conn)
+}
+
+/// Export the main database of `conn`, at `path`, into `tmp` encrypted with `new`, then close `conn`.
+fn export_rekeyed(
+    conn: Connection,
+    path: &Path,
+    tmp: &Path,
+    new: &ntqq::DBDecryptInfo,
+) -> crate::Result<()> {
+    let mut header = vec![0u8; offset_info(&conn, "main")?.offset as usize];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .context(IoSnafu {
+            op: format!("read header of {}", path.display()),
+        })?;
+    fs::write(tmp, &header).context(IoSnafu {
+        op: format!("write header to {}", tmp.display()),
+    })?;
+
+    attach_new_encrypted_db(&conn, tmp, "rekeyed", new)?;
+    let stmt = "SELECT sqlcipher_export('rekeyed');";
+    conn.query_row(stmt, [], |_| Ok(()))
+        .context(SqliteSnafu { op: stmt })?;
+    // sqlcipher_export doesn't carry the journal mode over, and NTQQ databases are in WAL mode
+    let journal_mode: String = conn
+        .query_row("PRAGMA main.journal_mode;", [], |row| row.get(0))
+        .context(SqliteSnafu {
+            op: "PRAGMA main.journal_mode",
+        })?;
+    if journal_mode.eq_ignore_ascii_case("wal") {
+        conn.query_row("PRAGMA rekeyed.journal_mode = WAL;", [], |_| Ok(()))
+            .context(SqliteSnafu {
+                op: "PRAGMA rekeyed.journal_mode = WAL",
+            })?;
+    }
+    detach_db(&conn, "rekeyed")?;
+    conn.close().map_err(|(_, e)| e).context(SqliteSnafu {
+        op: format!("close {}", path.display()),
+    })?;
+    ensure!(
+        !sidecar_path(path, "-wal").exists(),
+        DatabaseInUseSnafu { path }
+    );
+
+    let check = open_memory_db()?;
+    attach_encrypted_db(&check, tmp, "rekeyed", new.clone())?;
+    detach_db(&check, "rekeyed")
+}
@@ -0,0 +1,109 @@
+use super::*;
+use std::path::PathBuf;
+
+/// Databases in an account's `nt_db` directory that [NtDbSet::open] attaches, by file stem.
+///
+/// Each is attached under its file stem as schema name, e.g. `nt_msg.group_msg_table`.
+/// SQLite allows at most 10 attached databases, so the full-text-search indexes (`*_fts.db`)
+/// and `guild_msg` are left out, which keeps a slot free for e.g. the target of [export_to_plain].
+/// Use [NtDbSet::open_with] to pick others.
+pub const KNOWN_DATABASES: [&str; 9] = [
+    "nt_msg",
+    "profile_info",
+    "group_info",
+    "recent_contact",
+    "files_in_chat",
+    "rich_media",
+    "emoji",
+    "collection",
+    "misc",
+];
+
+/// All databases of one NTQQ account, attached to a single connection.
+///
+/// Every database is encrypted with the same key, so one [ntqq::DBDecryptInfo] is enough
+/// to open the whole `nt_db` directory. Cross-database queries then just work,
+/// e.g. joining `nt_msg.group_msg_table` with tables in `profile_info` or `group_info`.
+#[derive(Debug)]
+pub struct NtDbSet {
+    conn: Connection,
+    dir: PathBuf,
+    databases: Vec<AttachedDb>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AttachedDb {
+    /// The schema name the database is attached as.
+    pub schema: String,
+    pub path: PathBuf,
+    /// Decrypt info with the working hmac algorithm filled in.
+    pub decrypt_info: ntqq::DBDecryptInfo,
+}
+
+impl NtDbSet {
+    /// Open every database in [KNOWN_DATABASES] that exists in `dir`.
+    pub fn open(dir: impl AsRef<Path>, d: ntqq::DBDecryptInfo) -> crate::Result<Self> {
+        Self::open_with(dir, d, &KNOWN_DATABASES)
+    }
+
+    /// Open the databases named by `stems` (file names without `.db`) in `dir`.
+    ///
+    /// Missing files are skipped silently, and files that cannot be decrypted are skipped with a warning.
+    /// It's an error if nothing could be attached at all,
+    /// or if more files exist than SQLite can attach (10 by default).
+    pub fn open_with(
+        dir: impl AsRef<Path>,
+        d: ntqq::DBDecryptInfo,
+        stems: &[&str],
+    ) -> crate::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        // an in-memory main database, so that every real database gets a stable schema name
+        let conn = open_memory_db()?;
+        let limit = unsafe {
+            rusqlite::ffi::sqlite3_limit(conn.handle(), rusqlite::ffi::SQLITE_LIMIT_ATTACHED, -1)
+        } as usize;
+
+        let mut databases = Vec::with_capacity(stems.len());
+        for stem in stems {
+            let path = dir.join(format!("{}.db", stem));
+            if !path.is_file() {
+                continue;
+            }
+            // failed attempts are detached again, so only the attached ones take up the slots
+            snafu::ensure!(
+                databases.len() < limit,
+                TooManyAttachedSnafu { path, limit }
+            );
+            match attach_encrypted_db(&conn, &path, stem, d.clone()) {
+                Ok(decrypt_info) => databases.push(AttachedDb {
+                    schema: stem.to_string(),
+                    path,
+                    decrypt_info,
+                }),
+                Err(e) => log::warn!("skip {}: {}", path.display(), e),
+            }
+        }
+        snafu::ensure!(!databases.is_empty(), NoDatabaseSnafu { dir: dir.clone() });
+        Ok(Self {
+            conn,
+            dir,
+            databases,
+        })
+    }
+
+    pub fn conn(&self) -> &Connection {
+        &self.conn
+    }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    pub fn databases(&self) -> &[AttachedDb] {
+        &self.databases
+    }
+    pub fn get(&self, schema: &str) -> Option<&AttachedDb> {
+        self.databases.iter().find(|x| x.schema == schema)
+    }
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+}
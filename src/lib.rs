@@ -1,6 +1,7 @@
 pub mod db;
 pub mod ntqq;
 mod protos;
+pub mod sqlcipher;
 pub mod util;
 
 use snafu::prelude::*;
@@ -19,6 +20,10 @@ pub enum Error {
     NTQQ {
         source: ntqq::Error,
     },
+    #[snafu(transparent)]
+    SQLCipher {
+        source: sqlcipher::Error,
+    },
     UnsupportedPlatform {
         platform: ntqq::Platform,
     },
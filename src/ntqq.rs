@@ -1,9 +1,15 @@
+pub mod analyzer;
 pub mod android;
+pub mod keyscan;
+pub mod linux;
+pub mod macos;
 pub mod windows;
 
+use crate::util::md5_hex;
 use core::fmt;
 use snafu::Snafu;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Platform {
@@ -45,6 +51,11 @@ pub struct UserDBFile {
     pub path: PathBuf,
     pub uid: Option<String>,
     pub uin: Option<u64>,
+    /// Android user profile, e.g. 0 for the owner, 999 for the dual-app space of some ROMs,
+    /// or the id of a secondary user or work profile.
+    pub profile: Option<u32>,
+    /// Android package name, e.g. `com.tencent.mobileqq`.
+    pub package: Option<String>,
 }
 impl fmt::Display for UserDBFile {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -59,10 +70,81 @@ impl fmt::Display for UserDBFile {
         } else {
             write!(f, "(unknown_uid)")?;
         }
+        if let Some(package) = &self.package {
+            write!(f, " {}", package)?;
+        }
+        if let Some(profile) = self.profile {
+            write!(f, " user/{}", profile)?;
+        }
         Ok(())
     }
 }
 
+/// Length of an NTQQ uid, e.g. `u_` followed by 22 chars.
+const UID_LEN: usize = 24;
+
+/// The `<hash>` in `nt_qq_<hash>`, the name of the account directory of `uid`. It's the same on all platforms.
+pub fn account_dir_hash(uid: &str) -> String {
+    md5_hex(md5_hex(uid) + "nt_kernel")
+}
+
+/// The `<hash>` of the account directory `path`, or of the one containing it if `path` is a database in it.
+pub fn account_dir_hash_of(path: &Path) -> Option<String> {
+    let is_hash = |x: &str| x.len() == 32 && x.bytes().all(|c| c.is_ascii_hexdigit());
+    [Some(path), path.parent()]
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.file_name()?.to_str()?.strip_prefix("nt_qq_"))
+        .find(|x| is_hash(x))
+        .map(str::to_ascii_lowercase)
+}
+
+/// Collect uids found in file names under `dir`, along with the uin if the name has one before the uid,
+/// e.g. `<uin>###<uid>` like on Android.
+fn collect_uids(dir: &Path, depth: usize, uids: &mut BTreeMap<String, Option<u64>>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let name = entry.file_name();
+        add_uids(&name.to_string_lossy(), uids);
+        if depth > 1 && entry.file_type().is_ok_and(|x| x.is_dir()) {
+            collect_uids(&entry.path(), depth - 1, uids);
+        }
+    }
+}
+
+/// Add the uids found in `text` to `uids`, see [find_uids].
+fn add_uids(text: &str, uids: &mut BTreeMap<String, Option<u64>>) {
+    for (uid, uin) in find_uids(text) {
+        let known = uids.entry(uid.to_owned()).or_default();
+        *known = known.or(uin);
+    }
+}
+
+/// Find uid-like strings, i.e. `u_` followed by 22 url-safe chars, along with the uin right before it if any.
+fn find_uids(text: &str) -> impl Iterator<Item = (&str, Option<u64>)> {
+    let is_uid_char = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'-';
+    text.match_indices("u_").filter_map(move |(begin, _)| {
+        let uid = text.get(begin..begin + UID_LEN)?;
+        let boundary_ok = uid.bytes().all(is_uid_char)
+            && !text[..begin].bytes().next_back().is_some_and(is_uid_char)
+            && !text[begin + UID_LEN..]
+                .bytes()
+                .next()
+                .is_some_and(is_uid_char);
+        if !boundary_ok {
+            return None;
+        }
+        let uin = text[..begin]
+            .trim_end_matches(|c: char| !c.is_ascii_alphanumeric())
+            .rsplit(|c: char| !c.is_ascii_digit())
+            .next()
+            .and_then(|x| x.parse().ok());
+        Some((uid, uin))
+    })
+}
+
 pub fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
     {
         #[cfg(target_os = "windows")]
@@ -71,16 +153,17 @@ pub fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
         }
         #[cfg(target_os = "linux")]
         {
-            use crate::UnsupportedPlatformSnafu;
-
-            let platform = running_platform();
-            match platform {
-                Platform::Linux => UnsupportedPlatformSnafu { platform }.fail(),
+            match running_platform() {
+                Platform::Linux => linux::detect_db_file(),
                 Platform::Android => android::detect_db_file(),
                 _ => unreachable!(),
             }
         }
-        #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+        #[cfg(target_os = "macos")]
+        {
+            macos::detect_db_file()
+        }
+        #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
         {
             use crate::UnsupportedPlatformSnafu;
             UnsupportedPlatformSnafu {
@@ -90,7 +173,7 @@ pub fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
         }
     }
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct DBDecryptInfo {
     /// Acutually should be be represented as a Vec<u8>.
     /// However it seems it's always printable strings on most platform known.
@@ -101,6 +184,9 @@ pub struct DBDecryptInfo {
     /// And you must set this field to a `Some` value before displaying,
     /// or [DBDecryptInfo::display_pragma_stmts] will display an error.
     pub cipher_hmac_algorithm: Option<String>,
+    /// PBKDF2 iterations, `None` for [DBDecryptInfo::KDF_ITER], which NTQQ always uses.
+    /// Other values only come from databases rekeyed by [crate::db::rekey].
+    pub kdf_iter: Option<usize>,
 }
 
 pub struct DisplayPragmaStmts<'a>(&'a DBDecryptInfo);
@@ -113,7 +199,7 @@ impl fmt::Display for DisplayPragmaStmts<'_> {
             "PRAGMA cipher_page_size = {};",
             DBDecryptInfo::CIPHER_PAGE_SIZE
         )?;
-        writeln!(f, "PRAGMA kdf_iter = {};", DBDecryptInfo::KDF_ITER)?;
+        writeln!(f, "PRAGMA kdf_iter = {};", self.0.kdf_iter())?;
         writeln!(
             f,
             "PRAGMA cipher_hmac_algorithm = {};",
@@ -128,15 +214,46 @@ impl fmt::Display for DisplayPragmaStmts<'_> {
         Ok(())
     }
 }
+/// Same as [DisplayPragmaStmts], but sets SQLCipher's `cipher_default_*` settings instead,
+/// which is what an `ATTACH ... KEY` statement picks up. The key itself is not included.
+pub struct DisplayDefaultPragmaStmts<'a>(&'a DBDecryptInfo);
+
+impl fmt::Display for DisplayDefaultPragmaStmts<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "PRAGMA cipher_default_page_size = {};",
+            DBDecryptInfo::CIPHER_PAGE_SIZE
+        )?;
+        writeln!(f, "PRAGMA cipher_default_kdf_iter = {};", self.0.kdf_iter())?;
+        writeln!(
+            f,
+            "PRAGMA cipher_default_hmac_algorithm = {};",
+            self.0.cipher_hmac_algorithm.as_ref().ok_or(fmt::Error)?
+        )?;
+        writeln!(
+            f,
+            "PRAGMA cipher_default_kdf_algorithm = {};",
+            DBDecryptInfo::CIPHER_DEFAULT_KDF_ALGORITHM
+        )?;
+        Ok(())
+    }
+}
 impl DBDecryptInfo {
-    const CIPHER_PAGE_SIZE: usize = 4096;
-    const KDF_ITER: usize = 4000;
-    const CIPHER_DEFAULT_KDF_ALGORITHM: &str = "PBKDF2_HMAC_SHA512";
-    const CIPHER: &str = "aes-256-cbc";
+    pub const CIPHER_PAGE_SIZE: usize = 4096;
+    pub const KDF_ITER: usize = 4000;
+    pub const CIPHER_DEFAULT_KDF_ALGORITHM: &str = "PBKDF2_HMAC_SHA512";
+    pub const CIPHER: &str = "aes-256-cbc";
 
+    pub fn kdf_iter(&self) -> usize {
+        self.kdf_iter.unwrap_or(Self::KDF_ITER)
+    }
     pub fn display_pragma_stmts(&self) -> DisplayPragmaStmts<'_> {
         DisplayPragmaStmts(self)
     }
+    pub fn display_default_pragma_stmts(&self) -> DisplayDefaultPragmaStmts<'_> {
+        DisplayDefaultPragmaStmts(self)
+    }
 }
 
 #[derive(Debug, Snafu)]
@@ -145,4 +262,10 @@ pub enum Error {
     Windows { source: windows::Error },
     #[snafu(transparent)]
     Android { source: android::Error },
+    #[snafu(transparent)]
+    Linux { source: linux::Error },
+    #[snafu(transparent)]
+    KeyScan { source: keyscan::Error },
+    #[snafu(transparent)]
+    Analyzer { source: analyzer::Error },
 }
@@ -0,0 +1,667 @@
+//! Locate the key function in `wrapper.node`, whatever format and architecture QQ ships it in.
+//!
+//! The function is found through a string it references, see [DEFAULT_PATTERNS]:
+//! 1. Find the string in the read-only data of the binary.
+//! 2. Find the instructions referencing it, with the [ReferenceFinder] of the binary's architecture.
+//! 3. Find the function containing each of them, from the unwind info, symbols or prologues, see [BoundsSource].
+//!
+//! Everything found along the way is kept in an [AnalysisReport],
+//! so a new QQ build that fails the analysis can be diagnosed from the report alone.
+//!
+//! Supported formats are PE (Windows), ELF (Linux) and Mach-O including universal binaries (macOS).
+//! Supported architectures are x86_64, ARM64 and 32-bit x86.
+
+mod arch;
+mod bounds;
+#[cfg(test)]
+mod samples;
+mod signatures;
+pub use arch::*;
+pub use bounds::BoundsSource;
+pub use object::Architecture;
+pub use signatures::*;
+
+use core::fmt;
+use object::{Object, ObjectSection};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::prelude::*;
+use std::fs;
+use std::path::Path;
+
+/// The strings the key function references, the log format string of `sqlite3_key_v2`.
+pub const DEFAULT_PATTERNS: &[&[u8]] = &[b"nt_sqlite3_key_v2: db=%p zDb=%s"];
+
+/// All the fields are offsets from the image base, see [AnalysisReport::image_base].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TargetFunction {
+    /// The begin address of the function.
+    /// int64 f(const void *a1, const char *a2, int64 a3, unsigned int a4)
+    /// ref: https://docs.aaqwq.top/decrypt/NTQQ%20(Windows).html#%E6%89%BE%E5%88%B0%E6%95%B0%E6%8D%AE%E5%BA%93-passphrase
+    pub function_offset: u64,
+    /// Offset of the instruction referencing the log format string, which is a LEA on x86_64,
+    /// despite the name, an ADRP on ARM64, and a `push`/`mov` on 32-bit x86.
+    pub lea_instr_offset: u64,
+}
+
+impl TargetFunction {
+    /// Find the function in a `wrapper.node` file, see [TargetFunction::from_wrapper_node].
+    pub fn from_wrapper_node_file(wrapper_node: &Path) -> crate::Result<TargetFunction> {
+        Ok(Analyzer::new()
+            .analyze_file(wrapper_node)?
+            .target_function()?)
+    }
+
+    /// Find the function in the content of a `wrapper.node` with the default [Analyzer].
+    ///
+    /// For ELF, the offsets are relative to the load address of the shared object,
+    /// i.e. the start of its first mapping. For Mach-O, to the `__TEXT` segment.
+    pub fn from_wrapper_node(data: &[u8]) -> crate::Result<TargetFunction> {
+        Ok(Analyzer::new().analyze(data)?.target_function()?)
+    }
+}
+
+/// Searches binaries for the key function, with pluggable string patterns and architectures.
+pub struct Analyzer {
+    patterns: Vec<Vec<u8>>,
+    finders: Vec<Box<dyn ReferenceFinder>>,
+    slice: Option<Architecture>,
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self {
+            patterns: DEFAULT_PATTERNS.iter().map(|x| x.to_vec()).collect(),
+            finders: vec![
+                Box::new(X86_64Lea),
+                Box::new(Aarch64AdrpAdd),
+                Box::new(X86Absolute),
+            ],
+            slice: None,
+        }
+    }
+}
+
+impl Analyzer {
+    /// An analyzer with [DEFAULT_PATTERNS] and all the built-in architectures.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also look for `pattern`, e.g. when a new QQ build changes the log string.
+    pub fn pattern(mut self, pattern: impl Into<Vec<u8>>) -> Self {
+        self.patterns.push(pattern.into());
+        self
+    }
+
+    /// Look for `patterns` only, instead of [DEFAULT_PATTERNS].
+    pub fn patterns<P: Into<Vec<u8>>>(mut self, patterns: impl IntoIterator<Item = P>) -> Self {
+        self.patterns = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Use `finder` for its architecture, replacing the one registered before if any.
+    pub fn reference_finder(mut self, finder: impl ReferenceFinder + 'static) -> Self {
+        self.finders
+            .retain(|x| x.architecture() != finder.architecture());
+        self.finders.push(Box::new(finder));
+        self
+    }
+
+    /// Analyze the `arch` slice of a Mach-O universal binary, instead of the one of the running architecture.
+    /// Other binaries have a single architecture, and are analyzed as is.
+    pub fn slice(mut self, arch: Architecture) -> Self {
+        self.slice = Some(arch);
+        self
+    }
+
+    /// The architecture [Analyzer::analyze] analyzes in `data`, i.e. of the slice it picks in a universal binary.
+    pub fn architecture_of(&self, data: &[u8]) -> Result<Architecture> {
+        let (_, data) = self.universal_slice(data)?;
+        Ok(object::File::parse(data)?.architecture())
+    }
+
+    pub fn analyze_file(&self, path: &Path) -> Result<AnalysisReport> {
+        self.analyze(&map_file(path)?)
+    }
+
+    /// Analyze the content of a binary.
+    ///
+    /// This fails only if the binary can't be analyzed at all, e.g. unsupported format or architecture.
+    /// Not finding the function is reported in the [AnalysisReport], see [AnalysisReport::target_function].
+    pub fn analyze(&self, data: &[u8]) -> Result<AnalysisReport> {
+        let sha256 = sha256_hex(data);
+        let (slice_offset, data) = self.universal_slice(data)?;
+        let obj = object::File::parse(data)?;
+        let architecture = obj.architecture();
+        let finder = self
+            .finders
+            .iter()
+            .find(|x| x.architecture() == architecture)
+            .context(UnsupportedArchitectureSnafu {
+                arch: format!("{:?}", architecture),
+            })?;
+        let image_base = match &obj {
+            object::File::MachO64(macho) => bounds::macho_text_segment_addr(macho)?,
+            _ => obj.relative_address_base(),
+        };
+
+        let mut strings = Vec::new();
+        for section in obj.sections() {
+            if !section.name().is_ok_and(|x| is_string_section(&obj, x)) {
+                continue;
+            }
+            let section_data = section.data()?;
+            for pattern in &self.patterns {
+                for pos in memchr::memmem::find_iter(section_data, pattern) {
+                    strings.push(StringMatch {
+                        pattern: String::from_utf8_lossy(pattern).into_owned(),
+                        section: section.name()?.to_owned(),
+                        offset: section.address() + pos as u64 - image_base,
+                    });
+                }
+            }
+        }
+
+        let text = text_section(&obj)?;
+        let text_data = text.data()?;
+        let mut candidates = Vec::new();
+        for string in &strings {
+            let mut refs =
+                finder.find_references(text_data, text.address(), image_base + string.offset)?;
+            refs.sort_unstable();
+            refs.dedup();
+            for instr_addr in refs {
+                let mut candidate = Candidate {
+                    string_offset: string.offset,
+                    instr_offset: instr_addr - image_base,
+                    function_offset: None,
+                    bounds_source: None,
+                    error: None,
+                };
+                match bounds::function_begin(&obj, instr_addr) {
+                    Ok((begin, source)) => {
+                        candidate.function_offset = Some(begin - image_base);
+                        candidate.bounds_source = Some(source);
+                    }
+                    Err(e) => candidate.error = Some(e.to_string()),
+                }
+                candidates.push(candidate);
+            }
+        }
+
+        let (chosen, confidence) = choose(&candidates);
+        Ok(AnalysisReport {
+            sha256,
+            format: format!("{:?}", obj.format()),
+            architecture: format!("{:?}", architecture),
+            slice_offset,
+            image_base,
+            patterns: self
+                .patterns
+                .iter()
+                .map(|x| String::from_utf8_lossy(x).into_owned())
+                .collect(),
+            strings,
+            candidates,
+            chosen,
+            confidence,
+        })
+    }
+}
+
+fn map_file(path: &Path) -> Result<memmap2::Mmap> {
+    let file = fs::File::open(path).context(IoSnafu {
+        op: "open wrapper.node file",
+    })?;
+    unsafe {
+        // SAFETY: the executable file should not be modified during the mapping lifetime, in practice.
+        memmap2::MmapOptions::new().map(&file)
+    }
+    .context(IoSnafu {
+        op: "mmap wrapper.node file",
+    })
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Pick the function most candidates are in, the first one on a tie.
+fn choose(candidates: &[Candidate]) -> (Option<TargetFunction>, Confidence) {
+    let located = candidates
+        .iter()
+        .filter(|x| x.function_offset.is_some())
+        .collect::<Vec<_>>();
+    let count = |function_offset| {
+        located
+            .iter()
+            .filter(|x| x.function_offset == function_offset)
+            .count()
+    };
+    let Some(best) = located
+        .iter()
+        .rev()
+        .max_by_key(|x| count(x.function_offset))
+    else {
+        return (None, Confidence::NotFound);
+    };
+    let single_function = located
+        .iter()
+        .all(|x| x.function_offset == best.function_offset);
+    let confidence = if !single_function {
+        Confidence::Low
+    } else if located
+        .iter()
+        .all(|x| x.bounds_source.is_some_and(BoundsSource::is_exact))
+    {
+        Confidence::High
+    } else {
+        Confidence::Medium
+    };
+    let chosen = TargetFunction {
+        function_offset: best.function_offset.unwrap(),
+        lea_instr_offset: best.instr_offset,
+    };
+    (Some(chosen), confidence)
+}
+
+/// Sections the patterns are searched in.
+fn is_string_section(obj: &object::File, name: &str) -> bool {
+    match obj.format() {
+        object::BinaryFormat::Pe => name == ".rdata",
+        object::BinaryFormat::Elf => name.starts_with(".rodata"),
+        object::BinaryFormat::MachO => name == "__cstring" || name == "__const",
+        _ => false,
+    }
+}
+
+fn text_section<'data, 'file>(
+    obj: &'file object::File<'data>,
+) -> Result<object::Section<'data, 'file>> {
+    // also matches `__text` of Mach-O
+    obj.section_by_name(".text")
+        .context(SectionNotFoundSnafu { name: ".text" })
+}
+
+impl Analyzer {
+    /// Pick the slice of a Mach-O universal binary to analyze: the one set with [Analyzer::slice] if any.
+    /// Otherwise the one of the running architecture if any, or else the first x86_64 or ARM64 one.
+    /// Anything else is returned as is.
+    ///
+    /// Returns the offset of the slice in `data` along with it.
+    fn universal_slice<'data>(&self, data: &'data [u8]) -> Result<(u64, &'data [u8])> {
+        use object::read::macho::{FatArch, MachOFatFile32, MachOFatFile64};
+        fn pick<'data, Fat: FatArch>(
+            data: &'data [u8],
+            arches: &[Fat],
+            slice: Option<Architecture>,
+        ) -> Result<(u64, &'data [u8])> {
+            let arch = match slice {
+                Some(slice) => arches.iter().find(|x| x.architecture() == slice).context(
+                    NoUniversalSliceSnafu {
+                        arch: format!("{:?}", slice),
+                    },
+                )?,
+                None => {
+                    let native = if cfg!(target_arch = "aarch64") {
+                        Architecture::Aarch64
+                    } else {
+                        Architecture::X86_64
+                    };
+                    arches
+                        .iter()
+                        .find(|x| x.architecture() == native)
+                        .or_else(|| {
+                            arches.iter().find(|x| {
+                                matches!(
+                                    x.architecture(),
+                                    Architecture::X86_64 | Architecture::Aarch64
+                                )
+                            })
+                        })
+                        .context(NoUniversalSliceSnafu {
+                            arch: "x86_64 or ARM64",
+                        })?
+                }
+            };
+            Ok((arch.offset().into(), arch.data(data)?))
+        }
+        match object::FileKind::parse(data)? {
+            object::FileKind::MachOFat32 => {
+                pick(data, MachOFatFile32::parse(data)?.arches(), self.slice)
+            }
+            object::FileKind::MachOFat64 => {
+                pick(data, MachOFatFile64::parse(data)?.arches(), self.slice)
+            }
+            _ => Ok((0, data)),
+        }
+    }
+}
+
+/// Everything found analyzing a binary. All the addresses are offsets from [AnalysisReport::image_base].
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalysisReport {
+    /// SHA-256 of the whole file, in hex.
+    pub sha256: String,
+    pub format: String,
+    pub architecture: String,
+    /// Offset of the analyzed slice in a Mach-O universal binary, otherwise 0.
+    pub slice_offset: u64,
+    /// Virtual address the offsets are relative to: the image base for PE,
+    /// the first mapping for ELF, the `__TEXT` segment for Mach-O.
+    pub image_base: u64,
+    /// The patterns searched.
+    pub patterns: Vec<String>,
+    /// Where the patterns are found.
+    pub strings: Vec<StringMatch>,
+    /// Every instruction found referencing one of [AnalysisReport::strings].
+    pub candidates: Vec<Candidate>,
+    /// The function picked from the candidates.
+    pub chosen: Option<TargetFunction>,
+    pub confidence: Confidence,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StringMatch {
+    pub pattern: String,
+    pub section: String,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Candidate {
+    /// The string referenced.
+    pub string_offset: u64,
+    /// The referencing instruction.
+    pub instr_offset: u64,
+    /// Begin of the function containing the instruction, if located.
+    pub function_offset: Option<u64>,
+    pub bounds_source: Option<BoundsSource>,
+    /// Why the function is not located.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Confidence {
+    /// All the candidates are in one function, whose bounds are recorded in the binary.
+    High,
+    /// All the candidates are in one function, whose bounds are guessed, see [BoundsSource::is_exact].
+    Medium,
+    /// The candidates are in different functions.
+    Low,
+    /// No function is located.
+    NotFound,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl AnalysisReport {
+    /// The chosen function, or why there's none.
+    pub fn target_function(&self) -> Result<TargetFunction> {
+        if let Some(chosen) = &self.chosen {
+            return Ok(chosen.clone());
+        }
+        ensure!(
+            !self.strings.is_empty(),
+            PatternNotFoundSnafu {
+                patterns: self.patterns.clone(),
+            }
+        );
+        let candidate = self.candidates.first().context(ReferenceNotFoundSnafu {
+            strings: self.strings.len(),
+        })?;
+        FunctionNotLocatedSnafu {
+            addr: self.image_base + candidate.instr_offset,
+            method: candidate.bounds_source,
+            reason: candidate.error.clone().unwrap_or_default(),
+        }
+        .fail()
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub enum Error {
+    #[snafu(display("IO error when {}: {}", op, source))]
+    Io { source: std::io::Error, op: String },
+    #[snafu(transparent)]
+    Object { source: object::Error },
+    #[snafu(transparent)]
+    Capstone { source: capstone::Error },
+    #[snafu(transparent)]
+    Gimli { source: gimli::Error },
+    #[snafu(display("malformed signature database {}: {}", path.display(), source))]
+    MalformedSignatureDb {
+        source: serde_json::Error,
+        path: std::path::PathBuf,
+    },
+    #[snafu(display(
+        "signature database {} is of a newer format version {}",
+        path.display(),
+        version
+    ))]
+    UnsupportedSignatureDb {
+        path: std::path::PathBuf,
+        version: u32,
+    },
+    #[snafu(display("unsupported binary format {}", format))]
+    UnsupportedFormat { format: String },
+    #[snafu(display("no reference finder for architecture {}", arch))]
+    UnsupportedArchitecture { arch: String },
+    #[snafu(display("no {} slice in universal binary", arch))]
+    NoUniversalSlice { arch: String },
+    #[snafu(display("{} not found", name))]
+    SectionNotFound { name: String },
+    #[snafu(display("none of the patterns {:?} found in read-only data", patterns))]
+    PatternNotFound { patterns: Vec<String> },
+    #[snafu(display(
+        "no instruction references any of the {} pattern strings found",
+        strings
+    ))]
+    ReferenceNotFound { strings: usize },
+    #[snafu(display(
+        "function containing the instruction at 0x{:X} not located{}: {}",
+        addr,
+        method.map(|x| format!(" through {x}")).unwrap_or_default(),
+        reason
+    ))]
+    FunctionNotLocated {
+        addr: u64,
+        method: Option<BoundsSource>,
+        reason: String,
+    },
+}
+
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> Self {
+        super::Error::from(e).into()
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::samples::*;
+    use super::*;
+
+    #[test]
+    fn elf() {
+        for (bounds, source) in [
+            (ElfBounds::Symbols, BoundsSource::Symbol),
+            (ElfBounds::EhFrame, BoundsSource::EhFrame),
+        ] {
+            let (data, layout) = elf_x86_64(bounds);
+            let report = Analyzer::new().analyze(&data).unwrap();
+            assert_eq!(report.format, "Elf");
+            assert_eq!(report.architecture, "X86_64");
+            assert_eq!(report.image_base, 0);
+            assert_eq!(report.strings.len(), 1);
+            assert_eq!(report.strings[0].section, ".rodata");
+            assert_eq!(report.strings[0].offset, layout.rodata);
+            assert_eq!(report.candidates.len(), 1);
+            assert_eq!(report.candidates[0].bounds_source, Some(source));
+            assert_eq!(
+                report.target_function().unwrap(),
+                TargetFunction {
+                    function_offset: layout.function,
+                    lea_instr_offset: layout.reference,
+                }
+            );
+            assert_eq!(report.confidence, Confidence::High);
+        }
+    }
+
+    #[test]
+    fn pe() {
+        let samples = [
+            (pe_x86_64(true), "X86_64", PE64_IMAGE_BASE, Confidence::High),
+            (
+                pe_arm64(Arm64Unwind::Packed),
+                "Aarch64",
+                PE64_IMAGE_BASE,
+                Confidence::High,
+            ),
+            (
+                pe_arm64(Arm64Unwind::Xdata),
+                "Aarch64",
+                PE64_IMAGE_BASE,
+                Confidence::High,
+            ),
+            (pe_i386(), "I386", PE32_IMAGE_BASE, Confidence::Medium),
+        ];
+        for ((data, layout), architecture, image_base, confidence) in samples {
+            let report = Analyzer::new().analyze(&data).unwrap();
+            assert_eq!(report.format, "Pe");
+            assert_eq!(report.architecture, architecture);
+            assert_eq!(report.image_base, image_base);
+            assert_eq!(report.strings.len(), 1);
+            assert_eq!(report.strings[0].section, ".rdata");
+            assert_eq!(report.strings[0].offset, layout.rodata);
+            assert_eq!(report.candidates.len(), 1, "{architecture}");
+            assert_eq!(
+                report.target_function().unwrap(),
+                TargetFunction {
+                    function_offset: layout.function,
+                    lea_instr_offset: layout.reference,
+                }
+            );
+            assert_eq!(report.confidence, confidence);
+        }
+    }
+
+    #[test]
+    fn macho() {
+        for (arch, name) in [(MachoArch::X86_64, "X86_64"), (MachoArch::Arm64, "Aarch64")] {
+            let (data, layout) = samples::macho(arch);
+            let report = Analyzer::new().analyze(&data).unwrap();
+            assert_eq!(report.format, "MachO");
+            assert_eq!(report.architecture, name);
+            assert_eq!(report.slice_offset, 0);
+            assert_eq!(report.image_base, MACHO_TEXT_SEGMENT);
+            assert_eq!(report.strings.len(), 1);
+            assert_eq!(report.strings[0].section, "__cstring");
+            assert_eq!(report.strings[0].offset, layout.rodata);
+            assert_eq!(
+                report.target_function().unwrap(),
+                TargetFunction {
+                    function_offset: layout.function,
+                    lea_instr_offset: layout.reference,
+                }
+            );
+            // LC_FUNCTION_STARTS has no function ends
+            assert_eq!(report.confidence, Confidence::Medium);
+        }
+    }
+
+    #[test]
+    fn universal_slice() {
+        let (x86_64, x86_64_layout) = samples::macho(MachoArch::X86_64);
+        let (aarch64, aarch64_layout) = samples::macho(MachoArch::Arm64);
+        let (data, offsets) = universal(&[&x86_64, &aarch64]);
+        let analyze = |analyzer: Analyzer| {
+            let architecture = analyzer.architecture_of(&data).unwrap();
+            let report = analyzer.analyze(&data).unwrap();
+            assert_eq!(report.architecture, format!("{:?}", architecture));
+            assert_eq!(report.sha256, sha256_hex(&data));
+            report
+        };
+
+        let report = analyze(Analyzer::new().slice(Architecture::X86_64));
+        assert_eq!(report.architecture, "X86_64");
+        assert_eq!(report.slice_offset, offsets[0]);
+        assert_eq!(
+            report.chosen.unwrap().function_offset,
+            x86_64_layout.function
+        );
+
+        let report = analyze(Analyzer::new().slice(Architecture::Aarch64));
+        assert_eq!(report.architecture, "Aarch64");
+        assert_eq!(report.slice_offset, offsets[1]);
+        assert_eq!(
+            report.chosen.unwrap().function_offset,
+            aarch64_layout.function
+        );
+
+        // the running architecture by default, whichever the order
+        let native = if cfg!(target_arch = "aarch64") { 1 } else { 0 };
+        assert_eq!(analyze(Analyzer::new()).slice_offset, offsets[native]);
+        let (data, offsets) = universal(&[&aarch64, &x86_64]);
+        let report = Analyzer::new().analyze(&data).unwrap();
+        assert_eq!(report.slice_offset, offsets[1 - native]);
+
+        // or the first supported one
+        let (data, offsets) = universal(&[if native == 0 { &aarch64 } else { &x86_64 }]);
+        let report = Analyzer::new().analyze(&data).unwrap();
+        assert_eq!(report.slice_offset, offsets[0]);
+
+        assert!(matches!(
+            Analyzer::new().slice(Architecture::I386).analyze(&data),
+            Err(Error::NoUniversalSlice { .. })
+        ));
+        // not a universal binary
+        let report = Analyzer::new()
+            .slice(Architecture::Aarch64)
+            .analyze(&x86_64)
+            .unwrap();
+        assert_eq!(report.architecture, "X86_64");
+    }
+
+    #[test]
+    fn elf_without_bounds() {
+        let (data, layout) = elf_x86_64(ElfBounds::None);
+        let report = Analyzer::new().analyze(&data).unwrap();
+        assert_eq!(report.candidates[0].instr_offset, layout.reference);
+        assert_eq!(report.confidence, Confidence::NotFound);
+        assert!(matches!(
+            report.target_function(),
+            Err(Error::FunctionNotLocated { .. })
+        ));
+    }
+
+    #[test]
+    fn pattern_not_found() {
+        let (data, layout) = elf_x86_64(ElfBounds::Symbols);
+        let report = Analyzer::new()
+            .patterns(["no such string"])
+            .analyze(&data)
+            .unwrap();
+        assert!(report.strings.is_empty());
+        assert!(matches!(
+            report.target_function(),
+            Err(Error::PatternNotFound { .. })
+        ));
+
+        // referenced by the decoy function only
+        let report = Analyzer::new().patterns([OTHER]).analyze(&data).unwrap();
+        assert_eq!(
+            report.target_function().unwrap().function_offset,
+            layout.text
+        );
+    }
+}
@@ -0,0 +1,354 @@
+//! Finding the instructions that reference a string, per architecture.
+
+use super::*;
+use capstone::Capstone;
+use capstone::arch::{BuildsCapstone, BuildsCapstoneSyntax};
+
+/// Finds the instructions referencing an address, for one architecture.
+///
+/// Register one with [Analyzer::reference_finder] to support a new architecture,
+/// or a new way the string is referenced.
+pub trait ReferenceFinder: Send + Sync {
+    fn architecture(&self) -> Architecture;
+    /// Addresses of the instructions in `text`, which is loaded at `text_addr`, referencing `target_addr`.
+    fn find_references(&self, text: &[u8], text_addr: u64, target_addr: u64) -> Result<Vec<u64>>;
+}
+
+/// x86_64: a RIP-relative LEA.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct X86_64Lea;
+
+impl ReferenceFinder for X86_64Lea {
+    fn architecture(&self) -> Architecture {
+        Architecture::X86_64
+    }
+    fn find_references(&self, text: &[u8], text_addr: u64, target_addr: u64) -> Result<Vec<u64>> {
+        let cs = Capstone::new()
+            .x86()
+            .mode(capstone::arch::x86::ArchMode::Mode64)
+            .syntax(capstone::arch::x86::ArchSyntax::Intel)
+            .detail(true)
+            .build()?;
+
+        let mut found = Vec::new();
+        for i in memchr::memchr_iter(0x8d, text) {
+            // 64位相对寻址。 Prefix(1B)    Opcode(1B)    ModR/M(1B)     Displacement(4B)
+            // 所以长度固定为 7 字节
+            let (Some(ins_l), ins_r) = (i.checked_sub(1), i.wrapping_add(6)) else {
+                continue;
+            };
+            let Some(code) = text.get(ins_l..ins_r) else {
+                continue;
+            };
+            let addr = text_addr + ins_l as u64;
+            let Ok(insn) = cs.disasm_count(code, addr, 1) else {
+                continue;
+            };
+            let Some(insn) = insn.iter().next() else {
+                continue;
+            };
+            if insn.id().0 != capstone::arch::x86::X86Insn::X86_INS_LEA as u32 {
+                continue;
+            }
+            let detail = cs.insn_detail(insn)?;
+            let ops = detail.arch_detail().operands();
+            let Some(capstone::arch::ArchOperand::X86Operand(op)) = ops.get(1) else {
+                continue;
+            };
+            let capstone::arch::x86::X86OperandType::Mem(mem) = op.op_type else {
+                continue;
+            };
+            if mem.base().0 != capstone::arch::x86::X86Reg::X86_REG_RIP as u16 {
+                continue;
+            }
+            let lea_target_addr = insn
+                .address()
+                .wrapping_add(insn.len() as u64)
+                .wrapping_add(mem.disp() as u64);
+            if lea_target_addr == target_addr {
+                found.push(insn.address());
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// ARM64: an ADRP+ADD pair. The reference is the ADRP.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aarch64AdrpAdd;
+
+impl Aarch64AdrpAdd {
+    /// How far after an ADRP the ADD is looked for, as the compiler may schedule
+    /// other instructions in between.
+    pub const ADD_SEARCH_WINDOW: usize = 8;
+}
+
+impl ReferenceFinder for Aarch64AdrpAdd {
+    fn architecture(&self) -> Architecture {
+        Architecture::Aarch64
+    }
+    fn find_references(&self, text: &[u8], text_addr: u64, target_addr: u64) -> Result<Vec<u64>> {
+        let words = text
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let mut found = Vec::new();
+        for (i, &adrp) in words.iter().enumerate() {
+            // ADRP Xd, label: 1 immlo(2) 10000 immhi(19) Rd(5)
+            if adrp & 0x9F00_0000 != 0x9000_0000 {
+                continue;
+            }
+            let pc = text_addr + i as u64 * 4;
+            let rd = adrp & 0x1F;
+            let imm = (((adrp >> 5) & 0x7FFFF) << 2) | ((adrp >> 29) & 0x3);
+            // sign extend the 21-bit immediate, in pages
+            let imm = ((imm << 11) as i32 >> 11) as i64;
+            let page = (pc & !0xFFF).wrapping_add((imm << 12) as u64);
+            if page != target_addr & !0xFFF {
+                continue;
+            }
+            let add = words[i + 1..]
+                .iter()
+                .take(Self::ADD_SEARCH_WINDOW)
+                // ADD Xd, Xn, #imm12 (not shifted): 1001000100 imm12 Rn(5) Rd(5)
+                .find(|&&add| add & 0xFFC0_0000 == 0x9100_0000 && (add >> 5) & 0x1F == rd);
+            if let Some(add) = add
+                && page + ((add >> 10) & 0xFFF) as u64 == target_addr
+            {
+                found.push(pc);
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// 32-bit x86: a `push imm32` or `mov ..., imm32` of the absolute address.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct X86Absolute;
+
+impl ReferenceFinder for X86Absolute {
+    fn architecture(&self) -> Architecture {
+        Architecture::I386
+    }
+    fn find_references(&self, text: &[u8], text_addr: u64, target_addr: u64) -> Result<Vec<u64>> {
+        use capstone::arch::x86::X86Insn;
+        let cs = Capstone::new()
+            .x86()
+            .mode(capstone::arch::x86::ArchMode::Mode32)
+            .syntax(capstone::arch::x86::ArchSyntax::Intel)
+            .detail(true)
+            .build()?;
+        let needle = (target_addr as u32).to_le_bytes();
+        let mut found = Vec::new();
+        for imm_pos in memchr::memmem::find_iter(text, &needle) {
+            // the immediate ends the instruction, which is at most 7 bytes before it,
+            // e.g. `mov dword ptr [esp + disp32], imm32` is C7 84 24 disp32 imm32
+            for len_before in 1..=7 {
+                let Some(begin) = imm_pos.checked_sub(len_before) else {
+                    break;
+                };
+                let code = &text[begin..imm_pos + 4];
+                let Ok(insns) = cs.disasm_count(code, text_addr + begin as u64, 1) else {
+                    continue;
+                };
+                let Some(insn) = insns.iter().next() else {
+                    continue;
+                };
+                let id = insn.id().0;
+                if insn.len() != code.len()
+                    || (id != X86Insn::X86_INS_PUSH as u32 && id != X86Insn::X86_INS_MOV as u32)
+                {
+                    continue;
+                }
+                let detail = cs.insn_detail(insn)?;
+                let uses_target = detail.arch_detail().operands().iter().any(|op| {
+                    matches!(
+                        op,
+                        capstone::arch::ArchOperand::X86Operand(x)
+                            if matches!(x.op_type, capstone::arch::x86::X86OperandType::Imm(imm) if imm as u32 == target_addr as u32)
+                    )
+                });
+                if uses_target {
+                    found.push(insn.address());
+                    break;
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::samples::adrp_add;
+    use super::*;
+
+    const TEXT: u64 = 0x1000;
+    const TARGET: u64 = 0x2000;
+
+    /// `lea <reg>, [rip + disp32]` at `addr` with the REX prefix `rex` and ModR/M `modrm`, referencing `target`.
+    fn lea(rex: u8, modrm: u8, addr: u64, target: u64) -> Vec<u8> {
+        let disp = target.wrapping_sub(addr + 7) as i32;
+        [[rex, 0x8D, modrm].as_slice(), &disp.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn x86_64_lea() {
+        let mut text = vec![0x90; 4];
+        // lea rdx, [rip + TARGET]
+        text.extend(lea(0x48, 0x15, TEXT + 4, TARGET));
+        // lea rax, [rsp]: not rip-relative
+        text.extend([0x48, 0x8D, 0x04, 0x24]);
+        // lea rcx, [rip + TARGET + 8]: elsewhere
+        text.extend(lea(0x48, 0x0D, TEXT + 15, TARGET + 8));
+        // lea r8, [rip + TARGET]
+        text.extend(lea(0x4C, 0x05, TEXT + 22, TARGET));
+        // mov rdx, [rip + TARGET]: not a lea
+        text.extend([0x48, 0x8B, 0x15]);
+        text.extend(((TARGET - (TEXT + 36)) as i32).to_le_bytes());
+        // a lea cut off at the end
+        text.extend([0x48, 0x8D, 0x15, 0x00]);
+
+        let found = X86_64Lea.find_references(&text, TEXT, TARGET).unwrap();
+        assert_eq!(found, [TEXT + 4, TEXT + 22]);
+        assert_eq!(
+            X86_64Lea.find_references(&text, TEXT, TARGET + 8).unwrap(),
+            [TEXT + 15]
+        );
+        assert!(
+            X86_64Lea
+                .find_references(&text, TEXT, TARGET + 1)
+                .unwrap()
+                .is_empty()
+        );
+        // the displacement is relative to the address the text is loaded at
+        assert!(
+            X86_64Lea
+                .find_references(&text, TEXT + 0x100, TARGET)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            X86_64Lea
+                .find_references(&text, TEXT + 0x100, TARGET + 0x100)
+                .unwrap(),
+            [TEXT + 0x104, TEXT + 0x116]
+        );
+    }
+
+    #[test]
+    fn aarch64_adrp_add() {
+        const NOP: u32 = 0xD503_201F;
+        let target = TARGET + 0x123;
+        let mut words = vec![NOP];
+        // adjacent
+        words.extend(adrp_add(0, TEXT + 4, target, 0));
+        // as far as the window allows
+        words.extend(adrp_add(
+            1,
+            TEXT + 12,
+            target,
+            Aarch64AdrpAdd::ADD_SEARCH_WINDOW - 1,
+        ));
+        // too far
+        words.extend(adrp_add(
+            2,
+            TEXT + 48,
+            target,
+            Aarch64AdrpAdd::ADD_SEARCH_WINDOW,
+        ));
+        // the ADD is on another register
+        let mut other_register = adrp_add(3, TEXT + 88, target, 0);
+        other_register[1] = (other_register[1] & !0x3FF) | 4 << 5 | 4;
+        words.extend(other_register);
+        // another offset in the page
+        words.extend(adrp_add(5, TEXT + 96, target + 8, 0));
+        // ADRP only, at the end
+        words.extend(&adrp_add(6, TEXT + 104, target, 0)[..1]);
+        let text = words
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            Aarch64AdrpAdd.find_references(&text, TEXT, target).unwrap(),
+            [TEXT + 4, TEXT + 12]
+        );
+        assert_eq!(
+            Aarch64AdrpAdd
+                .find_references(&text, TEXT, target + 8)
+                .unwrap(),
+            [TEXT + 96]
+        );
+        assert!(
+            Aarch64AdrpAdd
+                .find_references(&text, TEXT, target + 0x1000)
+                .unwrap()
+                .is_empty()
+        );
+
+        // the page offset is negative
+        let text_addr = 0x10_0000;
+        let text = adrp_add(0, text_addr, target, 0)
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            Aarch64AdrpAdd
+                .find_references(&text, text_addr, target)
+                .unwrap(),
+            [text_addr]
+        );
+    }
+
+    #[test]
+    fn x86_absolute() {
+        let target = 0x1000_2000u32;
+        let imm = target.to_le_bytes();
+        let text = [
+            // push target
+            [0x68].as_slice(),
+            &imm,
+            // mov eax, target
+            &[0xB8],
+            &imm,
+            // mov dword ptr [esp + 4], target
+            &[0xC7, 0x44, 0x24, 0x04],
+            &imm,
+            // mov dword ptr [esp + 0x100], target
+            &[0xC7, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00],
+            &imm,
+            // cmp eax, target
+            &[0x3D],
+            &imm,
+            // add eax, target
+            &[0x05],
+            &imm,
+            // push target + 8
+            &[0x68],
+            &(target + 8).to_le_bytes(),
+            // the address as data
+            &imm,
+        ]
+        .concat();
+        let text_addr = 0x1000_1000;
+        assert_eq!(
+            X86Absolute
+                .find_references(&text, text_addr, target as u64)
+                .unwrap(),
+            [text_addr, text_addr + 5, text_addr + 10, text_addr + 18]
+        );
+        assert_eq!(
+            X86Absolute
+                .find_references(&text, text_addr, target as u64 + 8)
+                .unwrap(),
+            [text_addr + 39]
+        );
+        assert!(
+            X86Absolute
+                .find_references(&text, text_addr, 0x2000)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,504 @@
+//! Locating the function containing an instruction, per binary format.
+
+use super::*;
+use gimli::UnwindSection;
+use object::pe::{IMAGE_DIRECTORY_ENTRY_EXCEPTION, ImageRuntimeFunctionEntry};
+use object::read::macho::{LoadCommandVariant, MachOFile64};
+use object::read::pe::PeFile64;
+use object::{ObjectSegment, ObjectSymbol};
+use std::cmp::Ordering;
+
+/// Where the bounds of a function came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BoundsSource {
+    /// PE `.pdata`, x86_64 or ARM64.
+    ExceptionDirectory,
+    /// ELF `.symtab` or `.dynsym`.
+    Symbol,
+    /// ELF `.eh_frame`.
+    EhFrame,
+    /// Mach-O `LC_FUNCTION_STARTS`, which has no end of the functions.
+    FunctionStarts,
+    /// Guessed from the nearest prologue, for 32-bit PE which has no unwind info.
+    Prologue,
+}
+
+impl BoundsSource {
+    /// Whether the bounds are recorded in the binary, rather than guessed.
+    pub fn is_exact(self) -> bool {
+        matches!(
+            self,
+            BoundsSource::ExceptionDirectory | BoundsSource::Symbol | BoundsSource::EhFrame
+        )
+    }
+}
+
+impl fmt::Display for BoundsSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BoundsSource::ExceptionDirectory => "exception directory",
+            BoundsSource::Symbol => "symbol table",
+            BoundsSource::EhFrame => ".eh_frame",
+            BoundsSource::FunctionStarts => "LC_FUNCTION_STARTS",
+            BoundsSource::Prologue => "function prologue",
+        })
+    }
+}
+
+/// Find the begin address of the function containing `addr`, through whatever the format of `obj` has.
+pub(super) fn function_begin(obj: &object::File, addr: u64) -> Result<(u64, BoundsSource)> {
+    match obj {
+        object::File::Pe64(pe) if obj.architecture() == Architecture::Aarch64 => {
+            let source = BoundsSource::ExceptionDirectory;
+            Ok((arm64_pe_function_begin(pe, addr)?, source))
+        }
+        object::File::Pe64(pe) => Ok((
+            pe64_function_begin(pe, addr)?,
+            BoundsSource::ExceptionDirectory,
+        )),
+        object::File::Pe32(_) => Ok((x86_function_begin(obj, addr)?, BoundsSource::Prologue)),
+        object::File::MachO64(macho) => Ok((
+            macho_function_begin(macho, addr)?,
+            BoundsSource::FunctionStarts,
+        )),
+        _ if obj.format() == object::BinaryFormat::Elf => match symbol_function_begin(obj, addr) {
+            Some(x) => Ok((x, BoundsSource::Symbol)),
+            None => Ok((eh_frame_function_begin(obj, addr)?, BoundsSource::EhFrame)),
+        },
+        _ => UnsupportedFormatSnafu {
+            format: format!("{:?}", obj.format()),
+        }
+        .fail(),
+    }
+}
+
+/// Find the begin address of the function containing `addr` through the x86_64 exception directory.
+fn pe64_function_begin(pe: &PeFile64, addr: u64) -> Result<u64> {
+    let source = BoundsSource::ExceptionDirectory;
+    let image_base = pe.relative_address_base();
+    let data = exception_directory(pe)?;
+    // SAFETY: prechecked PE format and data directory
+    let entries: &[ImageRuntimeFunctionEntry] = unsafe {
+        std::slice::from_raw_parts(
+            data.as_ptr() as *const ImageRuntimeFunctionEntry,
+            data.len() / std::mem::size_of::<ImageRuntimeFunctionEntry>(),
+        )
+    };
+    let target_rva = (addr - image_base) as u32;
+    let located_entry = entries
+        .binary_search_by(|entry| {
+            if target_rva < entry.begin_address.get(Default::default()) {
+                // 目标 RVA 小于当前函数的起始 -> 当前条目偏大 -> 往左找
+                Ordering::Greater
+            } else if target_rva >= entry.end_address.get(Default::default()) {
+                // 目标 RVA 大于等于当前函数的结束 -> 当前条目偏小 -> 往右找
+                Ordering::Less
+            } else {
+                // begin <= target_rva < end -> 命中！
+                Ordering::Equal
+            }
+        })
+        .ok()
+        .context(FunctionNotLocatedSnafu {
+            addr,
+            method: source,
+            reason: "no entry covers it",
+        })?;
+    Ok(image_base + entries[located_entry].begin_address.get(Default::default()) as u64)
+}
+
+/// The content of the exception directory, i.e. `.pdata`.
+fn exception_directory<'data>(pe: &PeFile64<'data>) -> Result<&'data [u8]> {
+    let dir = pe
+        .data_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION)
+        .context(SectionNotFoundSnafu {
+            name: "exception directory",
+        })?;
+    let section_table = pe.section_table();
+    Ok(dir.data(pe.data(), &section_table)?)
+}
+
+/// Find the begin address of the function containing `addr` through the ARM64 exception directory.
+///
+/// Unlike x86_64, an ARM64 `.pdata` entry has only the begin address and the unwind data,
+/// which either packs the function length, or points to the `.xdata` record that has it.
+fn arm64_pe_function_begin(pe: &PeFile64, addr: u64) -> Result<u64> {
+    let image_base = pe.relative_address_base();
+    let data = exception_directory(pe)?;
+    let entries = data
+        .chunks_exact(8)
+        .map(|x| {
+            (
+                u32::from_le_bytes(x[..4].try_into().unwrap()),
+                u32::from_le_bytes(x[4..].try_into().unwrap()),
+            )
+        })
+        .collect::<Vec<_>>();
+    let target_rva = (addr - image_base) as u32;
+    // entries are sorted by begin address, take the last one beginning before the target
+    let located = entries
+        .partition_point(|(begin, _)| *begin <= target_rva)
+        .checked_sub(1)
+        .map(|x| entries[x]);
+    let function_length = |unwind_data: u32| -> Option<u32> {
+        if unwind_data & 0x3 != 0 {
+            // packed unwind data: Flag(2) FunctionLength(11) ...
+            Some(((unwind_data >> 2) & 0x7FF) * 4)
+        } else {
+            // .xdata record: FunctionLength(18) ...
+            let xdata = pe.section_table().pe_data_at(pe.data(), unwind_data)?;
+            let header = u32::from_le_bytes(xdata.get(..4)?.try_into().unwrap());
+            Some((header & 0x3FFFF) * 4)
+        }
+    };
+    match located {
+        Some((begin, unwind_data))
+            if function_length(unwind_data).is_some_and(|len| target_rva - begin < len) =>
+        {
+            Ok(image_base + begin as u64)
+        }
+        _ => FunctionNotLocatedSnafu {
+            addr,
+            method: BoundsSource::ExceptionDirectory,
+            reason: "no entry covers it",
+        }
+        .fail(),
+    }
+}
+
+/// How far back [x86_function_begin] looks for a prologue.
+const MAX_FUNCTION_SIZE: usize = 0x10000;
+
+/// Guess the begin address of the function containing `addr`: the nearest frame-pointer prologue before it
+/// that follows padding (`int3`/`nop`) or the `ret` of the previous function.
+fn x86_function_begin(obj: &object::File, addr: u64) -> Result<u64> {
+    const PROLOGUES: [&[u8]; 3] = [
+        // mov edi, edi; push ebp; mov ebp, esp (hot-patchable)
+        &[0x8B, 0xFF, 0x55, 0x8B, 0xEC],
+        // push ebp; mov ebp, esp
+        &[0x55, 0x8B, 0xEC],
+        &[0x55, 0x89, 0xE5],
+    ];
+    let text = text_section(obj)?;
+    let text_data = text.data()?;
+    let offset = addr
+        .checked_sub(text.address())
+        .map(|x| x as usize)
+        .filter(|x| *x <= text_data.len())
+        .context(FunctionNotLocatedSnafu {
+            addr,
+            method: BoundsSource::Prologue,
+            reason: "out of .text section",
+        })?;
+    (1..=offset)
+        .rev()
+        .take(MAX_FUNCTION_SIZE)
+        .find(|&i| {
+            matches!(text_data[i - 1], 0xCC | 0x90 | 0xC3)
+                && PROLOGUES.iter().any(|x| text_data[i..].starts_with(x))
+        })
+        .map(|i| text.address() + i as u64)
+        .context(FunctionNotLocatedSnafu {
+            addr,
+            method: BoundsSource::Prologue,
+            reason: format!("no prologue in the {MAX_FUNCTION_SIZE:#X} bytes before it"),
+        })
+}
+
+pub(super) fn macho_text_segment_addr(macho: &MachOFile64) -> Result<u64> {
+    macho
+        .segments()
+        .find(|x| x.name().ok().flatten() == Some("__TEXT"))
+        .map(|x| x.address())
+        .context(SectionNotFoundSnafu { name: "__TEXT" })
+}
+
+/// Find the begin address of the function containing `addr`: the last one in `LC_FUNCTION_STARTS`
+/// not after it. Unlike `.pdata` or `.eh_frame`, only the starts are known, so the function is assumed
+/// to extend to the next start.
+fn macho_function_begin(macho: &MachOFile64, addr: u64) -> Result<u64> {
+    let text_segment_addr = macho_text_segment_addr(macho)?;
+    let mut commands = macho.macho_load_commands()?;
+    while let Some(command) = commands.next()? {
+        let LoadCommandVariant::LinkeditData(linkedit) = command.variant()? else {
+            continue;
+        };
+        if linkedit.cmd.get(macho.endian()) != object::macho::LC_FUNCTION_STARTS {
+            continue;
+        }
+        let mut begin = None;
+        let mut starts =
+            linkedit.function_starts(macho.endian(), macho.data(), text_segment_addr)?;
+        while let Some(start) = starts.next()? {
+            if start > addr {
+                break;
+            }
+            begin = Some(start);
+        }
+        return begin.context(FunctionNotLocatedSnafu {
+            addr,
+            method: BoundsSource::FunctionStarts,
+            reason: "no function starts before it",
+        });
+    }
+    SectionNotFoundSnafu {
+        name: "LC_FUNCTION_STARTS",
+    }
+    .fail()
+}
+
+/// Find the function symbol containing `addr`, from `.symtab` or else `.dynsym`.
+///
+/// `wrapper.node` is usually stripped and the function not exported, so this rarely succeeds.
+fn symbol_function_begin(obj: &object::File, addr: u64) -> Option<u64> {
+    obj.symbols()
+        .chain(obj.dynamic_symbols())
+        .filter(|x| x.kind() == object::SymbolKind::Text && x.size() > 0)
+        .find(|x| (x.address()..x.address() + x.size()).contains(&addr))
+        .map(|x| x.address())
+}
+
+/// Find the begin address of the function containing `addr` through the FDEs in `.eh_frame`.
+fn eh_frame_function_begin(obj: &object::File, addr: u64) -> Result<u64> {
+    let section = obj
+        .section_by_name(".eh_frame")
+        .context(SectionNotFoundSnafu { name: ".eh_frame" })?;
+    let mut eh_frame = gimli::EhFrame::new(section.data()?, gimli::LittleEndian);
+    eh_frame.set_address_size(if obj.is_64() { 8 } else { 4 });
+    let mut bases = gimli::BaseAddresses::default().set_eh_frame(section.address());
+    if let Some(text) = obj.section_by_name(".text") {
+        bases = bases.set_text(text.address());
+    }
+    if let Some(got) = obj.section_by_name(".got") {
+        bases = bases.set_got(got.address());
+    }
+    let mut entries = eh_frame.entries(&bases);
+    while let Some(entry) = entries.next()? {
+        let gimli::CieOrFde::Fde(partial) = entry else {
+            continue;
+        };
+        let fde = partial.parse(gimli::EhFrame::cie_from_offset)?;
+        if fde.contains(addr) {
+            return Ok(fde.initial_address());
+        }
+    }
+    FunctionNotLocatedSnafu {
+        addr,
+        method: BoundsSource::EhFrame,
+        reason: "no FDE covers it",
+    }
+    .fail()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::samples::*;
+    use super::*;
+
+    #[test]
+    fn elf_symbol() {
+        let (data, layout) = elf_x86_64(ElfBounds::Symbols);
+        let obj = object::File::parse(data.as_slice()).unwrap();
+        for addr in [
+            layout.function,
+            layout.reference,
+            layout.function + layout.function_len - 1,
+        ] {
+            assert_eq!(
+                function_begin(&obj, addr).unwrap(),
+                (layout.function, BoundsSource::Symbol)
+            );
+        }
+        assert_eq!(
+            function_begin(&obj, layout.text + 4).unwrap(),
+            (layout.text, BoundsSource::Symbol)
+        );
+        assert_eq!(
+            symbol_function_begin(&obj, layout.function + layout.function_len),
+            None
+        );
+    }
+
+    #[test]
+    fn elf_eh_frame() {
+        let (data, layout) = elf_x86_64(ElfBounds::EhFrame);
+        let obj = object::File::parse(data.as_slice()).unwrap();
+        assert_eq!(symbol_function_begin(&obj, layout.reference), None);
+        for addr in [
+            layout.function,
+            layout.reference,
+            layout.function + layout.function_len - 1,
+        ] {
+            assert_eq!(
+                function_begin(&obj, addr).unwrap(),
+                (layout.function, BoundsSource::EhFrame)
+            );
+        }
+        assert_eq!(
+            function_begin(&obj, layout.text).unwrap(),
+            (layout.text, BoundsSource::EhFrame)
+        );
+        assert!(matches!(
+            function_begin(&obj, layout.function + layout.function_len),
+            Err(Error::FunctionNotLocated {
+                method: Some(BoundsSource::EhFrame),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn elf_no_bounds() {
+        let (data, layout) = elf_x86_64(ElfBounds::None);
+        let obj = object::File::parse(data.as_slice()).unwrap();
+        assert!(matches!(
+            function_begin(&obj, layout.reference),
+            Err(Error::SectionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn pe64_pdata() {
+        let (data, layout) = pe_x86_64(true);
+        let obj = object::File::parse(data.as_slice()).unwrap();
+        for rva in [
+            layout.function,
+            layout.reference,
+            layout.function + layout.function_len - 1,
+        ] {
+            assert_eq!(
+                function_begin(&obj, PE64_IMAGE_BASE + rva).unwrap(),
+                (
+                    PE64_IMAGE_BASE + layout.function,
+                    BoundsSource::ExceptionDirectory
+                )
+            );
+        }
+        assert_eq!(
+            function_begin(&obj, PE64_IMAGE_BASE + layout.text + 4)
+                .unwrap()
+                .0,
+            PE64_IMAGE_BASE + layout.text
+        );
+        assert!(matches!(
+            function_begin(
+                &obj,
+                PE64_IMAGE_BASE + layout.function + layout.function_len
+            ),
+            Err(Error::FunctionNotLocated { .. })
+        ));
+
+        let (data, layout) = pe_x86_64(false);
+        let obj = object::File::parse(data.as_slice()).unwrap();
+        assert!(matches!(
+            function_begin(&obj, PE64_IMAGE_BASE + layout.reference),
+            Err(Error::SectionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn arm64_pdata() {
+        for unwind in [Arm64Unwind::Packed, Arm64Unwind::Xdata] {
+            let (data, layout) = pe_arm64(unwind);
+            let obj = object::File::parse(data.as_slice()).unwrap();
+            assert_eq!(obj.architecture(), Architecture::Aarch64);
+            for rva in [
+                layout.function,
+                layout.reference,
+                layout.function + layout.function_len - 4,
+            ] {
+                assert_eq!(
+                    function_begin(&obj, PE64_IMAGE_BASE + rva).unwrap(),
+                    (
+                        PE64_IMAGE_BASE + layout.function,
+                        BoundsSource::ExceptionDirectory
+                    ),
+                    "{unwind:?}"
+                );
+            }
+            assert_eq!(
+                function_begin(&obj, PE64_IMAGE_BASE + layout.text + 4)
+                    .unwrap()
+                    .0,
+                PE64_IMAGE_BASE + layout.text
+            );
+            // past the length of the last entry, and before the first one
+            for rva in [layout.function + layout.function_len, layout.text - 4] {
+                assert!(matches!(
+                    function_begin(&obj, PE64_IMAGE_BASE + rva),
+                    Err(Error::FunctionNotLocated { .. })
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn i386_prologue() {
+        let (data, layout) = pe_i386();
+        let obj = object::File::parse(data.as_slice()).unwrap();
+        let text = PE32_IMAGE_BASE + layout.text;
+        // the prologue in the middle doesn't follow padding or a ret
+        for rva in [
+            layout.function,
+            layout.reference,
+            layout.function + layout.function_len - 1,
+        ] {
+            assert_eq!(
+                function_begin(&obj, PE32_IMAGE_BASE + rva).unwrap(),
+                (PE32_IMAGE_BASE + layout.function, BoundsSource::Prologue)
+            );
+        }
+        // the first function follows nothing
+        assert!(matches!(
+            function_begin(&obj, text + 4),
+            Err(Error::FunctionNotLocated { .. })
+        ));
+        assert!(matches!(
+            function_begin(&obj, text - 1),
+            Err(Error::FunctionNotLocated { .. })
+        ));
+    }
+
+    #[test]
+    fn macho_function_starts() {
+        for arch in [MachoArch::X86_64, MachoArch::Arm64] {
+            let (data, layout) = macho(arch);
+            let obj = object::File::parse(data.as_slice()).unwrap();
+            let object::File::MachO64(macho) = &obj else {
+                panic!("{:?}", obj.format());
+            };
+            assert_eq!(macho_text_segment_addr(macho).unwrap(), MACHO_TEXT_SEGMENT);
+            for offset in [
+                layout.function,
+                layout.reference,
+                layout.function + layout.function_len - 1,
+            ] {
+                assert_eq!(
+                    function_begin(&obj, MACHO_TEXT_SEGMENT + offset).unwrap(),
+                    (
+                        MACHO_TEXT_SEGMENT + layout.function,
+                        BoundsSource::FunctionStarts
+                    )
+                );
+            }
+            assert_eq!(
+                function_begin(&obj, MACHO_TEXT_SEGMENT + layout.function - 1).unwrap(),
+                (
+                    MACHO_TEXT_SEGMENT + layout.text,
+                    BoundsSource::FunctionStarts
+                )
+            );
+            // the last function extends to wherever
+            assert_eq!(
+                function_begin(&obj, MACHO_TEXT_SEGMENT + 0x10000)
+                    .unwrap()
+                    .0,
+                MACHO_TEXT_SEGMENT + layout.function
+            );
+            assert!(matches!(
+                function_begin(&obj, MACHO_TEXT_SEGMENT + layout.text - 1),
+                Err(Error::FunctionNotLocated { .. })
+            ));
+        }
+    }
+}
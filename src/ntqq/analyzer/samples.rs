@@ -0,0 +1,556 @@
+//! Minimal `wrapper.node` look-alikes for the tests: a function referencing [super::DEFAULT_PATTERNS] next to a decoy function,
+//! in each format and architecture [super::Analyzer] supports, built with `object::write` or by hand.
+
+use object::write::{elf, pe};
+
+/// The referenced string, right at the start of the read-only data.
+pub const PATTERN: &[u8] = b"nt_sqlite3_key_v2: db=%p zDb=%s\0";
+/// Another string, which only the decoy function references.
+pub const OTHER: &[u8] = b"some other string\0";
+/// Where [OTHER] is, from the start of the read-only data.
+pub const OTHER_OFFSET: u64 = 0x40;
+
+/// Addresses in a sample, relative to the image base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub text: u64,
+    pub rodata: u64,
+    /// The function referencing [PATTERN].
+    pub function: u64,
+    pub function_len: u64,
+    /// The referencing instruction.
+    pub reference: u64,
+}
+
+/// The read-only data: [PATTERN], then [OTHER] at [OTHER_OFFSET].
+pub fn rodata() -> Vec<u8> {
+    let mut data = PATTERN.to_vec();
+    data.resize(OTHER_OFFSET as usize, 0);
+    data.extend_from_slice(OTHER);
+    data
+}
+
+/// x86_64 code at `text`: a decoy function, then the one loading the address of [PATTERN] with a RIP-relative LEA.
+pub fn x86_64_code(text: u64, rodata: u64) -> (Vec<u8>, Layout) {
+    let rip_relative =
+        |next_ip: u64, target: u64| ((target.wrapping_sub(next_ip)) as i32).to_le_bytes();
+    // push rbp; mov rbp, rsp; lea rax, [rip + OTHER]; pop rbp; ret
+    let mut code = vec![0x55, 0x48, 0x89, 0xE5, 0x48, 0x8D, 0x05];
+    code.extend(rip_relative(text + 11, rodata + OTHER_OFFSET));
+    code.extend([0x5D, 0xC3]);
+    code.resize(0x10, 0xCC);
+    // push rbp; mov rbp, rsp; lea rdx, [rip + PATTERN]; pop rbp; ret
+    code.extend([0x55, 0x48, 0x89, 0xE5, 0x48, 0x8D, 0x15]);
+    code.extend(rip_relative(text + 0x1B, rodata));
+    code.extend([0x5D, 0xC3]);
+    code.resize(0x20, 0xCC);
+    let layout = Layout {
+        text,
+        rodata,
+        function: text + 0x10,
+        function_len: 0x10,
+        reference: text + 0x14,
+    };
+    (code, layout)
+}
+
+/// ARM64 code at `text`: a decoy function, then the one loading the address of [PATTERN] with ADRP, then ADD
+/// two instructions later.
+pub fn aarch64_code(text: u64, rodata: u64) -> (Vec<u8>, Layout) {
+    const STP: u32 = 0xA9BF_7BFD; // stp x29, x30, [sp, #-16]!
+    const LDP: u32 = 0xA8C1_7BFD; // ldp x29, x30, [sp], #16
+    const RET: u32 = 0xD65F_03C0;
+    const NOP: u32 = 0xD503_201F;
+    let mut words = vec![STP];
+    words.extend(adrp_add(0, text + 4, rodata + OTHER_OFFSET, 0));
+    words.extend([LDP, RET]);
+    words.resize(8, NOP);
+    words.extend([STP]);
+    words.extend(adrp_add(1, text + 0x24, rodata, 1));
+    words.extend([LDP, RET]);
+    words.resize(16, NOP);
+    let layout = Layout {
+        text,
+        rodata,
+        function: text + 0x20,
+        function_len: 0x20,
+        reference: text + 0x24,
+    };
+    (
+        words.into_iter().flat_map(u32::to_le_bytes).collect(),
+        layout,
+    )
+}
+
+/// `adrp x<rd>, target; <nop>...; add x<rd>, x<rd>, :lo12:target`, with `gap` nops in between, at `pc`.
+pub fn adrp_add(rd: u32, pc: u64, target: u64, gap: usize) -> Vec<u32> {
+    let pages = ((target & !0xFFF).wrapping_sub(pc & !0xFFF) as i64 >> 12) as u32;
+    let adrp = 0x9000_0000 | (pages & 0x3) << 29 | ((pages >> 2) & 0x7FFFF) << 5 | rd;
+    let add = 0x9100_0000 | ((target & 0xFFF) as u32) << 10 | rd << 5 | rd;
+    let mut words = vec![adrp];
+    words.resize(1 + gap, 0xD503_201F);
+    words.push(add);
+    words
+}
+
+/// i386 code at `text` of an image loaded at `image_base`: a decoy function, then the one pushing the address of
+/// [PATTERN]. It begins with a hot-patchable prologue, and has the bytes of a prologue in the middle.
+pub fn i386_code(image_base: u64, text: u64, rodata: u64) -> (Vec<u8>, Layout) {
+    let absolute = |addr: u64| ((image_base + addr) as u32).to_le_bytes();
+    // push ebp; mov ebp, esp; mov eax, OTHER; pop ebp; ret
+    let mut code = vec![0x55, 0x8B, 0xEC, 0xB8];
+    code.extend(absolute(rodata + OTHER_OFFSET));
+    code.extend([0x5D, 0xC3]);
+    code.resize(0x10, 0xCC);
+    // mov edi, edi; push ebp; mov ebp, esp; push eax; push ebp; mov ebp, esp; push PATTERN; add esp, 8; pop ebp; ret
+    code.extend([0x8B, 0xFF, 0x55, 0x8B, 0xEC, 0x50, 0x55, 0x8B, 0xEC, 0x68]);
+    code.extend(absolute(rodata));
+    code.extend([0x83, 0xC4, 0x08, 0x5D, 0xC3]);
+    code.resize(0x30, 0xCC);
+    let layout = Layout {
+        text,
+        rodata,
+        function: text + 0x10,
+        function_len: 0x20,
+        reference: text + 0x19,
+    };
+    (code, layout)
+}
+
+/// What an ELF sample records about the function bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfBounds {
+    /// A `.symtab` entry for each function.
+    Symbols,
+    /// An `.eh_frame` FDE for each function.
+    EhFrame,
+    /// Neither.
+    None,
+}
+
+const ELF_TEXT: u64 = 0x1000;
+const ELF_RODATA: u64 = 0x2000;
+const ELF_EH_FRAME: u64 = 0x3000;
+
+/// An x86_64 ELF shared object, loaded at 0 with file offsets equal to addresses.
+pub fn elf_x86_64(bounds: ElfBounds) -> (Vec<u8>, Layout) {
+    let (text, layout) = x86_64_code(ELF_TEXT, ELF_RODATA);
+    let rodata = rodata();
+    let functions = [(ELF_TEXT, 0x10), (layout.function, layout.function_len)];
+    let eh_frame = eh_frame(ELF_EH_FRAME, &functions);
+
+    let mut data = Vec::new();
+    let mut w = elf::Writer::new(object::Endianness::Little, true, &mut data);
+    w.reserve_file_header();
+    w.reserve_program_headers(1);
+
+    w.reserve_null_section_index();
+    let text_name = w.add_section_name(b".text");
+    let text_index = w.reserve_section_index();
+    let rodata_name = w.add_section_name(b".rodata");
+    w.reserve_section_index();
+    let eh_frame_name = (bounds == ElfBounds::EhFrame).then(|| {
+        let name = w.add_section_name(b".eh_frame");
+        w.reserve_section_index();
+        name
+    });
+    let mut symbol_names = Vec::new();
+    if bounds == ElfBounds::Symbols {
+        w.reserve_null_symbol_index();
+        for name in [b"decoy".as_slice(), b"target"] {
+            symbol_names.push(w.add_string(name));
+            w.reserve_symbol_index(Some(text_index));
+        }
+        w.reserve_symtab_section_index();
+        w.reserve_strtab_section_index();
+    }
+    w.reserve_shstrtab_section_index();
+
+    w.reserve_until(ELF_TEXT as usize);
+    w.reserve(text.len(), 1);
+    w.reserve_until(ELF_RODATA as usize);
+    w.reserve(rodata.len(), 1);
+    if eh_frame_name.is_some() {
+        w.reserve_until(ELF_EH_FRAME as usize);
+        w.reserve(eh_frame.len(), 1);
+    }
+    let loaded_len = w.reserved_len() as u64;
+    if bounds == ElfBounds::Symbols {
+        w.reserve_symtab();
+        w.reserve_strtab();
+    }
+    w.reserve_shstrtab();
+    w.reserve_section_headers();
+
+    w.write_file_header(&elf::FileHeader {
+        os_abi: object::elf::ELFOSABI_NONE,
+        abi_version: 0,
+        e_type: object::elf::ET_DYN,
+        e_machine: object::elf::EM_X86_64,
+        e_entry: 0,
+        e_flags: 0,
+    })
+    .unwrap();
+    w.write_align_program_headers();
+    w.write_program_header(&elf::ProgramHeader {
+        p_type: object::elf::PT_LOAD,
+        p_flags: object::elf::PF_R | object::elf::PF_X,
+        p_offset: 0,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: loaded_len,
+        p_memsz: loaded_len,
+        p_align: 0x1000,
+    });
+    w.pad_until(ELF_TEXT as usize);
+    w.write(&text);
+    w.pad_until(ELF_RODATA as usize);
+    w.write(&rodata);
+    if eh_frame_name.is_some() {
+        w.pad_until(ELF_EH_FRAME as usize);
+        w.write(&eh_frame);
+    }
+    if bounds == ElfBounds::Symbols {
+        w.write_null_symbol();
+        for (name, (addr, len)) in symbol_names.into_iter().zip(functions) {
+            w.write_symbol(&elf::Sym {
+                name: Some(name),
+                section: Some(text_index),
+                st_info: (object::elf::STB_GLOBAL << 4) | object::elf::STT_FUNC,
+                st_other: 0,
+                st_shndx: 0,
+                st_value: addr,
+                st_size: len,
+            });
+        }
+        w.write_strtab();
+    }
+    w.write_shstrtab();
+
+    w.write_null_section_header();
+    let section = |name, sh_type, sh_flags, addr: u64, len: usize| elf::SectionHeader {
+        name: Some(name),
+        sh_type,
+        sh_flags,
+        sh_addr: addr,
+        sh_offset: addr,
+        sh_size: len as u64,
+        sh_link: 0,
+        sh_info: 0,
+        sh_addralign: 1,
+        sh_entsize: 0,
+    };
+    let alloc = object::elf::SHF_ALLOC as u64;
+    w.write_section_header(&section(
+        text_name,
+        object::elf::SHT_PROGBITS,
+        alloc | object::elf::SHF_EXECINSTR as u64,
+        ELF_TEXT,
+        text.len(),
+    ));
+    w.write_section_header(&section(
+        rodata_name,
+        object::elf::SHT_PROGBITS,
+        alloc,
+        ELF_RODATA,
+        rodata.len(),
+    ));
+    if let Some(name) = eh_frame_name {
+        w.write_section_header(&section(
+            name,
+            object::elf::SHT_PROGBITS,
+            alloc,
+            ELF_EH_FRAME,
+            eh_frame.len(),
+        ));
+    }
+    if bounds == ElfBounds::Symbols {
+        w.write_symtab_section_header(1);
+        w.write_strtab_section_header();
+    }
+    w.write_shstrtab_section_header();
+    (data, layout)
+}
+
+/// An `.eh_frame` at `addr` with one CIE and an FDE for each of `functions`, `(begin, len)`.
+fn eh_frame(addr: u64, functions: &[(u64, u64)]) -> Vec<u8> {
+    // length, CIE id, version 1, augmentation "zR", code alignment 1, data alignment -8, return address register 16,
+    // augmentation data: FDE pointers are pc-relative sdata4,
+    // then def_cfa rsp+8, offset of rip cfa-8, and padding
+    let mut data = vec![
+        20, 0, 0, 0, 0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 16, 1, 0x1B, 0x0C, 0x07, 0x08, 0x90,
+        0x01, 0, 0,
+    ];
+    for &(begin, len) in functions {
+        let fde = data.len() as u64;
+        data.extend(16u32.to_le_bytes());
+        // offset back to the CIE, from this field
+        data.extend((fde as u32 + 4).to_le_bytes());
+        let pc_begin = begin.wrapping_sub(addr + fde + 8) as i32;
+        data.extend(pc_begin.to_le_bytes());
+        data.extend((len as u32).to_le_bytes());
+        // no augmentation data, padding
+        data.extend([0, 0, 0, 0]);
+    }
+    // terminator
+    data.extend([0, 0, 0, 0]);
+    data
+}
+
+pub const PE_TEXT: u64 = 0x1000;
+pub const PE_RDATA: u64 = 0x2000;
+pub const PE_PDATA: u64 = 0x3000;
+pub const PE_XDATA: u64 = 0x4000;
+
+/// A PE image with `.text`, `.rdata` at [PE_RDATA], and the given `.pdata` and `.xdata`.
+fn pe(machine: u16, image_base: u64, text: &[u8], pdata: &[u8], xdata: &[u8]) -> Vec<u8> {
+    let is_64 = machine != object::pe::IMAGE_FILE_MACHINE_I386;
+    let rodata = rodata();
+    let section_num = 2 + !pdata.is_empty() as u16 + !xdata.is_empty() as u16;
+    let mut data = Vec::new();
+    let mut w = pe::Writer::new(is_64, 0x1000, 0x200, &mut data);
+    w.reserve_dos_header_and_stub();
+    w.reserve_nt_headers(object::pe::IMAGE_NUMBEROF_DIRECTORY_ENTRIES);
+    w.reserve_section_headers(section_num);
+    let text_range = w.reserve_text_section(text.len() as u32);
+    let rdata_range = w.reserve_rdata_section(rodata.len() as u32);
+    let pdata_range = (!pdata.is_empty()).then(|| w.reserve_pdata_section(pdata.len() as u32));
+    let xdata_range = (!xdata.is_empty()).then(|| w.reserve_xdata_section(xdata.len() as u32));
+    assert_eq!(text_range.virtual_address as u64, PE_TEXT);
+    assert_eq!(rdata_range.virtual_address as u64, PE_RDATA);
+    assert!(pdata_range.is_none_or(|x| x.virtual_address as u64 == PE_PDATA));
+    assert!(xdata_range.is_none_or(|x| x.virtual_address as u64 == PE_XDATA));
+
+    w.write_dos_header_and_stub().unwrap();
+    w.write_nt_headers(pe::NtHeaders {
+        machine,
+        time_date_stamp: 0,
+        characteristics: object::pe::IMAGE_FILE_EXECUTABLE_IMAGE | object::pe::IMAGE_FILE_DLL,
+        major_linker_version: 14,
+        minor_linker_version: 0,
+        address_of_entry_point: 0,
+        image_base,
+        major_operating_system_version: 6,
+        minor_operating_system_version: 0,
+        major_image_version: 0,
+        minor_image_version: 0,
+        major_subsystem_version: 6,
+        minor_subsystem_version: 0,
+        subsystem: object::pe::IMAGE_SUBSYSTEM_WINDOWS_CUI,
+        dll_characteristics: 0,
+        size_of_stack_reserve: 0x100000,
+        size_of_stack_commit: 0x1000,
+        size_of_heap_reserve: 0x100000,
+        size_of_heap_commit: 0x1000,
+    });
+    w.write_section_headers();
+    w.write_section(text_range.file_offset, text);
+    w.write_section(rdata_range.file_offset, &rodata);
+    if let Some(range) = pdata_range {
+        w.write_section(range.file_offset, pdata);
+    }
+    if let Some(range) = xdata_range {
+        w.write_section(range.file_offset, xdata);
+    }
+    data
+}
+
+pub const PE64_IMAGE_BASE: u64 = 0x1_8000_0000;
+
+/// An x86_64 PE DLL, with a `.pdata` entry for each function if `pdata`.
+pub fn pe_x86_64(pdata: bool) -> (Vec<u8>, Layout) {
+    let (text, layout) = x86_64_code(PE_TEXT, PE_RDATA);
+    let entries = if pdata {
+        // begin, end, unwind info
+        [
+            [PE_TEXT, PE_TEXT + 0x10, 0],
+            [layout.function, layout.function + layout.function_len, 0],
+        ]
+        .iter()
+        .flatten()
+        .flat_map(|&x| (x as u32).to_le_bytes())
+        .collect()
+    } else {
+        Vec::new()
+    };
+    let data = pe(
+        object::pe::IMAGE_FILE_MACHINE_AMD64,
+        PE64_IMAGE_BASE,
+        &text,
+        &entries,
+        &[],
+    );
+    (data, layout)
+}
+
+/// How the `.pdata` entry of the referencing function in [pe_arm64] records its length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arm64Unwind {
+    /// Packed in the entry.
+    Packed,
+    /// In the `.xdata` record the entry points to.
+    Xdata,
+}
+
+/// An ARM64 PE DLL.
+pub fn pe_arm64(unwind: Arm64Unwind) -> (Vec<u8>, Layout) {
+    let (text, layout) = aarch64_code(PE_TEXT, PE_RDATA);
+    // Flag 1 (packed), FunctionLength in words
+    let packed = |len: u64| 1 | (len as u32 / 4) << 2;
+    let (target_unwind, xdata) = match unwind {
+        Arm64Unwind::Packed => (packed(layout.function_len), Vec::new()),
+        Arm64Unwind::Xdata => {
+            // FunctionLength in words, E, and one code word: save_fplr_x, end
+            let header = (layout.function_len as u32 / 4) | 1 << 21 | 1 << 27;
+            let xdata = [header, 0xE4E4_E481].into_iter().flat_map(u32::to_le_bytes);
+            (PE_XDATA as u32, xdata.collect())
+        }
+    };
+    let pdata = [
+        [PE_TEXT as u32, packed(0x20)],
+        [layout.function as u32, target_unwind],
+    ]
+    .iter()
+    .flatten()
+    .flat_map(|x| x.to_le_bytes())
+    .collect::<Vec<_>>();
+    let data = pe(
+        object::pe::IMAGE_FILE_MACHINE_ARM64,
+        PE64_IMAGE_BASE,
+        &text,
+        &pdata,
+        &xdata,
+    );
+    (data, layout)
+}
+
+pub const PE32_IMAGE_BASE: u64 = 0x1000_0000;
+
+/// An i386 PE DLL, which has no `.pdata`.
+pub fn pe_i386() -> (Vec<u8>, Layout) {
+    let (text, layout) = i386_code(PE32_IMAGE_BASE, PE_TEXT, PE_RDATA);
+    let data = pe(
+        object::pe::IMAGE_FILE_MACHINE_I386,
+        PE32_IMAGE_BASE,
+        &text,
+        &[],
+        &[],
+    );
+    (data, layout)
+}
+
+/// Address of the `__TEXT` segment of [macho], which maps the file from its start.
+pub const MACHO_TEXT_SEGMENT: u64 = 0x10000;
+
+/// The architectures there are code samples for in a Mach-O, see [macho].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachoArch {
+    X86_64,
+    Arm64,
+}
+
+/// A Mach-O 64-bit dylib of `arch`, with `__text` at 0x1000, `__cstring` at 0x2000
+/// and the `LC_FUNCTION_STARTS` of both functions, all relative to the `__TEXT` segment.
+///
+/// [object::write] only writes object files, so this is written by hand.
+pub fn macho(arch: MachoArch) -> (Vec<u8>, Layout) {
+    use object::macho::*;
+    const TEXT: u64 = 0x1000;
+    const CSTRING: u64 = 0x2000;
+    const FUNCTION_STARTS: u64 = 0x3000;
+    let ((text, layout), cputype, cpusubtype) = match arch {
+        MachoArch::X86_64 => (
+            x86_64_code(TEXT, CSTRING),
+            CPU_TYPE_X86_64,
+            CPU_SUBTYPE_X86_64_ALL,
+        ),
+        MachoArch::Arm64 => (
+            aarch64_code(TEXT, CSTRING),
+            CPU_TYPE_ARM64,
+            CPU_SUBTYPE_ARM64_ALL,
+        ),
+    };
+    let cstring = rodata();
+    // ULEB128 deltas from the __TEXT segment, then from the previous start, ended by 0
+    let mut function_starts = vec![0x80, 0x20, (layout.function - TEXT) as u8, 0];
+    function_starts.resize(8, 0);
+
+    let mut data = Vec::new();
+    let u32s = |data: &mut Vec<u8>, x: &[u32]| data.extend(x.iter().flat_map(|x| x.to_le_bytes()));
+    let u64s = |data: &mut Vec<u8>, x: &[u64]| data.extend(x.iter().flat_map(|x| x.to_le_bytes()));
+    let name = |data: &mut Vec<u8>, x: &[u8]| {
+        let mut name = [0; 16];
+        name[..x.len()].copy_from_slice(x);
+        data.extend(name)
+    };
+    let segment_size = 72 + 80 * 2;
+    let function_starts_size = 16;
+    // header
+    u32s(&mut data, &[MH_MAGIC_64, cputype, cpusubtype, MH_DYLIB, 2]);
+    u32s(&mut data, &[segment_size + function_starts_size, 0, 0]);
+    // __TEXT segment, mapping the file up to the function starts
+    u32s(&mut data, &[LC_SEGMENT_64, segment_size]);
+    name(&mut data, b"__TEXT");
+    u64s(
+        &mut data,
+        &[MACHO_TEXT_SEGMENT, FUNCTION_STARTS, 0, FUNCTION_STARTS],
+    );
+    let prot = VM_PROT_READ | VM_PROT_EXECUTE;
+    u32s(&mut data, &[prot, prot, 2, 0]);
+    let section = |data: &mut Vec<u8>, sectname: &[u8], addr: u64, size: usize, flags| {
+        name(data, sectname);
+        name(data, b"__TEXT");
+        u64s(data, &[MACHO_TEXT_SEGMENT + addr, size as u64]);
+        u32s(data, &[addr as u32, 4, 0, 0, flags, 0, 0, 0]);
+    };
+    let instructions = S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS;
+    section(&mut data, b"__text", TEXT, text.len(), instructions);
+    section(
+        &mut data,
+        b"__cstring",
+        CSTRING,
+        cstring.len(),
+        S_CSTRING_LITERALS,
+    );
+    u32s(&mut data, &[LC_FUNCTION_STARTS, function_starts_size]);
+    u32s(
+        &mut data,
+        &[FUNCTION_STARTS as u32, function_starts.len() as u32],
+    );
+
+    data.resize(TEXT as usize, 0);
+    data.extend(&text);
+    data.resize(CSTRING as usize, 0);
+    data.extend(&cstring);
+    data.resize(FUNCTION_STARTS as usize, 0);
+    data.extend(&function_starts);
+    (data, layout)
+}
+
+/// A Mach-O universal binary of `slices`, each aligned to 16 KiB.
+///
+/// Returns the offset of each slice along with it.
+pub fn universal(slices: &[&[u8]]) -> (Vec<u8>, Vec<u64>) {
+    const ALIGN: u32 = 14;
+    let mut data = Vec::new();
+    let u32s = |data: &mut Vec<u8>, x: &[u32]| data.extend(x.iter().flat_map(|x| x.to_be_bytes()));
+    u32s(&mut data, &[object::macho::FAT_MAGIC, slices.len() as u32]);
+    let mut offsets = Vec::new();
+    let mut offset = 1 << ALIGN;
+    for slice in slices {
+        // of the little-endian header of the slice
+        let field = |i: usize| u32::from_le_bytes(slice[i * 4..i * 4 + 4].try_into().unwrap());
+        let (cputype, cpusubtype) = (field(1), field(2));
+        u32s(
+            &mut data,
+            &[cputype, cpusubtype, offset, slice.len() as u32, ALIGN],
+        );
+        offsets.push(offset as u64);
+        offset = (offset + slice.len() as u32).next_multiple_of(1 << ALIGN);
+    }
+    for (slice, offset) in slices.iter().zip(&offsets) {
+        data.resize(*offset as usize, 0);
+        data.extend(*slice);
+    }
+    (data, offsets)
+}
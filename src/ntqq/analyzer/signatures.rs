@@ -0,0 +1,276 @@
+//! A persistent database of analyzed `wrapper.node` builds, so known builds are not analyzed again.
+//!
+//! Entries are keyed by the SHA-256 of `wrapper.node` and the architecture, as a universal binary has a function
+//! in each slice. The QQ version is kept for reference only.
+//! The database is a JSON file, which can be exported and imported to share offsets between machines,
+//! see [SignatureDb::merge].
+
+use super::*;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Format version of the database file.
+const FORMAT_VERSION: u32 = 1;
+
+/// The offsets of one analyzed `wrapper.node` build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    /// SHA-256 of `wrapper.node`, in lowercase hex.
+    pub sha256: String,
+    pub qq_version: Option<String>,
+    pub architecture: String,
+    pub function_offset: u64,
+    pub lea_instr_offset: u64,
+}
+
+impl Signature {
+    /// The signature of the function chosen in `report`, if any.
+    pub fn from_report(report: &AnalysisReport, qq_version: Option<&str>) -> Option<Self> {
+        let func = report.chosen.as_ref()?;
+        Some(Self {
+            sha256: report.sha256.clone(),
+            qq_version: qq_version.map(str::to_owned),
+            architecture: report.architecture.clone(),
+            function_offset: func.function_offset,
+            lea_instr_offset: func.lea_instr_offset,
+        })
+    }
+
+    pub fn target_function(&self) -> TargetFunction {
+        TargetFunction {
+            function_offset: self.function_offset,
+            lea_instr_offset: self.lea_instr_offset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SignatureDb {
+    version: u32,
+    signatures: Vec<Signature>,
+}
+
+impl SignatureDb {
+    /// `<cache dir>/ntdb_unwrap/signatures.json`, e.g. `~/.cache` on Linux and `%LOCALAPPDATA%` on Windows.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::cache_dir().map(|x| x.join("ntdb_unwrap").join("signatures.json"))
+    }
+
+    /// Load the database at `path`. A missing file is an empty database.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = match fs::read(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).context(IoSnafu {
+                    op: format!("read signature database {}", path.display()),
+                });
+            }
+        };
+        let db: Self = serde_json::from_slice(&content).context(MalformedSignatureDbSnafu {
+            path: path.to_owned(),
+        })?;
+        ensure!(
+            db.version <= FORMAT_VERSION,
+            UnsupportedSignatureDbSnafu {
+                path: path.to_owned(),
+                version: db.version,
+            }
+        );
+        Ok(db)
+    }
+
+    /// Write the database to `path`, creating the parent directories if needed.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(IoSnafu {
+                op: format!("create directory {}", parent.display()),
+            })?;
+        }
+        let db = Self {
+            version: FORMAT_VERSION,
+            signatures: self.signatures.clone(),
+        };
+        let content = serde_json::to_vec_pretty(&db).expect("signature database is serializable");
+        // write to a temporary file first, so a crash never leaves a truncated database
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, content).context(IoSnafu {
+            op: format!("write signature database {}", tmp.display()),
+        })?;
+        fs::rename(&tmp, path).context(IoSnafu {
+            op: format!("replace signature database {}", path.display()),
+        })
+    }
+
+    pub fn signatures(&self) -> &[Signature] {
+        &self.signatures
+    }
+
+    /// The signature of the `architecture` slice of the build, named as in [AnalysisReport::architecture].
+    pub fn get(&self, sha256: &str, architecture: &str) -> Option<&Signature> {
+        self.signatures
+            .iter()
+            .find(|x| x.sha256.eq_ignore_ascii_case(sha256) && x.architecture == architecture)
+    }
+
+    /// Add `signature`, replacing the one with the same hash and architecture if any.
+    ///
+    /// Returns whether the database is changed.
+    pub fn insert(&mut self, mut signature: Signature) -> bool {
+        signature.sha256.make_ascii_lowercase();
+        match self
+            .signatures
+            .iter_mut()
+            .find(|x| x.sha256 == signature.sha256 && x.architecture == signature.architecture)
+        {
+            Some(x) if *x == signature => false,
+            Some(x) => {
+                *x = signature;
+                true
+            }
+            None => {
+                self.signatures.push(signature);
+                true
+            }
+        }
+    }
+
+    /// Import all the signatures of `other`, which take precedence over the existing ones.
+    ///
+    /// Returns how many signatures are added or changed.
+    pub fn merge(&mut self, other: SignatureDb) -> usize {
+        other
+            .signatures
+            .into_iter()
+            .filter(|x| self.insert(x.clone()))
+            .count()
+    }
+
+    /// Look up the build of `wrapper_node` (its content), or else analyze it with `analyzer` and record the result.
+    ///
+    /// The slice of a universal binary is the one `analyzer` picks, see [Analyzer::slice].
+    ///
+    /// Results of [Confidence::Low] are returned but not recorded, so the build is analyzed again next time.
+    pub fn lookup_or_analyze(
+        &mut self,
+        analyzer: &Analyzer,
+        wrapper_node: &[u8],
+        qq_version: Option<&str>,
+    ) -> Result<TargetFunction> {
+        let architecture = format!("{:?}", analyzer.architecture_of(wrapper_node)?);
+        if let Some(signature) = self.get(&sha256_hex(wrapper_node), &architecture) {
+            log::info!(
+                "wrapper.node {} ({}) found in signature database",
+                signature.sha256,
+                signature.architecture
+            );
+            return Ok(signature.target_function());
+        }
+        let report = analyzer.analyze(wrapper_node)?;
+        let func = report.target_function()?;
+        if report.confidence != Confidence::Low
+            && let Some(signature) = Signature::from_report(&report, qq_version)
+        {
+            self.insert(signature);
+        }
+        Ok(func)
+    }
+}
+
+impl TargetFunction {
+    /// Like [TargetFunction::from_wrapper_node_file], but looks up the [SignatureDb] at [SignatureDb::default_path] first,
+    /// and records the result there.
+    ///
+    /// Failing to read or write the database is only logged, as it's just a cache.
+    pub fn from_wrapper_node_file_cached(
+        wrapper_node: &Path,
+        qq_version: Option<&str>,
+    ) -> crate::Result<TargetFunction> {
+        let db_path = SignatureDb::default_path();
+        let mut db = match db_path.as_deref().map(SignatureDb::load) {
+            Some(Ok(db)) => Some(db),
+            Some(Err(e)) => {
+                log::warn!("signature database not used: {}", e);
+                None
+            }
+            None => None,
+        };
+        let data = map_file(wrapper_node)?;
+        let Some(db) = &mut db else {
+            return Self::from_wrapper_node(&data);
+        };
+        let count = db.signatures.len();
+        let func = db.lookup_or_analyze(&Analyzer::new(), &data, qq_version)?;
+        if db.signatures.len() != count
+            && let Some(path) = &db_path
+            && let Err(e) = db.save(path)
+        {
+            log::warn!("signature database not saved: {}", e);
+        }
+        Ok(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::samples;
+    use super::*;
+
+    fn signature(sha256: &str, architecture: &str, function_offset: u64) -> Signature {
+        Signature {
+            sha256: sha256.to_owned(),
+            qq_version: None,
+            architecture: architecture.to_owned(),
+            function_offset,
+            lea_instr_offset: function_offset + 4,
+        }
+    }
+
+    #[test]
+    fn keyed_by_architecture() {
+        let sha256 = "ab".repeat(32);
+        let mut db = SignatureDb::default();
+        assert!(db.insert(signature(&sha256.to_uppercase(), "X86_64", 0x100)));
+        assert!(db.insert(signature(&sha256, "Aarch64", 0x200)));
+        assert!(!db.insert(signature(&sha256, "X86_64", 0x100)));
+        assert_eq!(db.signatures().len(), 2);
+        assert_eq!(db.get(&sha256, "X86_64").unwrap().function_offset, 0x100);
+        assert_eq!(
+            db.get(&sha256.to_uppercase(), "Aarch64")
+                .unwrap()
+                .function_offset,
+            0x200
+        );
+        assert!(db.get(&sha256, "I386").is_none());
+
+        let mut other = SignatureDb::default();
+        other.insert(signature(&sha256, "Aarch64", 0x300));
+        other.insert(signature(&"cd".repeat(32), "Aarch64", 0x300));
+        assert_eq!(db.merge(other), 2);
+        assert_eq!(db.signatures().len(), 3);
+        assert_eq!(db.get(&sha256, "Aarch64").unwrap().function_offset, 0x300);
+        assert_eq!(db.get(&sha256, "X86_64").unwrap().function_offset, 0x100);
+    }
+
+    #[test]
+    fn lookup_universal() {
+        let (x86_64, x86_64_layout) = samples::macho(samples::MachoArch::X86_64);
+        let (aarch64, aarch64_layout) = samples::macho(samples::MachoArch::Arm64);
+        let (data, _) = samples::universal(&[&x86_64, &aarch64]);
+        let mut db = SignatureDb::default();
+        for _ in 0..2 {
+            for (arch, layout) in [
+                (Architecture::X86_64, x86_64_layout),
+                (Architecture::Aarch64, aarch64_layout),
+            ] {
+                let analyzer = Analyzer::new().slice(arch);
+                let func = db.lookup_or_analyze(&analyzer, &data, None).unwrap();
+                assert_eq!(func.function_offset, layout.function);
+            }
+            assert_eq!(db.signatures().len(), 2);
+        }
+        let sha256 = sha256_hex(&data);
+        let signature = db.get(&sha256, "Aarch64").unwrap();
+        assert_eq!(signature.lea_instr_offset, aarch64_layout.reference);
+    }
+}
@@ -1,103 +1,271 @@
-use snafu::ResultExt;
+mod backup;
+pub use backup::*;
+
+use snafu::{OptionExt, ResultExt};
 
 use super::*;
+use crate::db::{HeaderError, NtDbHeader};
+use crate::sqlcipher::{self, KeyCheck};
 use crate::util::md5_hex;
 use std::{env, fs};
 
-/// Decode the header of the db file.
-/// Normally you should pass the first 1024 bytes of the db file.
+/// Files larger than this are not scanned for uids by [uid_candidates].
+const UID_FILE_MAX_SIZE: u64 = 1 << 20;
+/// How deep [uid_candidates] looks into a directory.
+const UID_SCAN_DEPTH: usize = 3;
+
+/// Why no `rand` could be read from a database header by [parse_rand].
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+pub enum RandError {
+    #[snafu(display("header too short, only {} bytes", len))]
+    HeaderTooShort { len: usize },
+    #[snafu(display("no `QQ_NT` tag in header, it's likely not an NTQQ database"))]
+    NoQqntTag,
+    #[snafu(display("no `DB` tag after `QQ_NT` in header"))]
+    NoDbTag,
+    #[snafu(display(
+        "no rand in header, i.e. no run of {} or more printable chars after the tag",
+        NtDbHeader::RAND_MIN_LEN
+    ))]
+    RandTooShort,
+}
+
+/// Read the `rand` string from the header of a database file.
+/// Normally you should pass the first 1024 bytes of the db file, see [NtDbHeader].
 ///
-/// Result is the `rand` value as explained [here](https://github.com/QQBackup/qq-win-db-key/blob/master/%E6%95%99%E7%A8%8B%20-%20NTQQ%20(Android).md#%E8%8E%B7%E5%8F%96%E5%AF%86%E9%92%A5:~:text=%E8%B7%9F%E9%9A%8F%E5%9C%A8QQ_NT%20DB%E5%90%8E%E7%9A%84%E5%8F%AF%E8%AF%BB%E5%AD%97%E7%AC%A6%E4%B8%B2%E5%A4%8D%E5%88%B6%EF%BC%8C%E5%BD%A2%E5%A6%826tPaJ9GP%EF%BC%8C%E8%AE%B0%E4%B8%BArand)
-pub fn decode_db_header(uid: &str, bytes: &[u8]) -> Option<super::DBDecryptInfo> {
-    let qq_uid_hash = md5_hex(uid);
-    enum State {
-        /// scan for 'QQNT' pattern
-        MatchingQqntPat,
-        /// scan for 'DB' pattern
-        MatchingDbPat,
-        /// scan for 8-byte or more ascii-printable bytes
-        ReadingRand,
-    }
-    let mut state = State::MatchingQqntPat;
-    let mut wnd = Vec::with_capacity(8);
-    macro_rules! switch_state {
-        ($new_state:expr) => {
-            state = $new_state;
-            wnd.clear();
-        };
-    }
-    for byte in bytes {
-        match state {
-            State::MatchingQqntPat => {
-                if wnd.len() == 4 {
-                    switch_state!(State::MatchingDbPat);
-                } else if b"QQNT"[wnd.len()] == *byte {
-                    wnd.push(*byte);
-                }
-            }
-            State::MatchingDbPat => {
-                if wnd.len() == 2 {
-                    switch_state!(State::ReadingRand);
-                } else if b"DB"[wnd.len()] == *byte {
-                    wnd.push(*byte);
-                } else {
-                    // clear the window, because here we want a continuous sequence of 'DB'
-                    wnd.clear();
-                }
-            }
-            State::ReadingRand => {
-                if byte.is_ascii_graphic() {
-                    wnd.push(*byte);
-                } else if wnd.len() >= 8 {
-                    let rand = String::from_utf8(wnd).unwrap();
-                    let key = md5_hex(qq_uid_hash + &rand);
-                    return Some(super::DBDecryptInfo {
-                        key,
-                        cipher_hmac_algorithm: None,
+/// `rand` is explained [here](https://github.com/QQBackup/qq-win-db-key/blob/master/%E6%95%99%E7%A8%8B%20-%20NTQQ%20(Android).md#%E8%8E%B7%E5%8F%96%E5%AF%86%E9%92%A5:~:text=%E8%B7%9F%E9%9A%8F%E5%9C%A8QQ_NT%20DB%E5%90%8E%E7%9A%84%E5%8F%AF%E8%AF%BB%E5%AD%97%E7%AC%A6%E4%B8%B2%E5%A4%8D%E5%88%B6%EF%BC%8C%E5%BD%A2%E5%A6%826tPaJ9GP%EF%BC%8C%E8%AE%B0%E4%B8%BArand)
+///
+/// ```
+/// use ntdb_unwrap::ntqq::android::{RandError, parse_rand};
+///
+/// let mut header = [0u8; 1024];
+/// header[32..40].copy_from_slice(b"QQ_NT DB");
+/// header[41..49].copy_from_slice(b"6tPaJ9GP");
+/// assert_eq!(parse_rand(&header), Ok("6tPaJ9GP"));
+///
+/// assert_eq!(parse_rand(&header[..36]), Err(RandError::HeaderTooShort { len: 36 }));
+/// header[41..49].copy_from_slice(b"6tPa\0\0\0\0");
+/// assert_eq!(parse_rand(&header), Err(RandError::RandTooShort));
+/// header[37..40].copy_from_slice(b"\0\0\0");
+/// assert_eq!(parse_rand(&header), Err(RandError::NoDbTag));
+/// assert_eq!(parse_rand(&[0u8; 1024]), Err(RandError::NoQqntTag));
+/// ```
+pub fn parse_rand(header: &[u8]) -> Result<&str, RandError> {
+    let header = NtDbHeader::parse(header).map_err(|e| match e {
+        HeaderError::TooShort => RandError::HeaderTooShort { len: header.len() },
+        // the tag is `QQ_NT` and `DB` separated by a space
+        HeaderError::NoTag if header[NtDbHeader::TAG_RANGE].starts_with(b"QQ_NT") => {
+            RandError::NoDbTag
+        }
+        HeaderError::NoTag => RandError::NoQqntTag,
+    })?;
+    let rand = header.rand().context(RandTooShortSnafu)?;
+    Ok(std::str::from_utf8(rand).expect("rand is printable ASCII"))
+}
+
+/// Derive the database key of the account `uid` from the `rand` of its database,
+/// which is `md5(md5(uid) + rand)` in lowercase hex.
+///
+/// ```
+/// use ntdb_unwrap::ntqq::android::derive_key;
+///
+/// assert_eq!(
+///     derive_key("u_abcdefghijklmnopqrstuv", "6tPaJ9GP"),
+///     "985887e714e35fcb5da6ff52a3af8029"
+/// );
+/// ```
+pub fn derive_key(uid: &str, rand: &str) -> String {
+    md5_hex(md5_hex(uid) + rand)
+}
+
+/// Decode the header of the db file and derive the key from its `rand` and the `uid`,
+/// i.e. [parse_rand] then [derive_key].
+pub fn decode_db_header(uid: &str, bytes: &[u8]) -> Result<super::DBDecryptInfo, RandError> {
+    Ok(super::DBDecryptInfo {
+        key: derive_key(uid, parse_rand(bytes)?),
+        cipher_hmac_algorithm: None,
+        kdf_iter: None,
+    })
+}
+
+/// Package names of the QQ variants [detect_db_file] looks for: QQ, TIM and QQ Lite.
+pub const DEFAULT_PACKAGES: &[&str] = &[
+    "com.tencent.mobileqq",
+    "com.tencent.tim",
+    "com.tencent.qqlite",
+];
+
+/// `$ANDROID_DATA`, or `/data` if it's not set.
+pub fn data_dir() -> PathBuf {
+    env::var_os("ANDROID_DATA").map_or_else(|| "/data".into(), PathBuf::from)
+}
+
+pub fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
+    detect_db_file_for(&data_dir(), DEFAULT_PACKAGES)
+}
+
+/// Find the databases of `packages` in every user profile under `data_dir`,
+/// i.e. `<data_dir>/user/<profile>/<package>`, which covers secondary users, work profiles and dual apps.
+///
+/// Profiles without any of the packages, or that can't be read, are skipped.
+pub fn detect_db_file_for(
+    data_dir: &Path,
+    packages: &[impl AsRef<str>],
+) -> crate::Result<Vec<UserDBFile>> {
+    let user_dir = data_dir.join("user");
+    let mut profiles = fs::read_dir(&user_dir)
+        .context(IoOpSnafu {
+            op: "read android user directory",
+        })?
+        .filter_map(|x| x.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .collect::<Vec<_>>();
+    profiles.sort_unstable();
+
+    let mut files = Vec::new();
+    for profile in profiles {
+        for package in packages {
+            let package = package.as_ref();
+            let app_dir = user_dir.join(profile.to_string()).join(package);
+            let Ok(uids) = fs::read_dir(app_dir.join("files/uid")) else {
+                continue;
+            };
+            for entry in uids.filter_map(|x| x.ok()) {
+                let file_name = entry.file_name();
+                if let Some((uin, uid)) = file_name.to_string_lossy().split_once("###") {
+                    files.push(UserDBFile {
+                        path: app_dir
+                            .join("databases/nt_db")
+                            .join(format!("nt_qq_{}", account_dir_hash(uid)))
+                            .join("nt_msg.db"),
+                        uid: Some(uid.to_string()),
+                        uin: uin.parse().ok(),
+                        profile: Some(profile),
+                        package: Some(package.to_owned()),
                     });
-                } else {
-                    wnd.clear();
                 }
             }
         }
     }
-    None
+    Ok(files)
 }
 
-pub fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
-    let data_dir = env::var("ANDROID_DATA").unwrap_or("/data".into());
-    let uid_dir = format!("{}/user/0/com.tencent.mobileqq/files/uid", data_dir);
-    let uids = fs::read_dir(uid_dir).context(IoOpSnafu {
-        op: "read android qq uid directory",
-    })?;
-    let mut files = Vec::with_capacity(uids.size_hint().0);
-    for entry in uids {
-        let entry = entry.context(IoOpSnafu {
-            op: "read android qq uid directory entry",
+/// Collect candidate uids from `path`, along with the uin if known, for [resolve_uid]:
+/// - a directory, e.g. `files/uid` of QQ's app data: uids in the names of the files under it,
+///   which are like `<uin>###<uid>`, and in the content of the small files.
+/// - a file: uids in its content, e.g. a list of uids, or a plaintext file of QQ that mentions them.
+pub fn uid_candidates(path: &Path) -> crate::Result<BTreeMap<String, Option<u64>>> {
+    let mut uids = BTreeMap::new();
+    if path.is_dir() {
+        collect_uids(path, UID_SCAN_DEPTH, &mut uids);
+        collect_uids_in_files(path, UID_SCAN_DEPTH, &mut uids);
+    } else {
+        let content = fs::read(path).context(IoOpSnafu {
+            op: format!("read uid candidates file {}", path.display()),
         })?;
-        let file_name = entry.file_name();
-        if let Some((uin, uid)) = file_name.to_string_lossy().split_once("###") {
-            let uin = uin.parse().unwrap_or_default();
-            let qq_uid_hash = md5_hex(uid);
-            let qq_path_hash = md5_hex(qq_uid_hash + "nt_kernel");
-            files.push(UserDBFile {
-                path: format!(
-                    "/data/user/0/com.tencent.mobileqq/databases/nt_db/nt_qq_{}/nt_msg.db",
-                    qq_path_hash
-                )
-                .into(),
-                uid: Some(uid.to_string()),
-                uin: Some(uin),
-            });
+        add_uids(&String::from_utf8_lossy(&content), &mut uids);
+    }
+    Ok(uids)
+}
+
+fn collect_uids_in_files(dir: &Path, depth: usize, uids: &mut BTreeMap<String, Option<u64>>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() && depth > 1 {
+            collect_uids_in_files(&entry.path(), depth - 1, uids);
+        } else if file_type.is_file()
+            && entry.metadata().is_ok_and(|x| x.len() <= UID_FILE_MAX_SIZE)
+            && let Ok(content) = fs::read(entry.path())
+        {
+            add_uids(&String::from_utf8_lossy(&content), uids);
         }
     }
-    Ok(files)
+}
+
+/// How [resolve_uid] matched the uid to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UidMatch {
+    /// The [account_dir_hash] of the uid is the name of the directory the database is in.
+    DirHash,
+    /// The key derived from the uid decrypts the database.
+    Key,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolvedUid {
+    pub uid: String,
+    pub uin: Option<u64>,
+    pub matched_by: UidMatch,
+}
+
+/// Find the uid of the account the database at `db_path` belongs to, among `candidates`.
+///
+/// If the database is still in its account directory `nt_qq_<hash>` (or `db_path` is the directory),
+/// the candidate whose [account_dir_hash] is `<hash>` is picked.
+/// Otherwise, e.g. for a renamed copy, the key each candidate derives is checked against the database,
+/// see [crate::sqlcipher::verify_key].
+pub fn resolve_uid(
+    db_path: &Path,
+    candidates: &BTreeMap<String, Option<u64>>,
+) -> crate::Result<Option<ResolvedUid>> {
+    if let Some(hash) = account_dir_hash_of(db_path)
+        && let Some((uid, uin)) = candidates
+            .iter()
+            .find(|(uid, _)| account_dir_hash(uid) == hash)
+    {
+        return Ok(Some(ResolvedUid {
+            uid: uid.clone(),
+            uin: *uin,
+            matched_by: UidMatch::DirHash,
+        }));
+    }
+
+    let db_file = if db_path.is_dir() {
+        db_path.join("nt_msg.db")
+    } else {
+        db_path.to_owned()
+    };
+    let mut f = fs::File::open(&db_file).context(IoOpSnafu {
+        op: format!("open {}", db_file.display()),
+    })?;
+    let mut page1 = vec![0u8; sqlcipher::NTQQ_HEADER_SIZE + sqlcipher::PAGE_SIZE];
+    let n = sqlcipher::read_full(&mut f, &mut page1).context(IoOpSnafu {
+        op: format!("read page 1 of {}", db_file.display()),
+    })?;
+    page1.truncate(n);
+    let Ok(rand) = parse_rand(&page1) else {
+        // no rand in the header, no uid would work
+        return Ok(None);
+    };
+    for (uid, uin) in candidates {
+        let decrypt_info = super::DBDecryptInfo {
+            key: derive_key(uid, rand),
+            cipher_hmac_algorithm: None,
+            kdf_iter: None,
+        };
+        if let KeyCheck::Match(_) = sqlcipher::verify_key(&page1, &decrypt_info)? {
+            return Ok(Some(ResolvedUid {
+                uid: uid.clone(),
+                uin: *uin,
+                matched_by: UidMatch::Key,
+            }));
+        }
+    }
+    Ok(None)
 }
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("IO operation to {}: {}", op, source))]
     IoOp { source: std::io::Error, op: String },
+    #[snafu(display("unsupported backup: {}", reason))]
+    UnsupportedBackup { reason: String },
+    #[snafu(transparent)]
+    Rand { source: RandError },
 }
 
 impl From<Error> for crate::Error {
@@ -105,3 +273,165 @@ impl From<Error> for crate::Error {
         super::Error::from(e).into()
     }
 }
+
+impl From<RandError> for crate::Error {
+    fn from(e: RandError) -> Self {
+        Error::from(e).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header with the tag and `rand` right after it, as NTQQ writes.
+    fn header(rand: &[u8]) -> [u8; NtDbHeader::LEN] {
+        let mut header = [0u8; NtDbHeader::LEN];
+        header[NtDbHeader::TAG_RANGE].copy_from_slice(NtDbHeader::TAG);
+        header[41..41 + rand.len()].copy_from_slice(rand);
+        header
+    }
+
+    #[test]
+    fn rand() {
+        assert_eq!(parse_rand(&header(b"6tPaJ9GP")), Ok("6tPaJ9GP"));
+        assert_eq!(
+            parse_rand(&header(b"6tPaJ9GP6tPaJ9GP")),
+            Ok("6tPaJ9GP6tPaJ9GP")
+        );
+        // shorter runs before it are skipped
+        assert_eq!(parse_rand(&header(b"abc\0\x016tPaJ9GP")), Ok("6tPaJ9GP"));
+        // only the tag is needed, not the whole header
+        assert_eq!(parse_rand(&header(b"6tPaJ9GP")[..50]), Ok("6tPaJ9GP"));
+    }
+
+    #[test]
+    fn rand_errors() {
+        assert_eq!(parse_rand(&[]), Err(RandError::HeaderTooShort { len: 0 }));
+        assert_eq!(
+            parse_rand(&header(b"6tPaJ9GP")[..NtDbHeader::TAG_RANGE.end - 1]),
+            Err(RandError::HeaderTooShort { len: 39 })
+        );
+        assert_eq!(parse_rand(&[0u8; 1024]), Err(RandError::NoQqntTag));
+        let mut no_db = header(b"6tPaJ9GP");
+        no_db[37..40].copy_from_slice(b"\0\0\0");
+        assert_eq!(parse_rand(&no_db), Err(RandError::NoDbTag));
+        assert_eq!(parse_rand(&header(b"")), Err(RandError::RandTooShort));
+        assert_eq!(
+            parse_rand(&header(b"6tPaJ9G")),
+            Err(RandError::RandTooShort)
+        );
+        // the rand must be terminated within the given bytes
+        assert_eq!(
+            parse_rand(&header(b"6tPaJ9GP")[..45]),
+            Err(RandError::RandTooShort)
+        );
+        // non-ASCII bytes end a run, so the rand is always ASCII
+        assert_eq!(
+            parse_rand(&header("6tPa你好J9GP".as_bytes())),
+            Err(RandError::RandTooShort)
+        );
+    }
+
+    #[test]
+    fn key() {
+        // python: md5((md5(uid).hexdigest() + rand).encode()).hexdigest()
+        assert_eq!(
+            md5_hex("u_abcdefghijklmnopqrstuv"),
+            "b6862e571d876b09559b13cffa7f1ba4"
+        );
+        assert_eq!(
+            derive_key("u_abcdefghijklmnopqrstuv", "6tPaJ9GP"),
+            "985887e714e35fcb5da6ff52a3af8029"
+        );
+        assert_eq!(
+            derive_key("u_fixturefixturefixture0", "fIxTuRe0"),
+            "4531fbcd7044187dab835324af5541ab"
+        );
+
+        let d = decode_db_header("u_abcdefghijklmnopqrstuv", &header(b"6tPaJ9GP")).unwrap();
+        assert_eq!(d.key, "985887e714e35fcb5da6ff52a3af8029");
+        assert_eq!(d.cipher_hmac_algorithm, None);
+        assert_eq!(
+            decode_db_header("u_abcdefghijklmnopqrstuv", &[0u8; 1024]).unwrap_err(),
+            RandError::NoQqntTag
+        );
+    }
+
+    const UID_A: &str = "u_aaaaaaaaaaaaaaaaaaaaaa";
+    const UID_B: &str = "u_bbbbbbbbbbbbbbbbbbbbbb";
+    const UID_C: &str = "u_cccccccccccccccccccccc";
+
+    #[test]
+    fn candidates_and_dir_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let uid_dir = dir.path().join("files/uid");
+        fs::create_dir_all(&uid_dir).unwrap();
+        fs::write(uid_dir.join(format!("10001###{UID_A}")), b"").unwrap();
+        fs::write(uid_dir.join(format!("10002###{UID_B}")), b"").unwrap();
+        fs::write(dir.path().join("files/uids.txt"), format!("{UID_C}\n")).unwrap();
+
+        let candidates = uid_candidates(dir.path()).unwrap();
+        assert_eq!(
+            candidates.into_iter().collect::<Vec<_>>(),
+            [
+                (UID_A.to_string(), Some(10001)),
+                (UID_B.to_string(), Some(10002)),
+                (UID_C.to_string(), None),
+            ]
+        );
+        let candidates = uid_candidates(&dir.path().join("files/uids.txt")).unwrap();
+        assert_eq!(
+            candidates.into_iter().collect::<Vec<_>>(),
+            [(UID_C.to_string(), None)]
+        );
+
+        // the database needn't exist when its directory tells the uid
+        let candidates = uid_candidates(dir.path()).unwrap();
+        let account_dir = dir
+            .path()
+            .join(format!("nt_db/nt_qq_{}", account_dir_hash(UID_B)));
+        for path in [account_dir.join("nt_msg.db"), account_dir.clone()] {
+            let resolved = resolve_uid(&path, &candidates).unwrap().unwrap();
+            assert_eq!(resolved.uid, UID_B);
+            assert_eq!(resolved.uin, Some(10002));
+            assert_eq!(resolved.matched_by, UidMatch::DirHash);
+        }
+    }
+
+    #[cfg(feature = "fixture")]
+    #[test]
+    fn resolve_by_key() {
+        use crate::db::fixture::NtDbFixture;
+
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = NtDbFixture::sample();
+        let uid = "u_fixturefixturefixture0";
+        // renamed, so the directory doesn't tell
+        let path = dir.path().join("copy.db");
+        fixture.write(&path).unwrap();
+        let mut candidates = [UID_A, UID_B, uid]
+            .into_iter()
+            .map(|x| (x.to_string(), None))
+            .collect::<BTreeMap<_, _>>();
+        candidates.insert(uid.to_string(), Some(10000));
+
+        let resolved = resolve_uid(&path, &candidates).unwrap().unwrap();
+        assert_eq!(resolved.uid, uid);
+        assert_eq!(resolved.uin, Some(10000));
+        assert_eq!(resolved.matched_by, UidMatch::Key);
+
+        candidates.remove(uid);
+        assert!(resolve_uid(&path, &candidates).unwrap().is_none());
+        // nor in a directory that isn't an account directory
+        let db_dir = dir.path().join("nt_qq_renamed");
+        fs::create_dir(&db_dir).unwrap();
+        fs::copy(&path, db_dir.join("nt_msg.db")).unwrap();
+        candidates.insert(uid.to_string(), None);
+        let resolved = resolve_uid(&db_dir, &candidates).unwrap().unwrap();
+        assert_eq!(
+            (resolved.uid.as_str(), resolved.matched_by),
+            (uid, UidMatch::Key)
+        );
+    }
+}
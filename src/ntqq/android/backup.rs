@@ -0,0 +1,407 @@
+//! Find the databases in a backup of QQ's app data, for decrypting them offline.
+//!
+//! A backup is either a directory tree or an archive of it. Nothing is assumed about the layout
+//! above the app data directory, so any of these work:
+//! - a copy of `/data/user/<n>/com.tencent.mobileqq`, or of the whole `/data`, e.g. from TWRP.
+//! - a `.tar`/`.tar.gz` of them, e.g. from Neo Backup.
+//! - an `adb backup` file (`.ab`), or a `.tar` converted from it, where `files` is `f` and `databases` is `db`.
+//!
+//! The uids are taken from the `files/uid/<uin>###<uid>` entries, and matched to the `nt_db/nt_qq_<hash>`
+//! account directories by [account_dir_hash].
+
+use super::*;
+use snafu::ensure;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Component;
+
+/// How deep [detect_db_file_in_backup] looks into the backup directory.
+const BACKUP_SCAN_DEPTH: usize = 16;
+
+/// Find every account database in the backup directory `dir`, see the [module](self) docs.
+///
+/// Databases whose uid is not found in the backup are also returned, with [UserDBFile::uid] being `None`.
+pub fn detect_db_file_in_backup(dir: &Path) -> crate::Result<Vec<UserDBFile>> {
+    let mut uids = BTreeMap::new();
+    let mut db_files = Vec::new();
+    walk_backup(dir, BACKUP_SCAN_DEPTH, &mut uids, &mut db_files).context(IoOpSnafu {
+        op: format!("read backup directory {}", dir.display()),
+    })?;
+    db_files.sort();
+    Ok(db_files
+        .into_iter()
+        .map(|path| {
+            let hash = account_dir_hash_of(&path);
+            let found = uids
+                .iter()
+                .find(|(uid, _)| hash.as_deref() == Some(account_dir_hash(uid).as_str()));
+            let (profile, package) = profile_and_package_of(&path);
+            UserDBFile {
+                uid: found.map(|x| x.0.clone()),
+                uin: found.and_then(|x| *x.1),
+                path,
+                profile,
+                package,
+            }
+        })
+        .collect())
+}
+
+fn walk_backup(
+    dir: &Path,
+    depth: usize,
+    uids: &mut BTreeMap<String, Option<u64>>,
+    db_files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // symlinks are not followed, they may point out of the backup
+        let file_type = entry.file_type()?;
+        if is_uid_entry(&path) {
+            add_uids(&entry.file_name().to_string_lossy(), uids);
+        } else if file_type.is_file()
+            && entry.file_name() == "nt_msg.db"
+            && is_account_db_entry(&path)
+        {
+            db_files.push(path);
+        } else if file_type.is_dir() && depth > 1 {
+            walk_backup(&path, depth - 1, uids, db_files)?;
+        }
+    }
+    Ok(())
+}
+
+/// The profile and package of the app data `path` is in, if they're in the path,
+/// e.g. `.../user/10/com.tencent.tim/databases/nt_db/...`, `.../data/data/<package>/...` (profile 0),
+/// or `apps/<package>/db/nt_db/...` in an `adb backup`.
+fn profile_and_package_of(path: &Path) -> (Option<u32>, Option<String>) {
+    let names = path.iter().map(|x| x.to_string_lossy()).collect::<Vec<_>>();
+    let Some(i) = names.iter().position(|x| x == "nt_db") else {
+        return (None, None);
+    };
+    let name = |back: usize| i.checked_sub(back).map(|x| names[x].as_ref());
+    let package = name(2).filter(|x| x.contains('.')).map(str::to_owned);
+    let profile = match (name(4), name(3)) {
+        (Some("user"), Some(profile)) => profile.parse().ok(),
+        (Some("data"), Some("data")) => Some(0),
+        _ => None,
+    };
+    (profile, package)
+}
+
+/// Whether `path` is like `.../files/uid/<uin>###<uid>`, or `.../f/uid/...` in an `adb backup`.
+fn is_uid_entry(path: &Path) -> bool {
+    let mut names = path.iter().rev();
+    let (Some(name), Some(uid), Some(files)) = (names.next(), names.next(), names.next()) else {
+        return false;
+    };
+    name.to_string_lossy().contains("###") && uid == "uid" && (files == "files" || files == "f")
+}
+
+/// Whether `path` is in an account directory, like `.../nt_db/nt_qq_<hash>/...`.
+fn is_account_db_entry(path: &Path) -> bool {
+    let names = path.iter().collect::<Vec<_>>();
+    names
+        .windows(2)
+        .any(|x| x[0] == "nt_db" && x[1].to_string_lossy().starts_with("nt_qq_"))
+}
+
+/// Extract what [detect_db_file_in_backup] needs from the backup archive at `archive` into `dest`:
+/// the account directories and the uid entries, keeping their paths in the archive.
+///
+/// `archive` is a `.tar`, optionally gzip-compressed, or an unencrypted `adb backup` file.
+pub fn extract_backup(archive: &Path, dest: &Path) -> crate::Result<()> {
+    let file = fs::File::open(archive).context(IoOpSnafu {
+        op: format!("open backup {}", archive.display()),
+    })?;
+    let mut reader = BufReader::new(file);
+    let tar: Box<dyn Read> = match reader.fill_buf() {
+        Ok([0x1f, 0x8b, ..]) => Box::new(flate2::read::GzDecoder::new(reader)),
+        Ok(x) if x.starts_with(ADB_BACKUP_MAGIC) => adb_backup_tar(reader)?,
+        _ => Box::new(reader),
+    };
+    let mut tar = tar::Archive::new(tar);
+    let entries = tar.entries().context(IoOpSnafu {
+        op: "read backup archive",
+    })?;
+    for entry in entries {
+        let mut entry = entry.context(IoOpSnafu {
+            op: "read backup archive entry",
+        })?;
+        let path = entry
+            .path()
+            .context(IoOpSnafu {
+                op: "read backup archive entry path",
+            })?
+            .into_owned();
+        // links are not extracted, [detect_db_file_in_backup] doesn't follow them anyway
+        let kind = entry.header().entry_type();
+        let wanted = (kind.is_file() || kind.is_dir())
+            && path.components().all(|x| matches!(x, Component::Normal(_)))
+            && (is_uid_entry(&path) || is_account_db_entry(&path));
+        if !wanted {
+            continue;
+        }
+        // refuses paths out of `dest`, which are already skipped above anyway
+        entry.unpack_in(dest).context(IoOpSnafu {
+            op: format!("extract {}", path.display()),
+        })?;
+    }
+    Ok(())
+}
+
+const ADB_BACKUP_MAGIC: &[u8] = b"ANDROID BACKUP\n";
+
+/// The tar stream in an `adb backup` file, whose header is 4 lines:
+/// magic, format version, whether compressed, and encryption.
+fn adb_backup_tar(mut reader: BufReader<fs::File>) -> crate::Result<Box<dyn Read>> {
+    let mut lines = [String::new(), String::new(), String::new(), String::new()];
+    for line in &mut lines {
+        reader.read_line(line).context(IoOpSnafu {
+            op: "read adb backup header",
+        })?;
+    }
+    let encryption = lines[3].trim();
+    ensure!(
+        encryption == "none",
+        UnsupportedBackupSnafu {
+            reason: format!("encrypted ({encryption}) adb backup"),
+        }
+    );
+    Ok(if lines[2].trim() == "1" {
+        Box::new(flate2::read::ZlibDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntqq;
+    use std::io::Write;
+
+    const UID_A: &str = "u_aaaaaaaaaaaaaaaaaaaaaa";
+    const UID_B: &str = "u_bbbbbbbbbbbbbbbbbbbbbb";
+    const UID_C: &str = "u_cccccccccccccccccccccc";
+
+    /// `databases` is `db` in an `adb backup`.
+    fn db_path(app_dir: &str, databases: &str, uid: &str) -> String {
+        format!(
+            "{app_dir}/{databases}/nt_db/nt_qq_{}/nt_msg.db",
+            account_dir_hash(uid)
+        )
+    }
+
+    /// A tar entry with `path` written as is, which [tar::Builder] would refuse for `..` and absolute paths.
+    fn append(tar: &mut tar::Builder<Vec<u8>>, kind: tar::EntryType, path: &str, link: &str) {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_size(path.len() as u64);
+        header.set_cksum();
+        tar.append(&header, path.as_bytes()).unwrap();
+    }
+
+    /// A tar of the app data of QQ in profile 10, and of TIM in profile 0 as an `adb backup` lays it out,
+    /// along with entries that must not be extracted, some of which point into `outside`.
+    fn tar(outside: &Path) -> Vec<u8> {
+        let outside = outside.to_str().unwrap();
+        let qq = "data/user/10/com.tencent.mobileqq";
+        let tim = "apps/com.tencent.tim";
+        let symlink = format!("{qq}/databases/nt_db/nt_qq_link");
+        let mut tar = tar::Builder::new(Vec::new());
+        use tar::EntryType::*;
+        for (kind, path, target) in [
+            (Regular, format!("{qq}/files/uid/10001###{UID_A}"), ""),
+            (Regular, db_path(qq, "databases", UID_A), ""),
+            (
+                Regular,
+                format!("{qq}/databases/nt_db/nt_qq_unknown/nt_msg.db"),
+                "",
+            ),
+            (Regular, format!("{qq}/files/other.txt"), ""),
+            (Regular, format!("{tim}/f/uid/10002###{UID_B}"), ""),
+            (Regular, db_path(tim, "db", UID_B), ""),
+            (Regular, "../nt_db/nt_qq_up/nt_msg.db".to_string(), ""),
+            (Regular, format!("{outside}/nt_db/nt_qq_abs/nt_msg.db"), ""),
+            // the file after it would be written into `outside` if the symlink were extracted
+            (Symlink, symlink.clone(), outside),
+            (Regular, format!("{symlink}/nt_msg.db"), ""),
+            (Link, format!("{qq}/files/uid/0###{UID_C}"), "/etc/passwd"),
+        ] {
+            append(&mut tar, kind, &path, target);
+        }
+        tar.into_inner().unwrap()
+    }
+
+    fn adb_backup(compressed: bool, encryption: &str, tar: &[u8]) -> Vec<u8> {
+        let mut data =
+            format!("ANDROID BACKUP\n5\n{}\n{encryption}\n", compressed as u8).into_bytes();
+        if compressed {
+            let mut z = flate2::write::ZlibEncoder::new(&mut data, flate2::Compression::default());
+            z.write_all(tar).unwrap();
+            z.finish().unwrap();
+        } else {
+            data.extend(tar);
+        }
+        data
+    }
+
+    #[test]
+    fn extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let tar = tar(&outside);
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        let archives = [
+            ("tar", tar.clone()),
+            ("tar.gz", gz.finish().unwrap()),
+            ("ab", adb_backup(false, "none", &tar)),
+            ("zlib.ab", adb_backup(true, "none", &tar)),
+        ];
+        for (name, data) in archives {
+            let archive = dir.path().join(format!("backup.{name}"));
+            fs::write(&archive, data).unwrap();
+            let dest = dir.path().join(name).join("dest");
+            fs::create_dir_all(&dest).unwrap();
+            extract_backup(&archive, &dest).unwrap();
+
+            // nothing out of `dest`, not even next to it
+            assert_eq!(fs::read_dir(&outside).unwrap().count(), 0, "{name}");
+            assert_eq!(
+                fs::read_dir(dest.parent().unwrap()).unwrap().count(),
+                1,
+                "{name}"
+            );
+            let link = dest.join("data/user/10/com.tencent.mobileqq/databases/nt_db/nt_qq_link");
+            assert!(fs::symlink_metadata(&link).unwrap().is_dir(), "{name}");
+            assert!(
+                !dest
+                    .join("data/user/10/com.tencent.mobileqq/files/other.txt")
+                    .exists()
+            );
+            assert!(
+                !dest
+                    .join(format!(
+                        "data/user/10/com.tencent.mobileqq/files/uid/0###{UID_C}"
+                    ))
+                    .exists()
+            );
+
+            let files = detect_db_file_in_backup(&dest).unwrap();
+            let found = files
+                .iter()
+                .map(|x| {
+                    (
+                        x.path.strip_prefix(&dest).unwrap().to_str().unwrap(),
+                        x.uid.as_deref(),
+                        x.uin,
+                        x.profile,
+                        x.package.as_deref(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let qq_a = db_path("data/user/10/com.tencent.mobileqq", "databases", UID_A);
+            let tim_b = db_path("apps/com.tencent.tim", "db", UID_B);
+            let mut expected = vec![
+                (
+                    tim_b.as_str(),
+                    Some(UID_B),
+                    Some(10002),
+                    None,
+                    Some("com.tencent.tim"),
+                ),
+                (
+                    qq_a.as_str(),
+                    Some(UID_A),
+                    Some(10001),
+                    Some(10),
+                    Some("com.tencent.mobileqq"),
+                ),
+                (
+                    "data/user/10/com.tencent.mobileqq/databases/nt_db/nt_qq_link/nt_msg.db",
+                    None,
+                    None,
+                    Some(10),
+                    Some("com.tencent.mobileqq"),
+                ),
+                (
+                    "data/user/10/com.tencent.mobileqq/databases/nt_db/nt_qq_unknown/nt_msg.db",
+                    None,
+                    None,
+                    Some(10),
+                    Some("com.tencent.mobileqq"),
+                ),
+            ];
+            expected.sort();
+            assert_eq!(found, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn encrypted_adb_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("backup.ab");
+        fs::write(&archive, adb_backup(true, "AES-256", b"")).unwrap();
+        let e = extract_backup(&archive, dir.path()).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                crate::Error::NTQQ {
+                    source: ntqq::Error::Android {
+                        source: Error::UnsupportedBackup { .. }
+                    }
+                }
+            ),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn profile_and_package() {
+        let of = |path: &str| profile_and_package_of(Path::new(path));
+        let package = |x: &str| Some(x.to_string());
+        assert_eq!(
+            of("backup/data/user/10/com.tencent.tim/databases/nt_db/nt_qq_x/nt_msg.db"),
+            (Some(10), package("com.tencent.tim"))
+        );
+        assert_eq!(
+            of("data/user/999/com.tencent.mobileqq/databases/nt_db/nt_qq_x"),
+            (Some(999), package("com.tencent.mobileqq"))
+        );
+        assert_eq!(
+            of("/data/data/com.tencent.mobileqq/databases/nt_db/nt_qq_x/nt_msg.db"),
+            (Some(0), package("com.tencent.mobileqq"))
+        );
+        assert_eq!(
+            of("apps/com.tencent.qqlite/db/nt_db/nt_qq_x/nt_msg.db"),
+            (None, package("com.tencent.qqlite"))
+        );
+        // not a package name, and no profile
+        assert_eq!(of("copy/databases/nt_db/nt_qq_x/nt_msg.db"), (None, None));
+        assert_eq!(of("nt_db/nt_qq_x/nt_msg.db"), (None, None));
+        assert_eq!(of("nt_qq_x/nt_msg.db"), (None, None));
+    }
+
+    #[test]
+    fn uid_entry() {
+        let is = |path: &str| is_uid_entry(Path::new(path));
+        assert!(is(&format!(
+            "com.tencent.mobileqq/files/uid/10001###{UID_A}"
+        )));
+        assert!(is(&format!(
+            "apps/com.tencent.mobileqq/f/uid/10001###{UID_A}"
+        )));
+        assert!(!is("com.tencent.mobileqq/files/uid/readme"));
+        assert!(!is(&format!(
+            "com.tencent.mobileqq/files/other/10001###{UID_A}"
+        )));
+        assert!(!is(&format!("com.tencent.mobileqq/db/uid/10001###{UID_A}")));
+        assert!(!is(&format!("uid/10001###{UID_A}")));
+    }
+}
@@ -0,0 +1,520 @@
+//! Recover the database key from a memory dump of QQ, for when a debugger can't be used.
+//!
+//! The key stays in memory as a NUL-terminated 16-char printable string (the same shape the debuggers accept),
+//! so every such string in the dump is a candidate. Each candidate is checked against the HMAC of page 1
+//! of a database, see [crate::sqlcipher::verify_key].
+//!
+//! Supported dumps are ELF core files (e.g. from `gcore`), Windows minidumps (`MDMP`, full memory or not),
+//! and anything else is scanned as raw memory, e.g. a copy of `/proc/<pid>/mem` regions.
+
+use super::DBDecryptInfo;
+use crate::sqlcipher::{self, KeyCheck};
+use object::{Object, ObjectSegment};
+use snafu::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Length of the key, see [DBDecryptInfo::key].
+pub const KEY_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    ElfCore,
+    Minidump,
+    Raw,
+}
+
+impl DumpFormat {
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"MDMP") {
+            DumpFormat::Minidump
+        } else if data.starts_with(b"\x7fELF") {
+            DumpFormat::ElfCore
+        } else {
+            DumpFormat::Raw
+        }
+    }
+}
+
+/// A key candidate found in the dump.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub key: String,
+    /// Offset of the candidate in the dump file.
+    pub file_offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FoundKey {
+    /// Decrypt info with the working hmac algorithm filled in.
+    pub decrypt_info: DBDecryptInfo,
+    pub candidate: Candidate,
+    /// Position of the candidate among the distinct candidates in the dump, starting from 1.
+    pub position: usize,
+}
+
+/// Scan the dump at `dump` for the key of the database at `db`.
+///
+/// Returns `None` if no candidate works.
+pub fn scan_file(
+    dump: impl AsRef<Path>,
+    db: impl AsRef<Path>,
+    kdf_iter: Option<usize>,
+) -> crate::Result<Option<FoundKey>> {
+    let file = fs::File::open(dump).context(IoSnafu { op: "open dump" })?;
+    let data = unsafe {
+        // SAFETY: the dump file should not be modified during the mapping lifetime, in practice.
+        memmap2::MmapOptions::new().map(&file)
+    }
+    .context(IoSnafu { op: "mmap dump" })?;
+
+    let mut db = fs::File::open(db).context(IoSnafu { op: "open db" })?;
+    let mut page1 = vec![0u8; sqlcipher::NTQQ_HEADER_SIZE + sqlcipher::PAGE_SIZE];
+    let n = sqlcipher::read_full(&mut db, &mut page1).context(IoSnafu { op: "read page 1" })?;
+    page1.truncate(n);
+    scan(&data, &page1, kdf_iter)
+}
+
+/// Scan `dump` for the key of the database starting with `page1`,
+/// which is the first bytes of the database file, with or without the NTQQ header.
+///
+/// `kdf_iter` is as in [DBDecryptInfo::kdf_iter], `None` for NTQQ's own databases.
+pub fn scan(dump: &[u8], page1: &[u8], kdf_iter: Option<usize>) -> crate::Result<Option<FoundKey>> {
+    if sqlcipher::strip_ntqq_header(page1).len() < sqlcipher::PAGE_SIZE {
+        return Err(sqlcipher::Error::TruncatedPage { pgno: 1 }.into());
+    }
+    let candidates = candidates(dump)?;
+    log::info!("{} distinct key candidates found", candidates.len());
+
+    // each check runs a full PBKDF2, so spread them over all cores
+    let next = AtomicUsize::new(0);
+    let found = Mutex::new(None);
+    let workers = std::thread::available_parallelism().map_or(1, |x| x.get());
+    std::thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= candidates.len() || found.lock().unwrap().is_some() {
+                        break;
+                    }
+                    let d = DBDecryptInfo {
+                        key: candidates[i].key.clone(),
+                        cipher_hmac_algorithm: None,
+                        kdf_iter,
+                    };
+                    if let Ok(KeyCheck::Match(hmac)) = sqlcipher::verify_key(page1, &d) {
+                        *found.lock().unwrap() = Some((i, hmac));
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(found.into_inner().unwrap().map(|(i, hmac)| FoundKey {
+        decrypt_info: DBDecryptInfo {
+            key: candidates[i].key.clone(),
+            cipher_hmac_algorithm: Some(hmac.to_string()),
+            kdf_iter,
+        },
+        candidate: candidates[i].clone(),
+        position: i + 1,
+    }))
+}
+
+/// Collect the distinct key candidates in the memory of `dump`, in the order they appear.
+pub fn candidates(dump: &[u8]) -> Result<Vec<Candidate>> {
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+    for (region, file_offset) in memory_regions(dump)? {
+        for (offset, key) in find_candidates(region) {
+            if seen.insert(key) {
+                found.push(Candidate {
+                    key: String::from_utf8(key.to_vec()).unwrap(),
+                    file_offset: file_offset + offset as u64,
+                });
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// The memory captured in the dump, along with the file offset of each region.
+pub fn memory_regions(dump: &[u8]) -> Result<Vec<(&[u8], u64)>> {
+    let offset_of = |region: &[u8]| region.as_ptr() as u64 - dump.as_ptr() as u64;
+    match DumpFormat::detect(dump) {
+        DumpFormat::ElfCore => {
+            let obj = object::File::parse(dump)?;
+            let mut regions = Vec::new();
+            for segment in obj.segments() {
+                let data = segment.data()?;
+                if !data.is_empty() {
+                    regions.push((data, offset_of(data)));
+                }
+            }
+            Ok(regions)
+        }
+        DumpFormat::Minidump => Ok(minidump_regions(dump)?
+            .into_iter()
+            .map(|x| (x, offset_of(x)))
+            .collect()),
+        DumpFormat::Raw => Ok(vec![(dump, 0)]),
+    }
+}
+
+/// NUL-terminated runs of exactly [KEY_LEN] printable chars.
+fn find_candidates(region: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    memchr::memchr_iter(0, region).filter_map(move |end| {
+        let begin = end.checked_sub(KEY_LEN)?;
+        let key = &region[begin..end];
+        let bounded = begin == 0 || !region[begin - 1].is_ascii_graphic();
+        (bounded && key.iter().all(u8::is_ascii_graphic)).then_some((begin, key))
+    })
+}
+
+const MINIDUMP_MEMORY_LIST_STREAM: u32 = 5;
+const MINIDUMP_MEMORY64_LIST_STREAM: u32 = 9;
+
+/// Memory ranges in the `MemoryListStream` and `Memory64ListStream` of a minidump.
+fn minidump_regions(dump: &[u8]) -> Result<Vec<&[u8]>> {
+    let u32_at = |offset: u64| -> Result<u32> {
+        let bytes = slice(dump, offset, 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let u64_at = |offset: u64| -> Result<u64> {
+        let bytes = slice(dump, offset, 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+
+    // MINIDUMP_HEADER: Signature, Version, NumberOfStreams, StreamDirectoryRva, ...
+    let stream_count = u32_at(8)? as u64;
+    let directory = u32_at(12)? as u64;
+    let mut regions = Vec::new();
+    for i in 0..stream_count {
+        // MINIDUMP_DIRECTORY: StreamType, DataSize, Rva
+        let entry = directory + i * 12;
+        let stream_type = u32_at(entry)?;
+        let rva = u32_at(entry + 8)? as u64;
+        match stream_type {
+            MINIDUMP_MEMORY_LIST_STREAM => {
+                // NumberOfMemoryRanges, then MINIDUMP_MEMORY_DESCRIPTOR:
+                // StartOfMemoryRange (u64), DataSize (u32), Rva (u32)
+                let count = u32_at(rva)? as u64;
+                for j in 0..count {
+                    let descriptor = rva + 4 + j * 16;
+                    let size = u32_at(descriptor + 8)? as u64;
+                    let data_rva = u32_at(descriptor + 12)? as u64;
+                    regions.push(slice(dump, data_rva, size)?);
+                }
+            }
+            MINIDUMP_MEMORY64_LIST_STREAM => {
+                // NumberOfMemoryRanges (u64), BaseRva (u64), then MINIDUMP_MEMORY_DESCRIPTOR64:
+                // StartOfMemoryRange (u64), DataSize (u64). The data of all ranges follows BaseRva contiguously.
+                let count = u64_at(rva)?;
+                let mut data_rva = u64_at(rva + 8)?;
+                for j in 0..count {
+                    let size = u64_at(rva + 16 + j * 16 + 8)?;
+                    regions.push(slice(dump, data_rva, size)?);
+                    data_rva += size;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(regions)
+}
+
+fn slice(dump: &[u8], offset: u64, len: u64) -> Result<&[u8]> {
+    usize::try_from(offset)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(offset, len)| dump.get(offset..offset.checked_add(len)?))
+        .context(MalformedMinidumpSnafu { offset })
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("IO operation to {}: {}", op, source))]
+    Io { source: std::io::Error, op: String },
+    #[snafu(transparent)]
+    Object { source: object::Error },
+    #[snafu(display("malformed minidump: out of bounds at 0x{:X}", offset))]
+    MalformedMinidump { offset: u64 },
+}
+
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> Self {
+        super::Error::from(e).into()
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::write::elf;
+
+    const KEY_A: &[u8] = b"aaaaBBBBccccDDDD";
+    const KEY_B: &[u8] = b"0123456789abcdef";
+    const KEY_C: &[u8] = b"!#$%&()*+,-./:;<";
+
+    /// `key` NUL-terminated, after some non-printable bytes.
+    fn region(key: &[u8]) -> Vec<u8> {
+        let mut region = vec![0xFF; 8];
+        region.extend(key);
+        region.extend([0, 0xFF, 0xFF]);
+        region
+    }
+
+    /// A minidump with [region] of [KEY_A] in a `MemoryListStream`,
+    /// and of [KEY_B] and [KEY_C] in a `Memory64ListStream`.
+    /// Returns the file offsets of the regions as well.
+    fn minidump() -> (Vec<u8>, [u64; 3]) {
+        let regions = [region(KEY_A), region(KEY_B), region(KEY_C)];
+        let directory = 32u32;
+        let memory_list = directory + 2 * 12;
+        let memory64_list = memory_list + 4 + 16;
+        let data = memory64_list + 16 + 2 * 16;
+        let offsets = [0, 1, 2]
+            .map(|i| data as u64 + regions[..i].iter().map(|x| x.len() as u64).sum::<u64>());
+
+        let mut dump = Vec::new();
+        // MINIDUMP_HEADER
+        dump.extend(b"MDMP");
+        dump.extend(0xA793u32.to_le_bytes());
+        dump.extend(2u32.to_le_bytes());
+        dump.extend(directory.to_le_bytes());
+        dump.extend([0; 16]);
+        // MINIDUMP_DIRECTORY
+        for (stream_type, rva, size) in [
+            (MINIDUMP_MEMORY_LIST_STREAM, memory_list, 4 + 16),
+            (MINIDUMP_MEMORY64_LIST_STREAM, memory64_list, 16 + 2 * 16),
+        ] {
+            dump.extend(stream_type.to_le_bytes());
+            dump.extend((size as u32).to_le_bytes());
+            dump.extend(rva.to_le_bytes());
+        }
+        // MINIDUMP_MEMORY_LIST
+        dump.extend(1u32.to_le_bytes());
+        dump.extend(0x7000_0000u64.to_le_bytes());
+        dump.extend((regions[0].len() as u32).to_le_bytes());
+        dump.extend((offsets[0] as u32).to_le_bytes());
+        // MINIDUMP_MEMORY64_LIST
+        dump.extend(2u64.to_le_bytes());
+        dump.extend(offsets[1].to_le_bytes());
+        for (i, region) in regions[1..].iter().enumerate() {
+            dump.extend((0x7100_0000u64 + i as u64 * 0x1000).to_le_bytes());
+            dump.extend((region.len() as u64).to_le_bytes());
+        }
+        assert_eq!(dump.len(), data as usize);
+        dump.extend(regions.concat());
+        (dump, offsets)
+    }
+
+    /// An ELF core with a `PT_NOTE`, [region] of [KEY_A] and of [KEY_B] in two `PT_LOAD`s,
+    /// and a `PT_LOAD` with nothing in the file, as `gcore` writes for unreadable memory.
+    /// Returns the file offsets of the regions as well.
+    fn elf_core() -> (Vec<u8>, [u64; 2]) {
+        let regions = [region(KEY_A), region(KEY_B)];
+        let mut data = Vec::new();
+        let mut w = elf::Writer::new(object::Endianness::Little, true, &mut data);
+        w.reserve_file_header();
+        w.reserve_program_headers(4);
+        let offsets = regions.each_ref().map(|x| w.reserve(x.len(), 1) as u64);
+        w.write_file_header(&elf::FileHeader {
+            os_abi: object::elf::ELFOSABI_NONE,
+            abi_version: 0,
+            e_type: object::elf::ET_CORE,
+            e_machine: object::elf::EM_X86_64,
+            e_entry: 0,
+            e_flags: 0,
+        })
+        .unwrap();
+        w.write_align_program_headers();
+        w.write_program_header(&elf::ProgramHeader {
+            p_type: object::elf::PT_NOTE,
+            p_flags: 0,
+            p_offset: 0,
+            p_vaddr: 0,
+            p_paddr: 0,
+            p_filesz: 0,
+            p_memsz: 0,
+            p_align: 4,
+        });
+        for (i, (region, offset)) in regions.iter().zip(offsets).enumerate() {
+            w.write_program_header(&elf::ProgramHeader {
+                p_type: object::elf::PT_LOAD,
+                p_flags: object::elf::PF_R | object::elf::PF_W,
+                p_offset: offset,
+                p_vaddr: 0x7000_0000 + i as u64 * 0x1000,
+                p_paddr: 0,
+                p_filesz: region.len() as u64,
+                p_memsz: 0x1000,
+                p_align: 1,
+            });
+        }
+        w.write_program_header(&elf::ProgramHeader {
+            p_type: object::elf::PT_LOAD,
+            p_flags: 0,
+            p_offset: offsets[1] + regions[1].len() as u64,
+            p_vaddr: 0x7000_2000,
+            p_paddr: 0,
+            p_filesz: 0,
+            p_memsz: 0x1000,
+            p_align: 1,
+        });
+        for region in &regions {
+            w.write(region);
+        }
+        (data, offsets)
+    }
+
+    fn keys(candidates: &[Candidate]) -> Vec<(&[u8], u64)> {
+        candidates
+            .iter()
+            .map(|x| (x.key.as_bytes(), x.file_offset))
+            .collect()
+    }
+
+    #[test]
+    fn minidump_memory_lists() {
+        let (dump, offsets) = minidump();
+        assert_eq!(DumpFormat::detect(&dump), DumpFormat::Minidump);
+        let regions = memory_regions(&dump).unwrap();
+        let expected = [KEY_A, KEY_B, KEY_C]
+            .iter()
+            .zip(offsets)
+            .map(|(key, offset)| (region(key), offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            regions
+                .iter()
+                .map(|(x, offset)| (x.to_vec(), *offset))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            keys(&candidates(&dump).unwrap()),
+            [
+                (KEY_A, offsets[0] + 8),
+                (KEY_B, offsets[1] + 8),
+                (KEY_C, offsets[2] + 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn minidump_truncated() {
+        let (dump, offsets) = minidump();
+        // the last range of the Memory64ListStream is cut short
+        let e = memory_regions(&dump[..dump.len() - 1]).unwrap_err();
+        assert!(
+            matches!(e, Error::MalformedMinidump { offset } if offset == offsets[2]),
+            "{e}"
+        );
+        // the stream directory is cut off
+        let e = memory_regions(&dump[..40]).unwrap_err();
+        assert!(matches!(e, Error::MalformedMinidump { .. }), "{e}");
+    }
+
+    #[test]
+    fn elf_core_segments() {
+        let (dump, offsets) = elf_core();
+        assert_eq!(DumpFormat::detect(&dump), DumpFormat::ElfCore);
+        let regions = memory_regions(&dump).unwrap();
+        assert_eq!(
+            regions
+                .iter()
+                .map(|(x, offset)| (x.to_vec(), *offset))
+                .collect::<Vec<_>>(),
+            [(region(KEY_A), offsets[0]), (region(KEY_B), offsets[1])]
+        );
+        assert_eq!(
+            keys(&candidates(&dump).unwrap()),
+            [(KEY_A, offsets[0] + 8), (KEY_B, offsets[1] + 8)]
+        );
+    }
+
+    #[test]
+    fn raw_candidates() {
+        let mut dump = [KEY_A, b"\0", b"\xFF", KEY_B, b"\0"].concat();
+        // seen before, so not listed again
+        dump.extend([KEY_A, b"\0"].concat());
+        assert_eq!(DumpFormat::detect(&dump), DumpFormat::Raw);
+        assert_eq!(keys(&candidates(&dump).unwrap()), [(KEY_A, 0), (KEY_B, 18)]);
+    }
+
+    #[test]
+    fn candidate_bounds() {
+        fn found(region: &[u8]) -> Vec<(usize, &[u8])> {
+            find_candidates(region).collect()
+        }
+        // at the very start of the region
+        assert_eq!(found(&[KEY_A, b"\0"].concat()), [(0, KEY_A)]);
+        // 17 chars are not a key, nor are the last 16 of them
+        assert!(found(&[b"x", KEY_A, b"\0"].concat()).is_empty());
+        assert!(found(&[b"\0x", KEY_A, b"\0"].concat()).is_empty());
+        // 15 chars
+        assert!(found(&[&KEY_A[1..], b"\0"].concat()).is_empty());
+        // not NUL-terminated
+        assert!(found(KEY_A).is_empty());
+        assert!(found(&[b"\0", KEY_A, b"\xFF"].concat()).is_empty());
+        // a space is not printable here
+        assert!(found(b"aaaaBBBB ccccDDD\0").is_empty());
+        assert_eq!(found(&[b" ", KEY_A, b"\0"].concat()), [(1, KEY_A)]);
+    }
+
+    #[cfg(feature = "fixture")]
+    #[test]
+    fn scan_fixture() {
+        use crate::db::{self, fixture::NtDbFixture};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nt_msg.db");
+        let fixture = NtDbFixture::sample();
+        fixture.write(&path).unwrap();
+        // a 16-char key, as the desktop QQ uses
+        db::register_offset_vfs().unwrap();
+        let conn = rusqlite::Connection::open_with_flags_and_vfs(
+            &path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE,
+            db::OFFSET_VFS_NAME,
+        )
+        .unwrap();
+        db::try_decrypt_db(&conn, fixture.decrypt_info()).unwrap();
+        let key = String::from_utf8(KEY_C.to_vec()).unwrap();
+        let d = DBDecryptInfo {
+            key: key.clone(),
+            ..fixture.decrypt_info()
+        };
+        db::rekey(conn, d).unwrap();
+        let page1 = fs::read(&path).unwrap();
+
+        let dump = [b"\0", KEY_A, b"\0", KEY_B, b"\0", KEY_C, b"\0"].concat();
+        let found = scan(&dump, &page1, None).unwrap().unwrap();
+        assert_eq!(found.decrypt_info.key, key);
+        assert_eq!(
+            found.decrypt_info.cipher_hmac_algorithm.as_deref(),
+            Some("HMAC_SHA1")
+        );
+        assert_eq!(found.candidate.file_offset, 35);
+        assert_eq!(found.position, 3);
+
+        assert!(scan(&dump[..35], &page1, None).unwrap().is_none());
+        assert!(scan(&dump, &page1, Some(1)).unwrap().is_none());
+        let e = scan(&dump, &page1[..100], None).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                crate::Error::SQLCipher {
+                    source: sqlcipher::Error::TruncatedPage { pgno: 1 }
+                }
+            ),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! NTQQ for Linux keeps one directory per account in its config dir:
+//! `~/.config/QQ/nt_qq_<hash>/nt_db/nt_msg.db`, where `<hash>` is derived from the account's uid
+//! the same way as on Android, see [super::account_dir_hash].
+//!
+//! The uid itself is not stored in plain sight, so [detect_db_file] collects every uid-like
+//! string (`u_` followed by 22 url-safe chars) from the file names under the config dir,
+//! and keeps the one whose hash matches the account directory.
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod debug_process;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use debug_process::*;
+
+use snafu::ResultExt;
+
+use super::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// How deep [collect_uids] looks into the config dir.
+const UID_SCAN_DEPTH: usize = 5;
+
+/// `$XDG_CONFIG_HOME/QQ`, or `~/.config/QQ` if it's not set.
+pub fn qq_config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|x| x.join("QQ"))
+}
+
+pub fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
+    let Some(config_dir) = qq_config_dir() else {
+        return Ok(Vec::new());
+    };
+    detect_db_file_in(&config_dir)
+}
+
+/// Same as [detect_db_file], but with the QQ config dir given.
+///
+/// Also used for macOS, whose QQ data dir has the same layout.
+pub fn detect_db_file_in(config_dir: &Path) -> crate::Result<Vec<UserDBFile>> {
+    let entries = fs::read_dir(config_dir).context(IoOpSnafu {
+        op: "read qq config directory",
+    })?;
+    let mut uids = BTreeMap::new();
+    collect_uids(config_dir, UID_SCAN_DEPTH, &mut uids);
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.context(IoOpSnafu {
+            op: "read qq config directory entry",
+        })?;
+        let file_name = entry.file_name();
+        let Some(hash) = file_name
+            .to_string_lossy()
+            .strip_prefix("nt_qq_")
+            .map(str::to_owned)
+        else {
+            continue;
+        };
+        let path = entry.path().join("nt_db/nt_msg.db");
+        if !path.is_file() {
+            continue;
+        }
+        let (uid, uin) = match uids.iter().find(|(uid, _)| account_dir_hash(uid) == hash) {
+            Some((uid, uin)) => (Some(uid.clone()), *uin),
+            None => (None, None),
+        };
+        files.push(UserDBFile {
+            path,
+            uid,
+            uin,
+            ..Default::default()
+        });
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("IO operation to {}: {}", op, source))]
+    IoOp { source: std::io::Error, op: String },
+    #[snafu(display("{}: {}", op, source))]
+    Ptrace { source: std::io::Error, op: String },
+    #[snafu(display("debug for key: {}", msg))]
+    DebugForKey { msg: String },
+    #[snafu(display("process {} exited before the key is found", pid))]
+    ProcessExited { pid: i32 },
+}
+
+#[allow(dead_code)]
+type Result<T> = std::result::Result<T, Error>;
+
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> Self {
+        super::Error::from(e).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID_A: &str = "u_aaaaaaaaaaaaaaaaaaaaaa";
+    const UID_B: &str = "u_bbbbbbbbbbbbbbbbbbbbbb";
+    /// Not in any file name.
+    const UID_C: &str = "u_cccccccccccccccccccccc";
+
+    fn account_dir(config_dir: &Path, uid: &str) -> PathBuf {
+        let dir = config_dir.join(format!("nt_qq_{}", account_dir_hash(uid)));
+        fs::create_dir_all(dir.join("nt_db")).unwrap();
+        fs::write(dir.join("nt_db/nt_msg.db"), b"").unwrap();
+        dir
+    }
+
+    #[test]
+    fn detect() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path();
+        let a = account_dir(config_dir, UID_A);
+        let c = account_dir(config_dir, UID_C);
+        // the uid of A in a file name deep in its own account dir, and of B in a stray dir
+        fs::create_dir_all(a.join("nt_data/login")).unwrap();
+        fs::write(a.join(format!("nt_data/login/10001.{UID_A}.json")), b"").unwrap();
+        fs::create_dir_all(config_dir.join("global/nt_data")).unwrap();
+        fs::write(
+            config_dir.join(format!("global/nt_data/10002###{UID_B}")),
+            b"",
+        )
+        .unwrap();
+        // neither is an account with a database
+        fs::create_dir(config_dir.join(format!("nt_qq_{}", account_dir_hash(UID_B)))).unwrap();
+        fs::write(config_dir.join("nt_qq_file"), b"").unwrap();
+
+        let files = detect_db_file_in(config_dir).unwrap();
+        let found = files
+            .iter()
+            .map(|x| (x.path.clone(), x.uid.as_deref(), x.uin))
+            .collect::<Vec<_>>();
+        let mut expected = vec![
+            (a.join("nt_db/nt_msg.db"), Some(UID_A), Some(10001)),
+            (c.join("nt_db/nt_msg.db"), None, None),
+        ];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert!(
+            files
+                .iter()
+                .all(|x| x.profile.is_none() && x.package.is_none())
+        );
+
+        assert!(detect_db_file_in(&config_dir.join("missing")).is_err());
+    }
+}
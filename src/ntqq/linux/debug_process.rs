@@ -0,0 +1,495 @@
+//! Extract the database key from a running Linux NTQQ with ptrace.
+//!
+//! Same idea as the Windows debugger: put an `int3` at the start of the key function
+//! (see [TargetFunction]) and read its key argument when it's hit.
+//! The function is `int64 f(void *db, const char *zDb, const void *key, int nKey)`, so by the SysV ABI
+//! the key is pointed to by `rdx`, and its length is in `rcx`.
+
+use crate::ntqq::DBDecryptInfo;
+use crate::ntqq::analyzer::TargetFunction;
+use log::{debug, error, info};
+use snafu::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::*;
+
+/// Name of the module containing the key function.
+pub const WRAPPER_NODE: &str = "wrapper.node";
+/// Where the official package installs QQ.
+pub const DEFAULT_INSTALL_DIR: &str = "/opt/QQ";
+
+/// The process to extract the key from.
+#[derive(Debug, Clone)]
+pub enum DebugTarget {
+    /// Start a new process, e.g. `/opt/QQ/qq`, and trace it from the beginning.
+    Spawn {
+        program: PathBuf,
+        args: Vec<OsString>,
+    },
+    /// Attach to a running process.
+    ///
+    /// This usually requires root or `CAP_SYS_PTRACE`, unless `kernel.yama.ptrace_scope` is 0.
+    /// Note the key function is only called when a database is opened, e.g. on login,
+    /// so attaching to an already logged in QQ waits forever.
+    Attach { pid: i32 },
+}
+
+/// Input information for the ptrace-based key extraction.
+#[derive(Debug, Clone)]
+pub struct DebugInfo {
+    pub target: DebugTarget,
+    /// Target function to set breakpoint on, relative to where [WRAPPER_NODE] is loaded.
+    pub func: TargetFunction,
+}
+
+/// `<install_dir>/resources/app/wrapper.node`
+pub fn wrapper_node_path(install_dir: impl AsRef<Path>) -> PathBuf {
+    install_dir
+        .as_ref()
+        .join("resources/app")
+        .join(WRAPPER_NODE)
+}
+
+/// Find running processes that have [WRAPPER_NODE] loaded, and the path it's loaded from.
+pub fn find_qq_processes() -> Vec<(i32, PathBuf)> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut found = entries
+        .filter_map(|x| x.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .filter_map(|pid| Some((pid, module_mapping(pid, WRAPPER_NODE).ok()??.1)))
+        .collect::<Vec<_>>();
+    found.sort();
+    found
+}
+
+/// Launch or attach to QQ, break on the key function and read the key.
+///
+/// The process is left running and detached afterwards.
+pub fn debug_for_key(info: &DebugInfo) -> crate::Result<DBDecryptInfo> {
+    let pid = match &info.target {
+        DebugTarget::Spawn { program, args } => spawn_traced(program, args)?,
+        DebugTarget::Attach { pid } => {
+            attach_all_threads(*pid)?;
+            *pid
+        }
+    };
+    info!("Tracing process PID: {}", pid);
+    let mut tracer = Tracer {
+        pid,
+        mem: None,
+        threads: fs::read_dir(format!("/proc/{}/task", pid))
+            .context(IoOpSnafu {
+                op: "list traced process threads",
+            })?
+            .filter_map(|x| x.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        breakpoint: None,
+        stepping_threads: HashSet::new(),
+        stopped_threads: HashSet::new(),
+    };
+    let key = tracer.run(info.func.function_offset);
+    tracer.detach_all();
+    Ok(DBDecryptInfo {
+        key: key?,
+        cipher_hmac_algorithm: None,
+        kdf_iter: None,
+    })
+}
+
+/// Software breakpoint management structure.
+struct SoftwareBreakpoint {
+    address: u64,
+    original_byte: u8,
+}
+
+struct Tracer {
+    pid: i32,
+    /// `/proc/<pid>/mem`, opened once [WRAPPER_NODE] is loaded, as a spawned process execs after being traced.
+    mem: Option<fs::File>,
+    /// Thread ids being traced.
+    threads: HashSet<i32>,
+    breakpoint: Option<SoftwareBreakpoint>,
+    /// Threads stepping over the (temporarily removed) breakpoint.
+    stepping_threads: HashSet<i32>,
+    /// Threads left stopped by us, which must not be waited for again.
+    stopped_threads: HashSet<i32>,
+}
+
+impl Tracer {
+    /// Run the tracee until the key is found.
+    fn run(&mut self, function_offset: u64) -> Result<String> {
+        loop {
+            if self.breakpoint.is_none()
+                && let Some((base, path)) = self.module_mapping()?
+            {
+                let address = base + function_offset;
+                info!(
+                    "{} found at: 0x{base:X} ({}), target: 0x{address:X}",
+                    WRAPPER_NODE,
+                    path.display()
+                );
+                self.mem = Some(
+                    fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(format!("/proc/{}/mem", self.pid))
+                        .context(IoOpSnafu {
+                            op: "open traced process memory",
+                        })?,
+                );
+                let original_byte = self.read_byte(address)?;
+                self.write_byte(address, 0xCC)?;
+                self.breakpoint = Some(SoftwareBreakpoint {
+                    address,
+                    original_byte,
+                });
+                info!("Software breakpoint set at 0x{address:X}");
+            }
+
+            let mut status = 0;
+            let tid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL | libc::WNOHANG) };
+            if tid == 0 {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            if tid < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::ECHILD) {
+                    // nothing left to trace
+                    return ProcessExitedSnafu { pid: self.pid }.fail();
+                }
+                return Err(e).context(PtraceSnafu { op: "wait" });
+            }
+
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                self.threads.remove(&tid);
+                self.stepping_threads.remove(&tid);
+                if tid == self.pid {
+                    return ProcessExitedSnafu { pid: self.pid }.fail();
+                }
+                continue;
+            }
+            if !libc::WIFSTOPPED(status) {
+                continue;
+            }
+            let sig = libc::WSTOPSIG(status);
+            let event = status >> 16;
+            if event == libc::PTRACE_EVENT_CLONE {
+                let mut new_tid: libc::c_ulong = 0;
+                ptrace(
+                    libc::PTRACE_GETEVENTMSG,
+                    tid,
+                    0,
+                    &mut new_tid as *mut _ as usize,
+                )?;
+                debug!("Thread created: {}", new_tid);
+                self.threads.insert(new_tid as i32);
+                self.cont(tid, 0);
+            } else if !self.threads.contains(&tid) && tgid_of(tid) != Some(self.pid) {
+                // not one of our threads, e.g. a child process traced by accident. Leave it alone.
+                self.detach_foreign(tid);
+            } else if sig == libc::SIGTRAP && self.stepping_threads.remove(&tid) {
+                self.threads.insert(tid);
+                // other threads may still be about to execute the original instruction
+                if self.stepping_threads.is_empty()
+                    && let Some(bp) = &self.breakpoint
+                {
+                    self.write_byte(bp.address, 0xCC)?;
+                    debug!("Breakpoint restored at 0x{:X}", bp.address);
+                }
+                self.cont(tid, 0);
+            } else if sig == libc::SIGTRAP && event == 0 {
+                self.threads.insert(tid);
+                if let Some(key) = self.handle_breakpoint(tid)? {
+                    return Ok(key);
+                }
+            } else if event != 0 {
+                // new threads and group stops, or events we don't care about
+                self.threads.insert(tid);
+                self.cont(tid, 0);
+            } else {
+                // deliver other signals as is
+                self.cont(tid, sig);
+            }
+        }
+    }
+
+    /// Where [WRAPPER_NODE] is mapped in the tracee, if it's loaded yet.
+    ///
+    /// Once the tracee is gone, so is its `/proc` entry, which is reported as [Error::ProcessExited]
+    /// rather than failing to read it.
+    fn module_mapping(&self) -> Result<Option<(u64, PathBuf)>> {
+        module_mapping(self.pid, WRAPPER_NODE).or_else(|e| match e {
+            Error::IoOp { source, .. } if source.kind() == io::ErrorKind::NotFound => {
+                ProcessExitedSnafu { pid: self.pid }.fail()
+            }
+            e => Err(e),
+        })
+    }
+
+    /// Handles a SIGTRAP, which is our breakpoint if the thread is right after it.
+    fn handle_breakpoint(&mut self, tid: i32) -> Result<Option<String>> {
+        let mut regs = get_regs(tid)?;
+        let Some(bp) = self
+            .breakpoint
+            .as_ref()
+            .filter(|bp| regs.rip.wrapping_sub(1) == bp.address)
+        else {
+            self.cont(tid, libc::SIGTRAP);
+            return Ok(None);
+        };
+        let address = bp.address;
+        debug!("Software breakpoint hit at 0x{:X}", address);
+        debug!("RDX Register Value: 0x{:X}, RCX: {}", regs.rdx, regs.rcx);
+
+        // resume from the original instruction, whatever happens next
+        self.write_byte(address, bp.original_byte)?;
+        regs.rip = address;
+        set_regs(tid, &regs)?;
+
+        // nKey is an int, the upper half of rcx is whatever was there
+        let key = self.read_key(regs.rdx, regs.rcx as u32 as usize);
+        match key {
+            Some(key) => {
+                info!("Found target key: {}", key);
+                self.stopped_threads.insert(tid);
+                Ok(Some(key))
+            }
+            None => {
+                debug!("Non-target call with key length {}", regs.rcx);
+                // step over the original instruction, then put the breakpoint back
+                ptrace(libc::PTRACE_SINGLESTEP, tid, 0, 0)?;
+                self.stepping_threads.insert(tid);
+                Ok(None)
+            }
+        }
+    }
+
+    /// The key is a 16-char printable ASCII string.
+    fn read_key(&self, address: u64, len: usize) -> Option<String> {
+        if len != 16 {
+            return None;
+        }
+        let mut buf = vec![0u8; len];
+        self.mem.as_ref()?.read_exact_at(&mut buf, address).ok()?;
+        buf.iter()
+            .all(u8::is_ascii_graphic)
+            .then(|| String::from_utf8(buf).unwrap())
+    }
+
+    fn read_byte(&self, address: u64) -> Result<u8> {
+        let mut buf = [0u8];
+        self.mem()?
+            .read_exact_at(&mut buf, address)
+            .context(IoOpSnafu {
+                op: "read original byte for breakpoint",
+            })?;
+        Ok(buf[0])
+    }
+    fn write_byte(&self, address: u64, byte: u8) -> Result<()> {
+        self.mem()?
+            .write_all_at(&[byte], address)
+            .context(IoOpSnafu {
+                op: "write breakpoint byte",
+            })
+    }
+    fn mem(&self) -> Result<&fs::File> {
+        self.mem.as_ref().context(DebugForKeySnafu {
+            msg: "process memory not opened",
+        })
+    }
+
+    fn cont(&self, tid: i32, sig: i32) {
+        if let Err(e) = ptrace(libc::PTRACE_CONT, tid, 0, sig as usize) {
+            // the thread may have just exited
+            debug!("Failed to continue thread {}: {}", tid, e);
+        }
+    }
+
+    fn detach_foreign(&self, tid: i32) {
+        if let Some(bp) = &self.breakpoint
+            && let Ok(mem) = fs::OpenOptions::new()
+                .write(true)
+                .open(format!("/proc/{}/mem", tid))
+        {
+            let _ = mem.write_all_at(&[bp.original_byte], bp.address);
+        }
+        let _ = ptrace(libc::PTRACE_DETACH, tid, 0, 0);
+    }
+
+    /// Remove the breakpoint and detach from every thread, leaving the process running.
+    fn detach_all(&mut self) {
+        let bp_address = self.breakpoint.as_ref().map(|x| x.address);
+        if let Some(bp) = self.breakpoint.take()
+            && let Err(e) = self.write_byte(bp.address, bp.original_byte)
+        {
+            error!("Failed to restore original byte: {}", e);
+        }
+        // every thread must be stopped to be detached
+        let mut pending = &self.threads - &self.stopped_threads;
+        for &tid in &pending {
+            let _ = ptrace(libc::PTRACE_INTERRUPT, tid, 0, 0);
+        }
+        let mut pending_signals = HashMap::new();
+        while !pending.is_empty() {
+            let mut status = 0;
+            let tid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+            if tid < 0 {
+                break;
+            }
+            if !pending.remove(&tid) {
+                continue;
+            }
+            if !libc::WIFSTOPPED(status) {
+                continue;
+            }
+            let sig = libc::WSTOPSIG(status);
+            let event = status >> 16;
+            if sig == libc::SIGTRAP && event == 0 {
+                // hit the breakpoint before it's removed, rewind to the original instruction
+                if !self.stepping_threads.contains(&tid)
+                    && let Some(address) = bp_address
+                    && let Ok(mut regs) = get_regs(tid)
+                    && regs.rip.wrapping_sub(1) == address
+                {
+                    regs.rip = address;
+                    let _ = set_regs(tid, &regs);
+                }
+            } else if event == 0 && sig != libc::SIGSTOP {
+                pending_signals.insert(tid, sig);
+            }
+        }
+        for &tid in &self.threads {
+            let sig = pending_signals.get(&tid).copied().unwrap_or(0);
+            if let Err(e) = ptrace(libc::PTRACE_DETACH, tid, 0, sig as usize) {
+                debug!("Failed to detach thread {}: {}", tid, e);
+            }
+        }
+        info!("Detached from process PID: {}", self.pid);
+    }
+}
+
+/// Fork and exec `program`, traced from the start.
+fn spawn_traced(program: &Path, args: &[OsString]) -> Result<i32> {
+    let to_cstring = |x: &[u8]| {
+        CString::new(x).ok().context(DebugForKeySnafu {
+            msg: "nul byte in program or arguments",
+        })
+    };
+    let program_c = to_cstring(program.as_os_str().as_bytes())?;
+    let mut argv = vec![program_c.clone()];
+    for arg in args {
+        argv.push(to_cstring(arg.as_bytes())?);
+    }
+    let mut argv_ptrs = argv.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+    argv_ptrs.push(std::ptr::null());
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(io::Error::last_os_error()).context(PtraceSnafu { op: "fork" });
+    }
+    if pid == 0 {
+        // child: wait to be seized, then exec. Only async-signal-safe calls here.
+        unsafe {
+            libc::raise(libc::SIGSTOP);
+            libc::execv(program_c.as_ptr(), argv_ptrs.as_ptr());
+            libc::_exit(127);
+        }
+    }
+    let mut status = 0;
+    unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED) };
+    ensure!(
+        libc::WIFSTOPPED(status),
+        DebugForKeySnafu {
+            msg: "spawned process did not stop before exec",
+        }
+    );
+    seize(pid)?;
+    unsafe { libc::kill(pid, libc::SIGCONT) };
+    Ok(pid)
+}
+
+/// Seize every thread of `pid`, listing again until no new thread shows up.
+fn attach_all_threads(pid: i32) -> Result<()> {
+    let mut seized = HashSet::new();
+    loop {
+        let tids = fs::read_dir(format!("/proc/{}/task", pid))
+            .context(IoOpSnafu {
+                op: "list threads to attach",
+            })?
+            .filter_map(|x| x.ok()?.file_name().to_str()?.parse::<i32>().ok())
+            .filter(|x| !seized.contains(x))
+            .collect::<Vec<_>>();
+        if tids.is_empty() {
+            return Ok(());
+        }
+        for tid in tids {
+            seize(tid)?;
+            seized.insert(tid);
+        }
+    }
+}
+
+fn seize(tid: i32) -> Result<()> {
+    let options = libc::PTRACE_O_TRACECLONE;
+    ptrace(libc::PTRACE_SEIZE, tid, 0, options as usize)
+}
+
+/// Find where `name` is mapped in `pid`: the start address of its mapping at file offset 0, and its path.
+fn module_mapping(pid: i32, name: &str) -> Result<Option<(u64, PathBuf)>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).context(IoOpSnafu {
+        op: "read process maps",
+    })?;
+    // start-end perms offset dev inode path
+    Ok(maps.lines().find_map(|line| {
+        let mut fields = line.split_ascii_whitespace();
+        let range = fields.next()?;
+        let offset = fields.nth(1)?;
+        let path = fields.nth(2)?;
+        if u64::from_str_radix(offset, 16).ok()? != 0
+            || Path::new(path).file_name().is_none_or(|x| x != name)
+        {
+            return None;
+        }
+        let start = u64::from_str_radix(range.split_once('-')?.0, 16).ok()?;
+        Some((start, PathBuf::from(path)))
+    }))
+}
+
+fn tgid_of(tid: i32) -> Option<i32> {
+    fs::read_to_string(format!("/proc/{}/status", tid))
+        .ok()?
+        .lines()
+        .find_map(|x| x.strip_prefix("Tgid:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn ptrace(request: libc::c_uint, tid: i32, addr: usize, data: usize) -> Result<()> {
+    let r = unsafe { libc::ptrace(request, tid, addr, data) };
+    if r < 0 {
+        return Err(io::Error::last_os_error()).context(PtraceSnafu {
+            op: format!("ptrace request {} on {}", request, tid),
+        });
+    }
+    Ok(())
+}
+
+fn get_regs(tid: i32) -> Result<libc::user_regs_struct> {
+    let mut regs = unsafe { std::mem::zeroed::<libc::user_regs_struct>() };
+    ptrace(libc::PTRACE_GETREGS, tid, 0, &mut regs as *mut _ as usize)?;
+    Ok(regs)
+}
+
+fn set_regs(tid: i32, regs: &libc::user_regs_struct) -> Result<()> {
+    ptrace(libc::PTRACE_SETREGS, tid, 0, regs as *const _ as usize)
+}
@@ -0,0 +1,21 @@
+//! NTQQ for macOS runs sandboxed, and keeps the same per-account layout as on Linux in its container:
+//! `~/Library/Containers/com.tencent.qq/Data/Library/Application Support/QQ/nt_qq_<hash>/nt_db/nt_msg.db`,
+//! so discovery is shared with [super::linux].
+
+use super::*;
+
+/// The QQ data directory, relative to the home directory.
+pub const QQ_DATA_DIR: &str =
+    "Library/Containers/com.tencent.qq/Data/Library/Application Support/QQ";
+
+/// `~/Library/Containers/com.tencent.qq/Data/Library/Application Support/QQ`.
+pub fn qq_data_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|x| x.join(QQ_DATA_DIR))
+}
+
+pub fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
+    let Some(data_dir) = qq_data_dir() else {
+        return Ok(Vec::new());
+    };
+    linux::detect_db_file_in(&data_dir)
+}
@@ -1,15 +1,16 @@
 #[cfg(target_os = "windows")]
 mod debug_process;
 #[cfg(target_os = "windows")]
-pub use debug_process::{DebugInfo, debug_for_key};
+pub use debug_process::{DebugInfo, DebugTarget, debug_for_key};
 #[cfg(target_os = "windows")]
 mod env_detect;
 #[cfg(target_os = "windows")]
 pub(crate) use env_detect::detect_db_file;
 #[cfg(target_os = "windows")]
-pub use env_detect::{InstalledQQInfo, get_installed_qq};
+pub use env_detect::{InstalledQQInfo, find_qq_processes, get_installed_qq};
+#[cfg(target_os = "windows")]
 mod static_analysis;
-pub use static_analysis::TargetFunction;
+pub use super::analyzer::TargetFunction;
 
 use snafu::Snafu;
 
@@ -46,14 +47,6 @@ pub enum Error {
     Object {
         source: object::Error,
     },
-    #[snafu(transparent)]
-    Capstone {
-        source: capstone::Error,
-    },
-    #[snafu(display("find target function offset: {}", msg))]
-    FindTargetFunction {
-        msg: String,
-    },
     #[snafu(display("debug for key: {}", msg))]
     DebugForKey {
         msg: String,
@@ -4,6 +4,21 @@ pub struct DebugInfo {
     pub qq: InstalledQQInfo,
     /// Target function to set breakpoint on.
     pub func: TargetFunction,
+    pub target: DebugTarget,
+}
+
+/// The QQ process to extract the key from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugTarget {
+    /// Start a new `QQ.exe` under the debugger, and terminate it once the key is found.
+    /// The user has to log in in the new process, so any running QQ must be logged out first.
+    Launch,
+    /// Attach to a running QQ process, see [super::find_qq_processes], and detach once the key is found,
+    /// leaving it running.
+    ///
+    /// The key is caught on the next call to the key function, i.e. when QQ opens another database.
+    /// For a logged in QQ, that's usually when a feature backed by a not yet opened database is used.
+    Attach { pid: u32 },
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -17,6 +17,9 @@ use windows::core::PSTR;
 
 use super::*;
 
+/// The trap flag of EFLAGS, which single-steps the thread.
+const TRAP_FLAG: u32 = 0x100;
+
 /// Software breakpoint management structure.
 struct SoftwareBreakpoint {
     address: u64,
@@ -75,12 +78,18 @@ impl OwnedHandle {
 /// which should never happen in practice.
 #[allow(clippy::too_many_lines)]
 pub fn debug_for_key(info: &DebugInfo) -> crate::Result<DBDecryptInfo> {
-    let qq_exe = info.qq.install_dir.join("QQ.exe");
-    snafu::ensure!(qq_exe.is_file(), QQInstallationNotFoundSnafu);
-
-    let process_info = create_debug_process(&qq_exe)?;
-    let process_handle = OwnedHandle::new(process_info.hProcess)
-        .expect("process handle should be valid after successful CreateProcessA");
+    let (process_id, process_handle) = match info.target {
+        DebugTarget::Launch => {
+            let qq_exe = info.qq.install_dir.join("QQ.exe");
+            snafu::ensure!(qq_exe.is_file(), QQInstallationNotFoundSnafu);
+
+            let process_info = create_debug_process(&qq_exe)?;
+            let process_handle = OwnedHandle::new(process_info.hProcess)
+                .expect("process handle should be valid after successful CreateProcessA");
+            (process_info.dwProcessId, process_handle)
+        }
+        DebugTarget::Attach { pid } => (pid, attach_debug_process(pid)?),
+    };
 
     let mut debug_event = DEBUG_EVENT::default();
     let mut wrapper_base = 0u64;
@@ -88,10 +97,7 @@ pub fn debug_for_key(info: &DebugInfo) -> crate::Result<DBDecryptInfo> {
     let mut breakpoint: Option<SoftwareBreakpoint> = None;
     let mut stepping_threads: HashMap<u32, ThreadStepState> = HashMap::new();
 
-    info!(
-        "Starting debug loop for QQ process PID: {}",
-        process_info.dwProcessId
-    );
+    info!("Starting debug loop for QQ process PID: {}", process_id);
 
     loop {
         // Wait for debug event with 10 second timeout
@@ -116,11 +122,40 @@ pub fn debug_for_key(info: &DebugInfo) -> crate::Result<DBDecryptInfo> {
 
                 match result {
                     ExceptionResult::KeyFound(key) => {
-                        terminate_process(process_handle.as_raw())?;
-                        info!("Target terminated. Job done.");
+                        match info.target {
+                            DebugTarget::Launch => {
+                                terminate_process(process_handle.as_raw())?;
+                                info!("Target terminated. Job done.");
+                            }
+                            DebugTarget::Attach { pid } => {
+                                // every thread is suspended until the event is continued,
+                                // so none of them can be trapped again in between
+                                if let Some(bp) = &breakpoint {
+                                    restore_for_detach(
+                                        process_handle.as_raw(),
+                                        pid,
+                                        bp,
+                                        &stepping_threads,
+                                    )?;
+                                }
+                                unsafe {
+                                    ContinueDebugEvent(
+                                        debug_event.dwProcessId,
+                                        debug_event.dwThreadId,
+                                        DBG_CONTINUE,
+                                    )
+                                    .context(WindowsOpSnafu {
+                                        op: "continue debug event",
+                                    })?;
+                                }
+                                detach_debug_process(pid)?;
+                                info!("Target detached. Job done.");
+                            }
+                        }
                         return Ok(DBDecryptInfo {
                             key,
                             cipher_hmac_algorithm: None,
+                            kdf_iter: None,
                         });
                     }
                     ExceptionResult::Continue(status) => {
@@ -231,6 +266,110 @@ fn create_debug_process(qq_exe: &std::path::Path) -> Result<PROCESS_INFORMATION>
     Ok(process_info)
 }
 
+/// Attaches the debugger to a running process, which keeps running after the debugger detaches.
+fn attach_debug_process(pid: u32) -> Result<OwnedHandle> {
+    unsafe {
+        DebugActiveProcess(pid).context(WindowsOpSnafu {
+            op: "attach debugger to QQ process",
+        })?;
+        DebugSetProcessKillOnExit(false).context(WindowsOpSnafu {
+            op: "keep QQ process alive on debugger exit",
+        })?;
+    }
+    let handle = unsafe {
+        OpenProcess(PROCESS_ALL_ACCESS, false, pid).context(WindowsOpSnafu {
+            op: "open QQ process",
+        })?
+    };
+    OwnedHandle::new(handle).context(DebugForKeySnafu {
+        msg: "invalid process handle",
+    })
+}
+
+/// Leave no trace of the debugger in the attached process, which runs on without it: restore the original byte,
+/// and on every thread clear the trap flag, and rewind to the original instruction if the thread hit the breakpoint
+/// but the exception is not reported yet. Either would otherwise crash the process once detached.
+///
+/// Must be called while the process is suspended for a debug event.
+fn restore_for_detach(
+    h_process: HANDLE,
+    pid: u32,
+    bp: &SoftwareBreakpoint,
+    stepping_threads: &HashMap<u32, ThreadStepState>,
+) -> Result<()> {
+    write_remote_memory(h_process, bp.address, &[bp.original_byte])?;
+    for thread_id in process_threads(pid)? {
+        let h_thread = match open_thread(thread_id) {
+            Ok(x) => x,
+            Err(e) => {
+                // the thread may have just exited
+                debug!("Failed to open thread {}: {}", thread_id, e);
+                continue;
+            }
+        };
+        let mut ctx = get_thread_context(h_thread.as_raw())?;
+        let stepping = ctx.EFlags & TRAP_FLAG != 0;
+        // a thread stepping over the original instruction may be right after it, if it's one byte long
+        let hit =
+            !stepping_threads.contains_key(&thread_id) && ctx.Rip == bp.address.wrapping_add(1);
+        if !stepping && !hit {
+            continue;
+        }
+        ctx.EFlags &= !TRAP_FLAG;
+        if hit {
+            ctx.Rip = bp.address;
+        }
+        set_thread_context(h_thread.as_raw(), &ctx)?;
+        debug!(
+            "Thread {} restored for detach, stepping: {}, hit: {}",
+            thread_id, stepping, hit
+        );
+    }
+    Ok(())
+}
+
+/// Ids of the threads of a process.
+fn process_threads(pid: u32) -> Result<Vec<u32>> {
+    let snapshot = unsafe {
+        CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0).context(WindowsOpSnafu {
+            op: "create toolhelp snapshot",
+        })?
+    };
+
+    let _snapshot_guard = OwnedHandle::new(snapshot);
+
+    let mut entry = THREADENTRY32 {
+        dwSize: u32::try_from(size_of::<THREADENTRY32>()).expect("THREADENTRY32 size fits in u32"),
+        ..Default::default()
+    };
+
+    let mut threads = Vec::new();
+    unsafe {
+        if Thread32First(snapshot, &mut entry).is_ok() {
+            loop {
+                if entry.th32OwnerProcessID == pid {
+                    threads.push(entry.th32ThreadID);
+                }
+
+                if Thread32Next(snapshot, &mut entry).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(threads)
+}
+
+/// Detaches the debugger from an attached process.
+fn detach_debug_process(pid: u32) -> Result<()> {
+    unsafe {
+        DebugActiveProcessStop(pid).context(WindowsOpSnafu {
+            op: "detach debugger from QQ process",
+        })?;
+    }
+    Ok(())
+}
+
 /// Handles an exception debug event.
 fn handle_exception_event(
     debug_event: &DEBUG_EVENT,
@@ -297,10 +436,10 @@ fn handle_breakpoint(
         Ok(ref s) if s.is_ascii() && s.len() == 16 => {
             info!("Found target key: {}", s);
 
-            // Restore breakpoint before termination
-            if let Err(e) = write_remote_memory(h_process, bp.address, &[0xCC]) {
-                error!("Failed to restore breakpoint before termination: {}", e);
-            }
+            // The original byte is restored above, rewind so that the process can go on
+            // in case it's detached instead of terminated.
+            set_thread_context(h_thread.as_raw(), &ctx)?;
+            bp.is_active = false;
 
             Ok(ExceptionResult::KeyFound(s.clone()))
         }
@@ -308,7 +447,7 @@ fn handle_breakpoint(
             debug!("Non-target call with R8 string: {}", unwanted);
 
             // Set single-step flag
-            ctx.EFlags |= 0x100; // TF flag
+            ctx.EFlags |= TRAP_FLAG;
 
             set_thread_context(h_thread.as_raw(), &ctx)?;
 
@@ -349,7 +488,7 @@ fn handle_single_step(
     let mut ctx = get_thread_context(h_thread.as_raw())?;
 
     // Clear single-step flag
-    ctx.EFlags &= !0x100;
+    ctx.EFlags &= !TRAP_FLAG;
     set_thread_context(h_thread.as_raw(), &ctx)?;
 
     // Restore breakpoint
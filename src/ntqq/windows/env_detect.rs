@@ -24,8 +24,8 @@ pub(crate) fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
             if db_path.is_file() {
                 Some(UserDBFile {
                     path: db_path,
-                    uid: None,
                     uin: Some(uin),
+                    ..Default::default()
                 })
             } else {
                 None
@@ -35,6 +35,75 @@ pub(crate) fn detect_db_file() -> crate::Result<Vec<UserDBFile>> {
     Ok(files)
 }
 
+/// Find running `QQ.exe` processes that have `wrapper.node` loaded, i.e. the main process of NTQQ,
+/// to be used with [super::DebugTarget::Attach].
+pub fn find_qq_processes() -> crate::Result<Vec<u32>> {
+    use std::ffi::CStr;
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::System::Diagnostics::ToolHelp::*;
+
+    let snapshot = unsafe {
+        CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0).context(WindowsOpSnafu {
+            op: "create process snapshot",
+        })?
+    };
+    let mut entry = PROCESSENTRY32 {
+        dwSize: std::mem::size_of::<PROCESSENTRY32>() as u32,
+        ..Default::default()
+    };
+    let mut pids = Vec::new();
+    unsafe {
+        if Process32First(snapshot, &mut entry).is_ok() {
+            loop {
+                let exe = CStr::from_ptr(entry.szExeFile.as_ptr()).to_string_lossy();
+                if exe.eq_ignore_ascii_case("QQ.exe")
+                    && has_module(entry.th32ProcessID, "wrapper.node")
+                {
+                    pids.push(entry.th32ProcessID);
+                }
+                if Process32Next(snapshot, &mut entry).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = CloseHandle(snapshot);
+    }
+    Ok(pids)
+}
+
+fn has_module(pid: u32, name: &str) -> bool {
+    use std::ffi::CStr;
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::System::Diagnostics::ToolHelp::*;
+
+    let Ok(snapshot) =
+        (unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPMODULE | TH32CS_SNAPMODULE32, pid) })
+    else {
+        return false;
+    };
+    let mut entry = MODULEENTRY32 {
+        dwSize: std::mem::size_of::<MODULEENTRY32>() as u32,
+        ..Default::default()
+    };
+    let mut found = false;
+    unsafe {
+        if Module32First(snapshot, &mut entry).is_ok() {
+            loop {
+                let module = CStr::from_ptr(entry.szModule.as_ptr()).to_string_lossy();
+                if module.eq_ignore_ascii_case(name) {
+                    found = true;
+                    break;
+                }
+                if Module32Next(snapshot, &mut entry).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = CloseHandle(snapshot);
+    }
+    found
+}
+
 #[derive(Debug)]
 pub struct InstalledQQInfo {
     pub install_dir: PathBuf,
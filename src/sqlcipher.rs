@@ -0,0 +1,470 @@
+//! Pure-Rust decryption of NTQQ's SQLCipher databases, without going through SQLite at all.
+//!
+//! NTQQ uses SQLCipher 4 with non-default parameters (see [DBDecryptInfo]):
+//!
+//! - key: PBKDF2-HMAC-SHA512 of the passphrase with the 16-byte salt stored at the start of page 1, 4000 iterations
+//!   unless [DBDecryptInfo::kdf_iter] says otherwise.
+//! - hmac key: PBKDF2-HMAC-SHA512 of the key with the salt XOR `0x3a`, 2 iterations.
+//! - every 4096-byte page is encrypted with AES-256-CBC. Each page ends with a reserved area of
+//!   the IV and the HMAC (SHA1 or SHA256) of `ciphertext || IV || page number (LE)`, padded to the AES block size.
+//! - page 1 leaves its first 16 bytes, the salt, unencrypted.
+//!
+//! The NTQQ 1024-byte header in front of the SQLCipher data is skipped if present.
+
+use crate::db::NtDbHeader;
+use crate::ntqq::DBDecryptInfo;
+use aes::cipher::{BlockDecryptMut, KeyIvInit, block_padding::NoPadding};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use snafu::prelude::*;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+pub const PAGE_SIZE: usize = DBDecryptInfo::CIPHER_PAGE_SIZE;
+pub const KDF_ITER: u32 = DBDecryptInfo::KDF_ITER as u32;
+pub const SALT_SIZE: usize = 16;
+/// Length of the NTQQ header in front of the SQLCipher data.
+pub const NTQQ_HEADER_SIZE: usize = NtDbHeader::LEN;
+const IV_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const AES_BLOCK_SIZE: usize = 16;
+const HMAC_SALT_MASK: u8 = 0x3a;
+const FAST_KDF_ITER: u32 = 2;
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HmacAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+impl HmacAlgorithm {
+    /// The algorithms NTQQ is known to use, in the order they should be tried.
+    pub const NTQQ: [HmacAlgorithm; 2] = [HmacAlgorithm::Sha256, HmacAlgorithm::Sha1];
+
+    pub fn digest_size(self) -> usize {
+        match self {
+            HmacAlgorithm::Sha1 => 20,
+            HmacAlgorithm::Sha256 => 32,
+            HmacAlgorithm::Sha512 => 64,
+        }
+    }
+    /// Size of the reserved area at the end of each page, i.e. IV and HMAC rounded up to the AES block size.
+    pub fn reserve_size(self) -> usize {
+        (IV_SIZE + self.digest_size()).div_ceil(AES_BLOCK_SIZE) * AES_BLOCK_SIZE
+    }
+}
+impl fmt::Display for HmacAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HmacAlgorithm::Sha1 => "HMAC_SHA1",
+            HmacAlgorithm::Sha256 => "HMAC_SHA256",
+            HmacAlgorithm::Sha512 => "HMAC_SHA512",
+        })
+    }
+}
+impl FromStr for HmacAlgorithm {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "HMAC_SHA1" => Ok(HmacAlgorithm::Sha1),
+            "HMAC_SHA256" => Ok(HmacAlgorithm::Sha256),
+            "HMAC_SHA512" => Ok(HmacAlgorithm::Sha512),
+            _ => UnknownHmacAlgorithmSnafu { name: s }.fail(),
+        }
+    }
+}
+
+/// Decrypts and verifies the pages of one database.
+pub struct PageCipher {
+    key: [u8; KEY_SIZE],
+    hmac_key: [u8; KEY_SIZE],
+    hmac: HmacAlgorithm,
+}
+impl PageCipher {
+    /// Derive the keys from the passphrase and the salt, i.e. the first 16 bytes of page 1,
+    /// with `kdf_iter` PBKDF2 iterations, normally [KDF_ITER].
+    ///
+    /// This runs the full KDF, so it's slow by design. Reuse the result for every page.
+    pub fn new(
+        passphrase: &[u8],
+        salt: &[u8; SALT_SIZE],
+        kdf_iter: u32,
+        hmac: HmacAlgorithm,
+    ) -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        pbkdf2::pbkdf2_hmac::<Sha512>(passphrase, salt, kdf_iter, &mut key);
+        Self::from_key(key, salt, hmac)
+    }
+    /// Same as [PageCipher::new], but from an already derived key.
+    pub fn from_key(key: [u8; KEY_SIZE], salt: &[u8; SALT_SIZE], hmac: HmacAlgorithm) -> Self {
+        let hmac_salt = salt.map(|x| x ^ HMAC_SALT_MASK);
+        let mut hmac_key = [0u8; KEY_SIZE];
+        pbkdf2::pbkdf2_hmac::<Sha512>(&key, &hmac_salt, FAST_KDF_ITER, &mut hmac_key);
+        Self {
+            key,
+            hmac_key,
+            hmac,
+        }
+    }
+    pub fn key(&self) -> &[u8; KEY_SIZE] {
+        &self.key
+    }
+    pub fn hmac_algorithm(&self) -> HmacAlgorithm {
+        self.hmac
+    }
+
+    /// Check the HMAC of an encrypted page. `pgno` starts from 1.
+    pub fn verify_page(&self, pgno: u32, page: &[u8]) -> bool {
+        if page.len() != PAGE_SIZE {
+            return false;
+        }
+        let (begin, end) = self.encrypted_range(pgno);
+        // the hmac covers the ciphertext and the iv following it
+        let input: [&[u8]; 2] = [&page[begin..end + IV_SIZE], &pgno.to_le_bytes()];
+        let expected = &page[end + IV_SIZE..end + IV_SIZE + self.hmac.digest_size()];
+        match self.hmac {
+            HmacAlgorithm::Sha1 => {
+                verify_hmac::<Hmac<sha1::Sha1>>(&self.hmac_key, &input, expected)
+            }
+            HmacAlgorithm::Sha256 => {
+                verify_hmac::<Hmac<sha2::Sha256>>(&self.hmac_key, &input, expected)
+            }
+            HmacAlgorithm::Sha512 => verify_hmac::<Hmac<Sha512>>(&self.hmac_key, &input, expected),
+        }
+    }
+
+    /// Verify and decrypt a page into a plain SQLite page of the same size.
+    ///
+    /// The reserved area is zeroed, and page 1 gets the SQLite magic in place of the salt.
+    pub fn decrypt_page(&self, pgno: u32, page: &[u8], out: &mut [u8]) -> Result<()> {
+        ensure!(
+            page.len() == PAGE_SIZE && out.len() == PAGE_SIZE,
+            TruncatedPageSnafu { pgno }
+        );
+        ensure!(self.verify_page(pgno, page), HmacMismatchSnafu { pgno });
+        let (begin, end) = self.encrypted_range(pgno);
+        let iv = &page[end..end + IV_SIZE];
+        cbc::Decryptor::<aes::Aes256>::new((&self.key).into(), iv.into())
+            .decrypt_padded_b2b_mut::<NoPadding>(&page[begin..end], &mut out[begin..end])
+            .map_err(|_| HmacMismatchSnafu { pgno }.build())?;
+        out[end..].fill(0);
+        if pgno == 1 {
+            out[..SALT_SIZE].copy_from_slice(SQLITE_HEADER);
+        }
+        Ok(())
+    }
+
+    /// Range of the page covered by the cipher: from after the salt (on page 1) to the reserved area.
+    fn encrypted_range(&self, pgno: u32) -> (usize, usize) {
+        let begin = if pgno == 1 { SALT_SIZE } else { 0 };
+        (begin, PAGE_SIZE - self.hmac.reserve_size())
+    }
+}
+
+fn verify_hmac<M: Mac + hmac::digest::KeyInit>(
+    key: &[u8],
+    input: &[&[u8]],
+    expected: &[u8],
+) -> bool {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("hmac accepts keys of any size");
+    for x in input {
+        mac.update(x);
+    }
+    mac.verify_slice(expected).is_ok()
+}
+
+/// Split off the NTQQ header, if any, and return the SQLCipher data.
+pub fn strip_ntqq_header(bytes: &[u8]) -> &[u8] {
+    match NtDbHeader::parse(bytes) {
+        Ok(header) if bytes.len() >= NTQQ_HEADER_SIZE => &bytes[header.data_offset() as usize..],
+        _ => bytes,
+    }
+}
+
+/// Find the [PageCipher] that verifies page 1, trying every algorithm of [HmacAlgorithm::NTQQ]
+/// unless `d` names one. The key is derived with [DBDecryptInfo::kdf_iter].
+///
+/// `page1` is the first page of SQLCipher data, i.e. after the NTQQ header.
+pub fn cipher_for_page1(page1: &[u8], d: &DBDecryptInfo) -> Result<PageCipher> {
+    ensure!(page1.len() >= PAGE_SIZE, TruncatedPageSnafu { pgno: 1u32 });
+    let candidates = match &d.cipher_hmac_algorithm {
+        Some(algo) => vec![algo.parse()?],
+        None => HmacAlgorithm::NTQQ.to_vec(),
+    };
+    let salt: &[u8; SALT_SIZE] = page1[..SALT_SIZE].try_into().unwrap();
+    let mut key = None;
+    for hmac in candidates {
+        let cipher = match key {
+            // the KDF does not depend on the hmac algorithm, only derive it once
+            Some(key) => PageCipher::from_key(key, salt, hmac),
+            None => PageCipher::new(d.key.as_bytes(), salt, d.kdf_iter() as u32, hmac),
+        };
+        key = Some(cipher.key);
+        if cipher.verify_page(1, &page1[..PAGE_SIZE]) {
+            return Ok(cipher);
+        }
+        log::debug!("page 1 hmac mismatch with {}", hmac);
+    }
+    WrongDecryptInfoSnafu.fail()
+}
+
+/// Result of [verify_key].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// Page 1 verifies with this hmac algorithm.
+    Match(HmacAlgorithm),
+    /// No hmac algorithm verifies page 1.
+    WrongKey,
+    /// The file is too short to hold page 1,
+    /// or page 1 verifies but does not decrypt into a valid SQLite header.
+    Corrupt,
+}
+
+/// Check `d` against the first page of a database, without opening it with SQLite.
+///
+/// `bytes` is the start of the database file (with or without the NTQQ header), at least up to the end of page 1.
+/// The key is derived only once, and every algorithm of [HmacAlgorithm::NTQQ] is checked unless `d` names one.
+pub fn verify_key(bytes: &[u8], d: &DBDecryptInfo) -> Result<KeyCheck> {
+    let Some(page1) = strip_ntqq_header(bytes).get(..PAGE_SIZE) else {
+        return Ok(KeyCheck::Corrupt);
+    };
+    let cipher = match cipher_for_page1(page1, d) {
+        Ok(cipher) => cipher,
+        Err(Error::WrongDecryptInfo) => return Ok(KeyCheck::WrongKey),
+        Err(e) => return Err(e),
+    };
+    let mut plain = vec![0u8; PAGE_SIZE];
+    cipher.decrypt_page(1, page1, &mut plain)?;
+    // page size (big endian), reserved bytes per page and the fixed payload fractions 64/32/32.
+    // SQLCipher may reserve more than it uses, e.g. when the hmac algorithm is changed after the key is set.
+    let page_size = u16::from_be_bytes([plain[16], plain[17]]) as usize;
+    let reserved = plain[20] as usize;
+    if page_size == PAGE_SIZE
+        && reserved >= cipher.hmac_algorithm().reserve_size()
+        && plain[21..24] == [64, 32, 32]
+    {
+        Ok(KeyCheck::Match(cipher.hmac_algorithm()))
+    } else {
+        Ok(KeyCheck::Corrupt)
+    }
+}
+
+/// [verify_key] on a file, reading only the header and page 1.
+pub fn verify_key_file(path: impl AsRef<Path>, d: &DBDecryptInfo) -> Result<KeyCheck> {
+    let mut file = fs::File::open(path).context(IoSnafu { op: "open db" })?;
+    let mut buf = vec![0u8; NTQQ_HEADER_SIZE + PAGE_SIZE];
+    let n = read_full(&mut file, &mut buf).context(IoSnafu { op: "read page 1" })?;
+    verify_key(&buf[..n], d)
+}
+
+/// Decrypt a whole database read from `reader` into a plain SQLite database written to `writer`.
+///
+/// Returns the hmac algorithm that worked.
+pub fn decrypt_db(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    d: &DBDecryptInfo,
+) -> Result<HmacAlgorithm> {
+    let mut head = vec![0u8; NTQQ_HEADER_SIZE];
+    let n = read_full(&mut reader, &mut head).context(IoSnafu {
+        op: "read db header",
+    })?;
+    head.truncate(n);
+    let mut page = vec![0u8; PAGE_SIZE];
+    let leftover = if strip_ntqq_header(&head).len() < head.len() {
+        &[][..]
+    } else {
+        &head[..]
+    };
+    page[..leftover.len()].copy_from_slice(leftover);
+    let n = read_full(&mut reader, &mut page[leftover.len()..])
+        .context(IoSnafu { op: "read page 1" })?;
+    ensure!(
+        leftover.len() + n == PAGE_SIZE,
+        TruncatedPageSnafu { pgno: 1u32 }
+    );
+
+    let cipher = cipher_for_page1(&page, d)?;
+    let mut out = vec![0u8; PAGE_SIZE];
+    let mut pgno = 1u32;
+    loop {
+        cipher.decrypt_page(pgno, &page, &mut out)?;
+        writer.write_all(&out).context(IoSnafu {
+            op: "write plain page",
+        })?;
+        pgno += 1;
+        match read_full(&mut reader, &mut page).context(IoSnafu { op: "read page" })? {
+            0 => break,
+            PAGE_SIZE => continue,
+            _ => return TruncatedPageSnafu { pgno }.fail(),
+        }
+    }
+    writer.flush().context(IoSnafu { op: "flush output" })?;
+    Ok(cipher.hmac_algorithm())
+}
+
+/// [decrypt_db] from file to file.
+pub fn decrypt_file(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    d: &DBDecryptInfo,
+) -> Result<HmacAlgorithm> {
+    let reader = fs::File::open(input).context(IoSnafu {
+        op: "open encrypted db",
+    })?;
+    let writer = fs::File::create(output).context(IoSnafu {
+        op: "create plain db",
+    })?;
+    decrypt_db(io::BufReader::new(reader), io::BufWriter::new(writer), d)
+}
+
+/// Like [Read::read_exact], but stops at EOF and returns the number of bytes read.
+pub(crate) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(x) => n += x,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("IO operation to {}: {}", op, source))]
+    Io { source: io::Error, op: String },
+    #[snafu(display("unknown hmac algorithm: {}", name))]
+    UnknownHmacAlgorithm { name: String },
+    #[snafu(display("page {} is truncated", pgno))]
+    TruncatedPage { pgno: u32 },
+    #[snafu(display("hmac check failed for page {}", pgno))]
+    HmacMismatch { pgno: u32 },
+    #[snafu(display("no hmac algorithm verifies page 1, the key is likely wrong"))]
+    WrongDecryptInfo,
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncryptMut;
+
+    // computed independently with python's hashlib, hmac and cryptography:
+    // pbkdf2_hmac('sha512', PASSPHRASE, SALT, 4000, 32), etc.
+    const PASSPHRASE: &[u8] = b"ntdb_unwrap";
+    const SALT: [u8; SALT_SIZE] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    const KEY: &str = "2948fcea14d7895ad15b9ae81b1289cfe8f96b13727a8a3416e3f93959446623";
+    const HMAC_KEY: &str = "9c80b90e4fd0ce1f339e49bb538da3ac76ae73ab3b45ccc1cdd274ee52384ec9";
+    /// AES-256-CBC of [plain_page] with [KEY] and [IV], the first and the last block.
+    const CIPHERTEXT_FIRST: &str = "a623a51af41713b05fc1cfc6747197c0";
+    const CIPHERTEXT_LAST: &str = "a3a7a8d53f32403a57af345ac17f4a74";
+    const IV: [u8; IV_SIZE] = [
+        0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad, 0xae,
+        0xaf,
+    ];
+    /// HMAC of `ciphertext || IV || 2u32.to_le_bytes()`, i.e. of page 2.
+    const HMAC_SHA1: &str = "ae21a0ef4fe365b87485c756c4046b3c8273cac7";
+    const HMAC_SHA256: &str = "4cf9cec3cdb514fe04274a3e4da2381aa193c129e30b587a485962e7487076ad";
+
+    /// Both SHA1 and SHA256 reserve 48 bytes, so the same plain data fits either.
+    const ENCRYPTED_LEN: usize = PAGE_SIZE - 48;
+
+    fn plain_page() -> Vec<u8> {
+        (0..ENCRYPTED_LEN).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    /// Page 2 encrypted with [KEY] and [IV], with `mac` as its HMAC.
+    fn encrypted_page(mac: &str) -> Vec<u8> {
+        let key = hex::decode(KEY).unwrap();
+        let mut page = vec![0u8; PAGE_SIZE];
+        cbc::Encryptor::<aes::Aes256>::new(key.as_slice().into(), (&IV).into())
+            .encrypt_padded_b2b_mut::<NoPadding>(&plain_page(), &mut page[..ENCRYPTED_LEN])
+            .unwrap();
+        page[ENCRYPTED_LEN..ENCRYPTED_LEN + IV_SIZE].copy_from_slice(&IV);
+        let mac = hex::decode(mac).unwrap();
+        page[ENCRYPTED_LEN + IV_SIZE..ENCRYPTED_LEN + IV_SIZE + mac.len()].copy_from_slice(&mac);
+        page
+    }
+
+    #[test]
+    fn kdf() {
+        let cipher = PageCipher::new(PASSPHRASE, &SALT, KDF_ITER, HmacAlgorithm::Sha1);
+        assert_eq!(hex::encode(cipher.key()), KEY);
+        assert_eq!(hex::encode(cipher.hmac_key), HMAC_KEY);
+    }
+
+    #[test]
+    fn aes_cbc() {
+        let page = encrypted_page(HMAC_SHA1);
+        assert_eq!(hex::encode(&page[..16]), CIPHERTEXT_FIRST);
+        assert_eq!(
+            hex::encode(&page[ENCRYPTED_LEN - 16..ENCRYPTED_LEN]),
+            CIPHERTEXT_LAST
+        );
+    }
+
+    #[test]
+    fn reserve_size() {
+        assert_eq!(HmacAlgorithm::Sha1.reserve_size(), 48);
+        assert_eq!(HmacAlgorithm::Sha256.reserve_size(), 48);
+        assert_eq!(HmacAlgorithm::Sha512.reserve_size(), 80);
+    }
+
+    #[test]
+    fn decrypt_page() {
+        let key = hex::decode(KEY).unwrap().try_into().unwrap();
+        for (hmac, mac) in [
+            (HmacAlgorithm::Sha1, HMAC_SHA1),
+            (HmacAlgorithm::Sha256, HMAC_SHA256),
+        ] {
+            let cipher = PageCipher::from_key(key, &SALT, hmac);
+            let page = encrypted_page(mac);
+            assert!(cipher.verify_page(2, &page), "{}", hmac);
+            // the page number is part of the hmac
+            assert!(!cipher.verify_page(3, &page), "{}", hmac);
+
+            let mut out = vec![0xffu8; PAGE_SIZE];
+            cipher.decrypt_page(2, &page, &mut out).unwrap();
+            assert_eq!(out[..ENCRYPTED_LEN], plain_page()[..], "{}", hmac);
+            assert!(out[ENCRYPTED_LEN..].iter().all(|&x| x == 0), "{}", hmac);
+
+            let mut tampered = page.clone();
+            tampered[100] ^= 1;
+            assert!(matches!(
+                cipher.decrypt_page(2, &tampered, &mut out),
+                Err(Error::HmacMismatch { pgno: 2 })
+            ));
+        }
+    }
+
+    #[test]
+    fn strip_header() {
+        let mut bytes = vec![0u8; NTQQ_HEADER_SIZE + PAGE_SIZE];
+        assert_eq!(strip_ntqq_header(&bytes).len(), bytes.len());
+        bytes[NtDbHeader::TAG_RANGE].copy_from_slice(NtDbHeader::TAG);
+        assert_eq!(strip_ntqq_header(&bytes).len(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn hmac_algorithm_names() {
+        for algo in [
+            HmacAlgorithm::Sha1,
+            HmacAlgorithm::Sha256,
+            HmacAlgorithm::Sha512,
+        ] {
+            assert_eq!(algo.to_string().parse::<HmacAlgorithm>().unwrap(), algo);
+        }
+        assert_eq!(
+            "hmac_sha1".parse::<HmacAlgorithm>().unwrap(),
+            HmacAlgorithm::Sha1
+        );
+        assert!("HMAC_MD5".parse::<HmacAlgorithm>().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! [debug_for_key] against a stand-in QQ: this same executable, run as `debug_process stand-in <wrapper.node>`,
+//! which maps a `wrapper.node` with a function of the `nt_sqlite3_key_v2` signature and keeps calling it.
+//!
+//! Hence no libtest harness, see `Cargo.toml`. The tests run one after another,
+//! as the tracer waits for any child process.
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn main() {
+    linux::main()
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn main() {}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod linux {
+    use ntdb_unwrap::ntqq;
+    use ntdb_unwrap::ntqq::analyzer::TargetFunction;
+    use ntdb_unwrap::ntqq::linux::*;
+    use std::ffi::{OsString, c_char, c_int, c_void};
+    use std::os::fd::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    /// Where the key function is in the stand-in `wrapper.node`.
+    const FUNCTION_OFFSET: u64 = 0x10;
+    const KEY: &str = "fIxTuRe0kEy!2345";
+    /// The key of another database, which is not 16 bytes like the one of QQ.
+    const OTHER_KEY: &[u8] = b"not the key we are looking for";
+    /// How long the stand-in keeps calling the function.
+    const STAND_IN_DURATION: Duration = Duration::from_secs(2);
+
+    /// `int64 f(void *db, const char *zDb, const void *key, int nKey)`
+    type KeyFn = unsafe extern "C" fn(*const c_void, *const c_char, *const c_void, c_int) -> i64;
+
+    pub fn main() {
+        let args = std::env::args_os().skip(1).collect::<Vec<_>>();
+        match args.first().and_then(|x| x.to_str()) {
+            Some("stand-in") => return stand_in(Path::new(&args[1])),
+            Some("exit") => return,
+            _ => {}
+        }
+        let tests: [(&str, fn()); 3] = [("spawn", spawn), ("attach", attach), ("exited", exited)];
+        for (name, test) in tests {
+            print!("test {name} ... ");
+            test();
+            println!("ok");
+        }
+    }
+
+    /// Map `wrapper_node` and call its function for [STAND_IN_DURATION], with [OTHER_KEY] then [KEY].
+    fn stand_in(wrapper_node: &Path) {
+        let file = std::fs::File::open(wrapper_node).unwrap();
+        let len = file.metadata().unwrap().len() as usize;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(base, libc::MAP_FAILED);
+        let f: KeyFn = unsafe { std::mem::transmute(base.add(FUNCTION_OFFSET as usize)) };
+        let deadline = Instant::now() + STAND_IN_DURATION;
+        while Instant::now() < deadline {
+            for key in [OTHER_KEY, KEY.as_bytes()] {
+                let r = unsafe {
+                    f(
+                        std::ptr::null(),
+                        c"main".as_ptr(),
+                        key.as_ptr().cast(),
+                        key.len() as c_int,
+                    )
+                };
+                assert_eq!(r, 0);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// A `wrapper.node` in `dir`, with `xor eax, eax; ret` at [FUNCTION_OFFSET].
+    fn wrapper_node(dir: &Path) -> PathBuf {
+        let mut code = vec![0xCC; FUNCTION_OFFSET as usize];
+        code.extend([0x31, 0xC0, 0xC3]);
+        let path = dir.join(WRAPPER_NODE);
+        std::fs::write(&path, code).unwrap();
+        path
+    }
+
+    fn debug_info(target: DebugTarget) -> DebugInfo {
+        DebugInfo {
+            target,
+            func: TargetFunction {
+                function_offset: FUNCTION_OFFSET,
+                lea_instr_offset: 0,
+            },
+        }
+    }
+
+    fn stand_in_args(wrapper_node: &Path) -> Vec<OsString> {
+        vec!["stand-in".into(), wrapper_node.into()]
+    }
+
+    fn spawn() {
+        let dir = tempfile::tempdir().unwrap();
+        let wrapper_node = wrapper_node(dir.path());
+        let info = debug_info(DebugTarget::Spawn {
+            program: std::env::current_exe().unwrap(),
+            args: stand_in_args(&wrapper_node),
+        });
+        let d = debug_for_key(&info).unwrap();
+        assert_eq!(d.key, KEY);
+
+        // detached and still running, until it's done calling
+        let (pid, path) = find_qq_processes()
+            .into_iter()
+            .find(|(_, path)| path == &wrapper_node)
+            .unwrap();
+        assert_eq!(path, wrapper_node);
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    fn attach() {
+        let dir = tempfile::tempdir().unwrap();
+        let wrapper_node = wrapper_node(dir.path());
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(stand_in_args(&wrapper_node))
+            .spawn()
+            .unwrap();
+        let info = debug_info(DebugTarget::Attach {
+            pid: child.id() as i32,
+        });
+        let d = debug_for_key(&info).unwrap();
+        assert_eq!(d.key, KEY);
+        assert!(child.wait().unwrap().success());
+    }
+
+    fn exited() {
+        let info = debug_info(DebugTarget::Spawn {
+            program: std::env::current_exe().unwrap(),
+            args: vec!["exit".into()],
+        });
+        let e = debug_for_key(&info).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                ntdb_unwrap::Error::NTQQ {
+                    source: ntqq::Error::Linux {
+                        source: Error::ProcessExited { .. }
+                    }
+                }
+            ),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,420 @@
+//! Decryption, the offset vfs and model parsing on databases made by [ntdb_unwrap::db::fixture].
+
+use ntdb_unwrap::db::fixture::{FixtureMessage, NtDbFixture};
+use ntdb_unwrap::db::model::{GroupMsgTable, Model};
+use ntdb_unwrap::db::{self, NtDbSet};
+use ntdb_unwrap::ntqq::DBDecryptInfo;
+use ntdb_unwrap::sqlcipher::{self, HmacAlgorithm, KeyCheck};
+use rusqlite::{Connection, OpenFlags};
+use std::fs;
+use std::path::Path;
+
+fn open_with_vfs(path: &Path, flags: OpenFlags, vfs: &str) -> Connection {
+    db::register_offset_vfs().unwrap();
+    Connection::open_with_flags_and_vfs(path, flags, vfs).unwrap()
+}
+
+fn open(path: &Path) -> Connection {
+    open_with_vfs(path, OpenFlags::SQLITE_OPEN_READ_WRITE, db::OFFSET_VFS_NAME)
+}
+
+fn rows(conn: &Connection, table: &str) -> Vec<GroupMsgTable> {
+    let mut stmt = conn
+        .prepare(&format!("SELECT * FROM {} ORDER BY \"40003\"", table))
+        .unwrap();
+    GroupMsgTable::parse_rows(&mut stmt.query([]).unwrap()).unwrap()
+}
+
+fn texts(rows: &[GroupMsgTable]) -> Vec<String> {
+    rows.iter()
+        .map(|x| {
+            x.message
+                .as_ref()
+                .unwrap()
+                .messages
+                .iter()
+                .map(|x| x.messageText.as_str())
+                .collect()
+        })
+        .collect()
+}
+
+fn without_hmac(d: DBDecryptInfo) -> DBDecryptInfo {
+    DBDecryptInfo {
+        cipher_hmac_algorithm: None,
+        ..d
+    }
+}
+
+#[test]
+fn decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    for hmac in HmacAlgorithm::NTQQ {
+        let fixture = NtDbFixture::sample().cipher_hmac_algorithm(hmac.to_string());
+        let path = dir.path().join(format!("{}.db", hmac));
+        fixture.write(&path).unwrap();
+        let d = without_hmac(fixture.decrypt_info());
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes[..db::NtDbHeader::LEN], fixture.header()[..]);
+        assert_eq!(
+            sqlcipher::verify_key(&bytes, &d).unwrap(),
+            KeyCheck::Match(hmac)
+        );
+
+        let conn = open(&path);
+        let found = db::try_decrypt_db_info(&conn, d.clone()).unwrap();
+        assert_eq!(found.cipher_hmac_algorithm, Some(hmac.to_string()));
+        let expected = texts(&rows(&conn, "group_msg_table"));
+        assert_eq!(expected, ["大家好", "你好", "", ""]);
+
+        let plain = dir.path().join(format!("{}.plain.db", hmac));
+        assert_eq!(sqlcipher::decrypt_file(&path, &plain, &d).unwrap(), hmac);
+        let plain = Connection::open(&plain).unwrap();
+        assert_eq!(texts(&rows(&plain, "group_msg_table")), expected);
+        assert_eq!(texts(&rows(&plain, "c2c_msg_table")), ["在吗", "在"]);
+    }
+}
+
+#[test]
+fn wrong_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nt_msg.db");
+    let fixture = NtDbFixture::sample();
+    fixture.write(&path).unwrap();
+    let wrong = without_hmac(NtDbFixture::new().rand("AnotherRand").decrypt_info());
+
+    assert_eq!(
+        sqlcipher::verify_key_file(&path, &wrong).unwrap(),
+        KeyCheck::WrongKey
+    );
+    assert!(db::try_decrypt_db(&open(&path), wrong).is_err());
+}
+
+#[test]
+fn cipher_defaults_restored() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nt_msg.db");
+    let fixture = NtDbFixture::sample();
+    fixture.write(&path).unwrap();
+    let conn = Connection::open_in_memory().unwrap();
+    let kdf_iter = |conn: &Connection| -> String {
+        conn.query_row("PRAGMA cipher_default_kdf_iter;", [], |row| row.get(0))
+            .unwrap()
+    };
+    let before = kdf_iter(&conn);
+    assert_ne!(before, fixture.decrypt_info().kdf_iter().to_string());
+
+    let wrong = without_hmac(NtDbFixture::new().rand("AnotherRand").decrypt_info());
+    assert!(db::attach_encrypted_db(&conn, &path, "nt_msg", wrong).is_err());
+    assert_eq!(kdf_iter(&conn), before);
+    // a syntax error half way through setting the defaults
+    let bogus = DBDecryptInfo {
+        cipher_hmac_algorithm: Some("HMAC SHA1".to_string()),
+        ..fixture.decrypt_info()
+    };
+    assert!(db::attach_encrypted_db(&conn, &path, "nt_msg", bogus).is_err());
+    assert_eq!(kdf_iter(&conn), before);
+    db::attach_encrypted_db(&conn, &path, "nt_msg", fixture.decrypt_info()).unwrap();
+    assert_eq!(kdf_iter(&conn), before);
+}
+
+#[test]
+fn model() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nt_msg.db");
+    let hello = FixtureMessage::text(1, 10001, "hello");
+    let mut at = FixtureMessage::text(5, 10002, "@all");
+    at.at_flag = 2;
+    at.sender_group_name = "群名片".to_string();
+    let fixture = NtDbFixture::new()
+        .group_message(hello.clone())
+        .group_message(FixtureMessage::reply(2, 10002, &hello, "hi"))
+        .group_message(FixtureMessage::image(3, 10003, "https://example.com/1.jpg"))
+        .group_message(FixtureMessage::file(4, 10001, "notes.txt", 1024))
+        .group_message(at)
+        .c2c_message(FixtureMessage::text(1, 10002, "在吗"));
+    fixture.write(&path).unwrap();
+    let conn = open(&path);
+    db::try_decrypt_db(&conn, fixture.decrypt_info()).unwrap();
+
+    let group = rows(&conn, "group_msg_table");
+    assert_eq!(group.len(), 5);
+    for (row, seq_id) in group.iter().zip(1..) {
+        assert_eq!(row.seq_id, seq_id);
+        assert_eq!(i64::from(row.chat_type), 2);
+        assert_eq!(row.group_number, 100_000);
+        assert_eq!(row.send_time, 1_700_000_000 + seq_id * 60);
+    }
+    let [hello, reply, image, file, at] = &group[..] else {
+        unreachable!()
+    };
+
+    assert_eq!(hello.sender_uin, 10001);
+    assert_eq!(hello.sender_nickname, "用户10001");
+    assert_eq!(i64::from(hello.msg_type), 2);
+    assert_eq!(
+        hello.message.as_ref().unwrap().messages[0].messageText,
+        "hello"
+    );
+
+    assert_eq!(i64::from(reply.msg_type), 9);
+    assert_eq!(reply.reply_msg_seq, 1);
+    let elements = &reply.message.as_ref().unwrap().messages;
+    assert_eq!(elements[0].messageType, 7);
+    assert_eq!(elements[0].senderUid, 10001);
+    assert_eq!(elements[0].replyMessage.messageText, "hello");
+    assert_eq!(elements[1].messageText, "hi");
+
+    let element = &image.message.as_ref().unwrap().messages[0];
+    assert_eq!(element.messageType, 2);
+    assert_eq!(element.imageUrlOrigin, "https://example.com/1.jpg");
+
+    let element = &file.message.as_ref().unwrap().messages[0];
+    assert_eq!(i64::from(file.msg_type), 3);
+    assert_eq!(element.fileName, "notes.txt");
+    assert_eq!(element.fileSize, 1024);
+
+    assert_eq!(i64::from(at.at_flag), 2);
+    assert_eq!(at.sender_group_name, "群名片");
+
+    let c2c = rows(&conn, "c2c_msg_table");
+    assert_eq!(c2c.len(), 1);
+    assert_eq!(i64::from(c2c[0].chat_type), 1);
+    assert_eq!(c2c[0].sender_uin, 10002);
+    assert_eq!(texts(&c2c), ["在吗"]);
+}
+
+#[test]
+fn offset_vfs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nt_msg.db");
+    let fixture = NtDbFixture::sample();
+    fixture.write(&path).unwrap();
+
+    let conn = open(&path);
+    let info = db::offset_info(&conn, "main").unwrap();
+    assert_eq!(info.offset, db::NtDbHeader::LEN as u64);
+    assert_ne!(info.header_detected, 0);
+    db::try_decrypt_db(&conn, fixture.decrypt_info()).unwrap();
+    assert_eq!(rows(&conn, "c2c_msg_table").len(), 2);
+    drop(conn);
+
+    // the read-only vfs reads the same and never writes
+    let before = fs::read(&path).unwrap();
+    let conn = open_with_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        db::OFFSET_VFS_READONLY_NAME,
+    );
+    db::try_decrypt_db(&conn, fixture.decrypt_info()).unwrap();
+    assert_eq!(rows(&conn, "group_msg_table").len(), 4);
+    assert!(conn.execute("DELETE FROM group_msg_table", []).is_err());
+    drop(conn);
+    assert!(fs::read(&path).unwrap() == before);
+
+    // the offset can be given explicitly, and the detection turned off
+    let uri = format!(
+        "file:{}?offset={}&detect=off",
+        path.display(),
+        db::NtDbHeader::LEN
+    );
+    let conn = open_with_vfs(
+        Path::new(&uri),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        db::OFFSET_VFS_NAME,
+    );
+    let info = db::offset_info(&conn, "main").unwrap();
+    assert_eq!(info.offset, db::NtDbHeader::LEN as u64);
+    assert_eq!(info.header_detected, 0);
+    db::try_decrypt_db(&conn, fixture.decrypt_info()).unwrap();
+    assert_eq!(rows(&conn, "c2c_msg_table").len(), 2);
+}
+
+#[test]
+fn offset_vfs_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nt_msg.db");
+    let wal = dir.path().join("nt_msg.db-wal");
+    let fixture = NtDbFixture::sample();
+    fixture.write(&path).unwrap();
+
+    // a writer keeping the transaction in the WAL, like a running QQ
+    let writer = open(&path);
+    db::try_decrypt_db(&writer, fixture.decrypt_info()).unwrap();
+    writer
+        .pragma_update_and_check(None, "journal_mode", "WAL", |x| x.get::<_, String>(0))
+        .unwrap();
+    writer.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+    let before = fs::read(&path).unwrap();
+    writer
+        .execute(
+            "DELETE FROM c2c_msg_table WHERE rowid = (SELECT min(rowid) FROM c2c_msg_table)",
+            [],
+        )
+        .unwrap();
+    assert!(fs::read(&path).unwrap() == before);
+    assert!(fs::metadata(&wal).unwrap().len() > 0);
+
+    let reader = open_with_vfs(
+        &path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        db::OFFSET_VFS_READONLY_NAME,
+    );
+    db::try_decrypt_db(&reader, fixture.decrypt_info()).unwrap();
+    assert_eq!(rows(&reader, "c2c_msg_table").len(), 1);
+    assert!(reader.execute("DELETE FROM c2c_msg_table", []).is_err());
+    drop(reader);
+
+    // the reader neither checkpoints nor touches the database
+    assert!(fs::read(&path).unwrap() == before);
+    assert_eq!(rows(&writer, "c2c_msg_table").len(), 1);
+}
+
+#[test]
+fn db_set() {
+    let dir = tempfile::tempdir().unwrap();
+    let fixture = NtDbFixture::sample();
+    let path = fixture.write_account_dir(dir.path()).unwrap();
+    let account_dir = path.parent().unwrap();
+    NtDbFixture::new()
+        .cipher_hmac_algorithm("HMAC_SHA256")
+        .c2c_message(FixtureMessage::text(1, 10003, "another db"))
+        .write(account_dir.join("misc.db"))
+        .unwrap();
+    // not an NTQQ database, skipped
+    fs::write(account_dir.join("emoji.db"), b"garbage").unwrap();
+
+    let set = NtDbSet::open(account_dir, without_hmac(fixture.decrypt_info())).unwrap();
+    let schemas: Vec<_> = set.databases().iter().map(|x| x.schema.as_str()).collect();
+    assert_eq!(schemas, ["nt_msg", "misc"]);
+    assert_eq!(
+        set.get("misc")
+            .unwrap()
+            .decrypt_info
+            .cipher_hmac_algorithm
+            .as_deref(),
+        Some("HMAC_SHA256")
+    );
+    assert_eq!(
+        set.get("nt_msg")
+            .unwrap()
+            .decrypt_info
+            .cipher_hmac_algorithm
+            .as_deref(),
+        Some("HMAC_SHA1")
+    );
+    let joined: i64 = set
+        .conn()
+        .query_row(
+            "SELECT count(*) FROM nt_msg.c2c_msg_table a JOIN misc.c2c_msg_table b ON a.\"40003\" = b.\"40003\"",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(joined, 1);
+}
+
+#[test]
+fn db_set_attach_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let fixture = NtDbFixture::new().c2c_message(FixtureMessage::text(1, 10001, "hi"));
+    let path = fixture.write_account_dir(dir.path()).unwrap();
+    let account_dir = path.parent().unwrap();
+    let mut stems = db::KNOWN_DATABASES.to_vec();
+    stems.extend(["guild_msg", "nt_msg_fts"]);
+    for stem in &stems[1..] {
+        fs::copy(&path, account_dir.join(format!("{}.db", stem))).unwrap();
+    }
+
+    // every known database, with a slot left for exporting
+    let set = NtDbSet::open(account_dir, fixture.decrypt_info()).unwrap();
+    assert_eq!(set.databases().len(), db::KNOWN_DATABASES.len());
+    let plain = dir.path().join("plain.db");
+    db::export_to_plain(set.conn(), &plain).unwrap();
+    assert!(plain.is_file());
+
+    let e = NtDbSet::open_with(account_dir, fixture.decrypt_info(), &stems).unwrap_err();
+    assert!(
+        matches!(
+            &e,
+            ntdb_unwrap::Error::DB {
+                source: db::Error::TooManyAttached { path, limit: 10 }
+            } if path.ends_with("nt_msg_fts.db")
+        ),
+        "{e}"
+    );
+}
+
+#[test]
+fn export_import_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let fixture = NtDbFixture::sample();
+    let path = fixture.write_account_dir(dir.path().join("src")).unwrap();
+    let d = without_hmac(fixture.decrypt_info());
+
+    let out = dir.path().join("out");
+    let manifest = db::export_dir_to_plain(path.parent().unwrap(), &out, d.clone()).unwrap();
+    assert!(manifest.failed.is_empty());
+    let [exported] = &manifest.databases[..] else {
+        panic!("{:?}", manifest.databases);
+    };
+    assert_eq!(exported.file_name, "nt_msg.db");
+    assert_eq!(exported.cipher_hmac_algorithm, "HMAC_SHA1");
+    assert_eq!(exported.tables["group_msg_table"], 4);
+    assert_eq!(exported.tables["c2c_msg_table"], 2);
+    assert!(out.join(db::MANIFEST_FILE_NAME).is_file());
+
+    let plain = out.join("nt_msg.db");
+    Connection::open(&plain)
+        .unwrap()
+        .execute("DELETE FROM group_msg_table WHERE \"40003\" > 2", [])
+        .unwrap();
+
+    let imported = dir.path().join("imported.db");
+    // the key is checked against the original even with the hmac algorithm given
+    for wrong in [
+        NtDbFixture::new().rand("AnotherRand").decrypt_info(),
+        DBDecryptInfo {
+            cipher_hmac_algorithm: Some("HMAC_SHA256".to_string()),
+            ..d.clone()
+        },
+    ] {
+        let e = db::import_from_plain(&plain, &path, &imported, wrong).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                ntdb_unwrap::Error::DB {
+                    source: db::Error::WrongDecryptInfo {}
+                }
+            ),
+            "{e}"
+        );
+        assert!(!imported.exists());
+    }
+    let used = db::import_from_plain(&plain, &path, &imported, d.clone()).unwrap();
+    assert_eq!(used.cipher_hmac_algorithm.as_deref(), Some("HMAC_SHA1"));
+    assert_eq!(
+        fs::read(&imported).unwrap()[..db::NtDbHeader::LEN],
+        fixture.header()[..]
+    );
+    let conn = open(&imported);
+    db::try_decrypt_db(&conn, d.clone()).unwrap();
+    assert_eq!(texts(&rows(&conn, "group_msg_table")), ["大家好", "你好"]);
+    assert_eq!(rows(&conn, "c2c_msg_table").len(), 2);
+
+    // the imported database exports again, with a new key after a rekey
+    let new = DBDecryptInfo {
+        key: "another key".to_string(),
+        cipher_hmac_algorithm: Some("HMAC_SHA256".to_string()),
+        kdf_iter: None,
+    };
+    drop(db::rekey(conn, new.clone()).unwrap());
+    let rekeyed = dir.path().join("rekeyed");
+    fs::create_dir(&rekeyed).unwrap();
+    fs::rename(&imported, rekeyed.join("nt_msg.db")).unwrap();
+    let manifest =
+        db::export_dir_to_plain(&rekeyed, dir.path().join("out2"), without_hmac(new)).unwrap();
+    assert_eq!(manifest.databases[0].cipher_hmac_algorithm, "HMAC_SHA256");
+    assert_eq!(manifest.databases[0].tables["group_msg_table"], 2);
+}
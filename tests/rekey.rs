@@ -0,0 +1,105 @@
+//! [db::rekey] on fixture databases, checked with both SQLCipher and the pure-Rust decryptor.
+
+use ntdb_unwrap::db::{self, fixture::NtDbFixture};
+use ntdb_unwrap::ntqq::DBDecryptInfo;
+use ntdb_unwrap::sqlcipher::{self, HmacAlgorithm, KeyCheck};
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+
+fn open(path: &Path) -> Connection {
+    db::register_offset_vfs().unwrap();
+    Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE,
+        db::OFFSET_VFS_NAME,
+    )
+    .unwrap()
+}
+
+fn message_count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT count(*) FROM group_msg_table", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[test]
+fn rekey_in_place() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nt_msg.db");
+    let fixture = NtDbFixture::sample();
+    fixture.write(&path).unwrap();
+
+    let conn = open(&path);
+    db::try_decrypt_db(&conn, fixture.decrypt_info()).unwrap();
+    let expected = message_count(&conn);
+    let new = DBDecryptInfo {
+        key: "my own key".to_string(),
+        cipher_hmac_algorithm: None,
+        kdf_iter: None,
+    };
+    let conn = db::rekey(conn, new.clone()).unwrap();
+    assert_eq!(message_count(&conn), expected);
+    drop(conn);
+
+    assert_eq!(
+        sqlcipher::verify_key_file(&path, &fixture.decrypt_info()).unwrap(),
+        KeyCheck::WrongKey
+    );
+    assert_eq!(
+        sqlcipher::verify_key_file(&path, &new).unwrap(),
+        KeyCheck::Match(HmacAlgorithm::Sha1)
+    );
+    let header = std::fs::read(&path).unwrap();
+    assert_eq!(header[..db::NtDbHeader::LEN], fixture.header()[..]);
+}
+
+#[test]
+fn rekey_kdf_iter() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nt_msg.db");
+    let fixture = NtDbFixture::sample();
+    fixture.write(&path).unwrap();
+
+    let conn = open(&path);
+    db::try_decrypt_db(&conn, fixture.decrypt_info()).unwrap();
+    let expected = message_count(&conn);
+    let new = DBDecryptInfo {
+        key: "my own key".to_string(),
+        cipher_hmac_algorithm: Some("HMAC_SHA256".to_string()),
+        kdf_iter: Some(10000),
+    };
+    drop(db::rekey(conn, new.clone()).unwrap());
+
+    // the pure-Rust check derives the key with the new iteration count
+    assert_eq!(
+        sqlcipher::verify_key_file(&path, &new).unwrap(),
+        KeyCheck::Match(HmacAlgorithm::Sha256)
+    );
+    let unknown_hmac = DBDecryptInfo {
+        cipher_hmac_algorithm: None,
+        ..new.clone()
+    };
+    assert_eq!(
+        sqlcipher::verify_key_file(&path, &unknown_hmac).unwrap(),
+        KeyCheck::Match(HmacAlgorithm::Sha256)
+    );
+    let default_iter = DBDecryptInfo {
+        kdf_iter: None,
+        ..unknown_hmac.clone()
+    };
+    assert_eq!(
+        sqlcipher::verify_key_file(&path, &default_iter).unwrap(),
+        KeyCheck::WrongKey
+    );
+
+    let plain = dir.path().join("plain.db");
+    assert_eq!(
+        sqlcipher::decrypt_file(&path, &plain, &unknown_hmac).unwrap(),
+        HmacAlgorithm::Sha256
+    );
+    assert_eq!(message_count(&Connection::open(&plain).unwrap()), expected);
+
+    let conn = open(&path);
+    let d = db::try_decrypt_db_info(&conn, unknown_hmac).unwrap();
+    assert_eq!(d.cipher_hmac_algorithm.as_deref(), Some("HMAC_SHA256"));
+    assert_eq!(message_count(&conn), expected);
+}
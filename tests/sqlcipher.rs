@@ -0,0 +1,130 @@
+//! The pure-Rust decryptor against SQLCipher itself, on databases SQLCipher encrypts with NTQQ's parameters.
+
+use ntdb_unwrap::ntqq::DBDecryptInfo;
+use ntdb_unwrap::sqlcipher::{self, HmacAlgorithm, KeyCheck};
+use rusqlite::Connection;
+use rusqlite::types::Value;
+use std::path::Path;
+
+const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+fn decrypt_info(hmac: HmacAlgorithm) -> DBDecryptInfo {
+    DBDecryptInfo {
+        key: KEY.to_string(),
+        cipher_hmac_algorithm: Some(hmac.to_string()),
+        ..Default::default()
+    }
+}
+
+/// An encrypted database spanning many pages, with overflow pages and an index.
+fn create_encrypted(path: &Path, d: &DBDecryptInfo) {
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch(&d.display_pragma_stmts().to_string())
+        .unwrap();
+    conn.execute_batch(
+        "CREATE TABLE msg (id INTEGER PRIMARY KEY, sender INTEGER, text TEXT, blob BLOB);
+        CREATE INDEX msg_sender ON msg (sender);",
+    )
+    .unwrap();
+    let mut insert = conn
+        .prepare("INSERT INTO msg (sender, text, blob) VALUES (?1, ?2, ?3)")
+        .unwrap();
+    for i in 0..500i64 {
+        let blob = vec![i as u8; (i as usize % 7) * 1500];
+        insert
+            .execute((i % 13, format!("message {}", i), blob))
+            .unwrap();
+    }
+}
+
+/// Every row of every table, in rowid order.
+fn dump(path: &Path) -> Vec<Vec<Value>> {
+    let conn = Connection::open(path).unwrap();
+    let check: String = conn
+        .query_row("PRAGMA integrity_check;", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(check, "ok");
+    let mut rows = Vec::new();
+    let mut tables = conn
+        .prepare("SELECT name, sql FROM sqlite_master ORDER BY name")
+        .unwrap();
+    let tables = tables
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Value>(1)?))
+        })
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .unwrap();
+    for (name, sql) in tables {
+        rows.push(vec![Value::Text(name.clone()), sql]);
+        let Ok(mut stmt) = conn.prepare(&format!("SELECT * FROM \"{}\" ORDER BY rowid", name))
+        else {
+            // indexes
+            continue;
+        };
+        let n = stmt.column_count();
+        let table = stmt
+            .query_map([], |row| (0..n).map(|i| row.get::<_, Value>(i)).collect())
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        rows.extend(table);
+    }
+    rows
+}
+
+#[test]
+fn decrypt_file_matches_sqlcipher_export() {
+    let dir = tempfile::tempdir().unwrap();
+    for hmac in HmacAlgorithm::NTQQ {
+        let d = decrypt_info(hmac);
+        let encrypted = dir.path().join(format!("{}.db", hmac));
+        create_encrypted(&encrypted, &d);
+
+        let bytes = std::fs::read(&encrypted).unwrap();
+        assert_eq!(
+            sqlcipher::verify_key(&bytes, &d).unwrap(),
+            KeyCheck::Match(hmac)
+        );
+        // the hmac algorithm is found when not given
+        let unknown = DBDecryptInfo {
+            cipher_hmac_algorithm: None,
+            ..d.clone()
+        };
+        assert_eq!(
+            sqlcipher::verify_key(&bytes, &unknown).unwrap(),
+            KeyCheck::Match(hmac)
+        );
+        let wrong = DBDecryptInfo {
+            key: "wrong".to_string(),
+            ..unknown.clone()
+        };
+        assert_eq!(
+            sqlcipher::verify_key(&bytes, &wrong).unwrap(),
+            KeyCheck::WrongKey
+        );
+
+        let pure = dir.path().join(format!("{}.pure.db", hmac));
+        assert_eq!(
+            sqlcipher::decrypt_file(&encrypted, &pure, &unknown).unwrap(),
+            hmac
+        );
+
+        let exported = dir.path().join(format!("{}.export.db", hmac));
+        let conn = Connection::open(&encrypted).unwrap();
+        conn.execute_batch(&d.display_pragma_stmts().to_string())
+            .unwrap();
+        conn.execute(
+            "ATTACH DATABASE ?1 AS plain KEY ''",
+            [exported.to_string_lossy()],
+        )
+        .unwrap();
+        conn.query_row("SELECT sqlcipher_export('plain');", [], |_| Ok(()))
+            .unwrap();
+        drop(conn);
+
+        let expected = dump(&exported);
+        assert!(expected.len() > 500, "{}", hmac);
+        assert_eq!(dump(&pure), expected, "{}", hmac);
+    }
+}